@@ -0,0 +1,586 @@
+use std::{cell::Cell, collections::{BTreeMap, HashMap}, fmt};
+
+use crate::{cpu::{commands::{Commands, Syntax}, model::CpuModel}, loader::{symbols::{Symbol, SymbolKind, SymbolTable}, LoadedImage}, mem::{BusError, Memory}, utils::{Address, Byte, Word}};
+
+use expression::{evaluate, is_symbol_char, parse_operand, parse_register, split_operands, strip_comment, Operand};
+
+pub mod expression;
+
+// A MACRO-11 subset for absolute programs: labels and local labels, direct assignment,
+// every addressing mode notation, .WORD .BYTE .ASCII .ASCIZ .BLKW .BLKB .EVEN .ODD .END and .=
+// The opcodes come from cpu::commands
+
+#[derive(Debug)]
+pub struct AssemblyError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Code {
+    None,
+    Value(Word), // Of a direct assignment
+    Words(Vec<Word>),
+    Bytes(Vec<Byte>),
+}
+
+#[derive(Clone, Debug)]
+pub struct ListingLine {
+    pub number: usize,
+    pub address: Option<Word>,
+    pub code: Code,
+    pub source: String,
+}
+
+impl fmt::Display for ListingLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let address = match (&self.code, self.address) {
+            (Code::Value(value), _) => format!("{value:06o}"),
+            (_, Some(address)) => format!("{address:06o}"),
+            _ => String::new(),
+        };
+        let code = match &self.code {
+            Code::None | Code::Value(_) => String::new(),
+            Code::Words(words) => words.iter().take(3).map(|word| format!("{word:06o}")).collect::<Vec<_>>().join(" "),
+            Code::Bytes(bytes) => bytes.iter().take(6).map(|byte| format!("{byte:03o}")).collect::<Vec<_>>().join(" "),
+        };
+
+        write!(f, "{:5} {address:6} {code:20} {}", self.number, self.source)
+    }
+}
+
+#[derive(Debug)]
+pub struct Program {
+    pub image: BTreeMap<Word, Byte>,
+    pub start_address: Option<Word>,
+    pub symbols: SymbolTable,
+    pub listing: Vec<ListingLine>,
+}
+
+impl Program {
+    pub fn byte(&self, address: Word) -> Option<Byte> {
+        self.image.get(&address).copied()
+    }
+
+    pub fn word(&self, address: Word) -> Option<Word> {
+        Some(self.byte(address)? as Word | (self.byte(address.wrapping_add(1))? as Word) << 8)
+    }
+
+    pub fn load(&self, memory: &mut Memory) -> Result<LoadedImage, BusError> {
+        for (address, byte) in self.image.iter() {
+            memory.write_byte(*address as Address, *byte)?;
+        }
+
+        Ok(LoadedImage {
+            start_address: self.start_address,
+            symbols: self.symbols.clone(),
+            ..LoadedImage::default()
+        })
+    }
+
+    pub fn listing(&self) -> String {
+        let mut listing: Vec<String> = self.listing.iter().map(|line| line.to_string()).collect();
+
+        listing.push(String::new());
+        listing.push("Symbol table".to_string());
+
+        for symbol in self.symbols.iter() {
+            listing.push(format!("{:8} {:06o}", symbol.name, symbol.value));
+        }
+
+        listing.join("\n")
+    }
+}
+
+pub struct Assembler {
+    mnemonics: HashMap<String, (Word, Syntax)>,
+}
+
+impl Default for Assembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Assembler {
+    // Accepts the instructions of every model
+    pub fn new() -> Self {
+        Self::with_commands(&Commands::all_models())
+    }
+
+    pub fn for_model(model: CpuModel) -> Self {
+        Self::with_commands(&Commands::for_model(model))
+    }
+
+    fn with_commands(commands: &Commands) -> Self {
+        let mut mnemonics = HashMap::new();
+
+        for (command, syntax) in commands.with_syntax() {
+            if syntax == Syntax::ConditionCodes {
+                // SE* and CL*, flags are C, V, Z and N from bit 0, SCC and CCC set or clear all
+                let prefix = command.1.trim_end_matches('*');
+
+                for (flag, bits) in [("C", 0x1), ("V", 0x2), ("Z", 0x4), ("N", 0x8)] {
+                    mnemonics.insert(format!("{prefix}{flag}"), (command.0 | bits, Syntax::None));
+                }
+
+                mnemonics.insert(format!("{}CC", &prefix[..1]), (command.0 | 0xF, Syntax::None));
+                continue;
+            }
+
+            for name in command.1.split('/') {
+                mnemonics.insert(name.to_string(), (command.0, syntax));
+            }
+        }
+
+        Assembler { mnemonics }
+    }
+
+    pub fn assemble(&self, source: &str) -> Result<Program, AssemblyError> {
+        let mut pass = Pass::new(self, HashMap::new(), false);
+        pass.run(source)?;
+
+        let mut pass = Pass::new(self, pass.symbols, true);
+        pass.run(source)?;
+
+        Ok(pass.into_program())
+    }
+}
+
+// 1$, 2$ and so on, symbols can't start with a digit otherwise
+fn is_local_label(name: &str) -> bool {
+    name.starts_with(|char: char| char.is_ascii_digit())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Definition {
+    Label,
+    Assignment,
+}
+
+struct Pass<'a> {
+    assembler: &'a Assembler,
+    final_pass: bool, // Symbols are all known and code is emitted
+    symbols: HashMap<String, (Word, Definition)>,
+    defined: Vec<String>, // Labels defined in this pass
+    location: Word,
+    line_location: Word, // "." stays at the start of the statement
+    local_scope: usize,
+    image: BTreeMap<Word, Byte>,
+    listing: Vec<ListingLine>,
+    start_address: Option<Word>,
+    undefined: Cell<bool>, // A forward reference was evaluated as 0 in the first pass
+}
+
+impl<'a> Pass<'a> {
+    fn new(assembler: &'a Assembler, symbols: HashMap<String, (Word, Definition)>, final_pass: bool) -> Self {
+        Pass {
+            assembler,
+            final_pass,
+            symbols,
+            defined: Vec::new(),
+            location: 0,
+            line_location: 0,
+            local_scope: 0,
+            image: BTreeMap::new(),
+            listing: Vec::new(),
+            start_address: None,
+            undefined: Cell::new(false),
+        }
+    }
+
+    fn run(&mut self, source: &str) -> Result<(), AssemblyError> {
+        for (index, line) in source.lines().enumerate() {
+            self.line_location = self.location;
+
+            let code = self.line(line).map_err(|message| AssemblyError { line: index + 1, message })?;
+
+            if self.final_pass {
+                let address = matches!(code, Some(Code::Words(_) | Code::Bytes(_))).then_some(self.line_location);
+
+                self.listing.push(ListingLine { number: index + 1, address, code: code.clone().unwrap_or(Code::None), source: line.to_string() });
+            }
+
+            if code.is_none() {
+                break; // .END
+            }
+        }
+
+        Ok(())
+    }
+
+    fn into_program(self) -> Program {
+        let symbols = self.symbols.into_iter()
+            .filter(|(name, _)| !is_local_label(name))
+            .map(|(name, (value, definition))| Symbol {
+                name,
+                kind: if definition == Definition::Label { SymbolKind::Text } else { SymbolKind::Absolute },
+                external: false,
+                value,
+            });
+
+        Program {
+            image: self.image,
+            start_address: self.start_address,
+            symbols: SymbolTable::new(symbols.collect()),
+            listing: self.listing,
+        }
+    }
+
+    // None after .END
+    fn line(&mut self, line: &str) -> Result<Option<Code>, String> {
+        let mut rest = line;
+
+        loop {
+            let text = rest.trim_start();
+            let name_length = text.find(|char: char| !is_symbol_char(char)).unwrap_or(text.len());
+            let (name, after) = text.split_at(name_length);
+            let after = after.trim_start();
+
+            if let Some(after) = after.strip_prefix(':') {
+                self.define_label(name)?;
+                rest = after.strip_prefix(':').unwrap_or(after);
+                continue;
+            }
+
+            if let Some(after) = after.strip_prefix('=') {
+                let expression = strip_comment(after.strip_prefix('=').unwrap_or(after));
+
+                return self.assignment(name, expression).map(Some);
+            }
+
+            if name.is_empty() {
+                return match strip_comment(text).trim() {
+                    "" => Ok(Some(Code::None)),
+                    text => Err(format!("syntax error at \"{text}\"")),
+                };
+            }
+
+            return self.statement(&name.to_ascii_uppercase(), after);
+        }
+    }
+
+    fn define_label(&mut self, name: &str) -> Result<(), String> {
+        if name.is_empty() {
+            return Err("missing label name".to_string());
+        }
+
+        if !is_local_label(name) {
+            self.local_scope += 1;
+        }
+
+        let key = self.symbol_key(&name.to_ascii_uppercase());
+
+        if self.defined.contains(&key) {
+            return Err(format!("label {name} is defined twice"));
+        }
+
+        if self.final_pass && self.symbols.get(&key).is_some_and(|(value, _)| *value != self.location) {
+            return Err(format!("label {name} moved between passes"));
+        }
+
+        self.defined.push(key.clone());
+        self.symbols.insert(key, (self.location, Definition::Label));
+
+        Ok(())
+    }
+
+    fn assignment(&mut self, name: &str, expression: &str) -> Result<Code, String> {
+        let value = self.evaluate_defined(expression)?;
+
+        if name == "." {
+            self.location = value;
+        } else {
+            self.symbols.insert(name.to_ascii_uppercase(), (value, Definition::Assignment));
+        }
+
+        Ok(Code::Value(value))
+    }
+
+    // Local labels like 1$ live between two ordinary labels
+    fn symbol_key(&self, name: &str) -> String {
+        if is_local_label(name) {
+            format!("{name}{}", self.local_scope)
+        } else {
+            name.to_string()
+        }
+    }
+
+    fn evaluate(&self, expression: &str) -> Result<Word, String> {
+        evaluate(expression, &mut |name| self.resolve(name))
+    }
+
+    // Location counter changes and block sizes can't wait for the second pass
+    fn evaluate_defined(&self, expression: &str) -> Result<Word, String> {
+        self.undefined.set(false);
+
+        let value = self.evaluate(expression)?;
+
+        if self.undefined.get() {
+            return Err(format!("\"{}\" uses a symbol defined later", expression.trim()));
+        }
+
+        Ok(value)
+    }
+
+    fn resolve(&self, name: &str) -> Result<Word, String> {
+        if name == "." {
+            return Ok(self.line_location);
+        }
+
+        match self.symbols.get(&self.symbol_key(name)) {
+            Some((value, _)) => Ok(*value),
+            None if self.final_pass => Err(format!("undefined symbol {name}")),
+            None => {
+                self.undefined.set(true);
+                Ok(0)
+            },
+        }
+    }
+
+    fn statement(&mut self, name: &str, operands: &str) -> Result<Option<Code>, String> {
+        match name {
+            ".ASCII" => return self.ascii(operands, false).map(Some),
+            ".ASCIZ" => return self.ascii(operands, true).map(Some),
+            _ => {},
+        }
+
+        let operands = split_operands(strip_comment(operands));
+
+        let code = match name {
+            ".WORD" => self.words(&operands)?,
+            ".BYTE" => self.bytes(&operands)?,
+            ".BLKW" => self.block(&operands, 2)?,
+            ".BLKB" => self.block(&operands, 1)?,
+            ".EVEN" => {
+                self.location += self.location & 0x1;
+                Code::None
+            },
+            ".ODD" => {
+                self.location |= 0x1;
+                Code::None
+            },
+            ".END" => {
+                if let Some(start) = operands.first() {
+                    self.start_address = Some(self.evaluate(start)?);
+                }
+
+                return Ok(None);
+            },
+            ".TITLE" | ".SBTTL" | ".IDENT" | ".PAGE" | ".LIST" | ".NLIST" | ".ENABL" | ".DSABL" | ".ASECT" | ".GLOBL" => Code::None,
+            _ => self.instruction(name, &operands)?,
+        };
+
+        Ok(Some(code))
+    }
+
+    fn words(&mut self, operands: &[&str]) -> Result<Code, String> {
+        let words = match operands {
+            [] => vec![0],
+            _ => operands.iter().map(|operand| self.evaluate(operand)).collect::<Result<Vec<_>, _>>()?,
+        };
+
+        self.emit_words(&words)?;
+
+        Ok(Code::Words(words))
+    }
+
+    fn bytes(&mut self, operands: &[&str]) -> Result<Code, String> {
+        let mut bytes = Vec::new();
+
+        for operand in operands {
+            let value = self.evaluate(operand)?;
+
+            if value > 0x00FF && value < 0xFF80 {
+                return Err(format!("{value:o} doesn't fit in a byte"));
+            }
+
+            bytes.push(value as Byte);
+        }
+
+        if bytes.is_empty() {
+            bytes.push(0);
+        }
+
+        self.emit_bytes(&bytes);
+
+        Ok(Code::Bytes(bytes))
+    }
+
+    fn block(&mut self, operands: &[&str], size: Word) -> Result<Code, String> {
+        let count = match operands {
+            [] => 1,
+            [count] => self.evaluate_defined(count)?,
+            _ => return Err("one block size expected".to_string()),
+        };
+
+        if size == 2 && self.location & 0x1 != 0 {
+            return Err(format!("odd address {:06o}", self.location));
+        }
+
+        self.location = self.location.wrapping_add(count.wrapping_mul(size));
+
+        Ok(Code::None)
+    }
+
+    // Strings between any pair of delimiters, <N> for single bytes
+    fn ascii(&mut self, text: &str, zero_terminated: bool) -> Result<Code, String> {
+        let mut bytes = Vec::new();
+        let mut rest = text.trim_start();
+
+        while let Some(delimiter) = rest.chars().next().filter(|char| *char != ';') {
+            rest = &rest[delimiter.len_utf8()..];
+
+            if delimiter == '<' {
+                let (expression, after) = rest.split_once('>').ok_or("missing '>'".to_string())?;
+
+                bytes.push(self.evaluate(expression)? as Byte);
+                rest = after.trim_start();
+                continue;
+            }
+
+            let (string, after) = rest.split_once(delimiter).ok_or(format!("missing closing {delimiter}"))?;
+
+            bytes.extend(string.bytes());
+            rest = after.trim_start();
+        }
+
+        if zero_terminated {
+            bytes.push(0);
+        }
+
+        self.emit_bytes(&bytes);
+
+        Ok(Code::Bytes(bytes))
+    }
+
+    fn instruction(&mut self, name: &str, operands: &[&str]) -> Result<Code, String> {
+        let (opcode, syntax) = *self.assembler.mnemonics.get(name).ok_or(format!("unknown instruction {name}"))?;
+
+        let expected = match syntax {
+            Syntax::None | Syntax::ConditionCodes => 0,
+            Syntax::Register | Syntax::Priority | Syntax::Operand | Syntax::Mark | Syntax::Branch => 1,
+            Syntax::Trap => operands.len().min(1),
+            _ => 2,
+        };
+
+        if operands.len() != expected {
+            return Err(format!("{name} takes {expected} operand(s)"));
+        }
+
+        let words = self.encode(opcode, syntax, operands)?;
+
+        self.emit_words(&words)?;
+
+        Ok(Code::Words(words))
+    }
+
+    fn encode(&self, opcode: Word, syntax: Syntax, operands: &[&str]) -> Result<Vec<Word>, String> {
+        let register = |text: &str| parse_register(text, &mut |name| self.resolve(name)).map(Word::from);
+        let operand = |text: &str| parse_operand(text, &mut |name| self.resolve(name));
+        let accumulator = |text: &str| match register(text)? {
+            ac if ac < 4 => Ok(ac),
+            _ => Err(format!("{text} is not an accumulator")),
+        };
+
+        let (word, operands): (Word, Vec<Operand>) = match syntax {
+            Syntax::None | Syntax::ConditionCodes => (opcode, vec![]),
+            Syntax::Register => (opcode | register(operands[0])?, vec![]),
+            Syntax::Priority => (opcode | self.evaluate(operands[0])? & 0x7, vec![]),
+            Syntax::Mark => (opcode | self.evaluate(operands[0])? & 0x3F, vec![]),
+            Syntax::Trap => (opcode | operands.first().map_or(Ok(0), |code| self.evaluate(code))? & 0xFF, vec![]),
+            Syntax::Operand => {
+                let destination = operand(operands[0])?;
+                (opcode | destination.field(), vec![destination])
+            },
+            Syntax::RegisterOperand => {
+                let destination = operand(operands[1])?;
+                (opcode | register(operands[0])? << 6 | destination.field(), vec![destination])
+            },
+            Syntax::OperandRegister => {
+                let source = operand(operands[0])?;
+                (opcode | register(operands[1])? << 6 | source.field(), vec![source])
+            },
+            Syntax::AccumulatorSource => {
+                let source = operand(operands[0])?;
+                (opcode | accumulator(operands[1])? << 6 | source.field(), vec![source])
+            },
+            Syntax::AccumulatorDestination => {
+                let destination = operand(operands[1])?;
+                (opcode | accumulator(operands[0])? << 6 | destination.field(), vec![destination])
+            },
+            Syntax::TwoOperands => {
+                let source = operand(operands[0])?;
+                let destination = operand(operands[1])?;
+                (opcode | source.field() << 6 | destination.field(), vec![source, destination])
+            },
+            Syntax::Branch => {
+                let offset = self.branch_offset(operands[0], false)?;
+                (opcode | offset & 0xFF, vec![])
+            },
+            Syntax::SubtractOneBranch => {
+                let offset = self.branch_offset(operands[1], true)?;
+                (opcode | register(operands[0])? << 6 | offset, vec![])
+            },
+        };
+
+        let mut words = vec![word];
+
+        for operand in operands {
+            let address = self.location.wrapping_add(2 * words.len() as Word);
+
+            if let Some(word) = operand.word(address) {
+                words.push(word);
+            }
+        }
+
+        Ok(words)
+    }
+
+    // In words from the next instruction, SOB only branches backwards
+    fn branch_offset(&self, target: &str, backwards: bool) -> Result<Word, String> {
+        let target = self.evaluate(target)?;
+        let next = self.location.wrapping_add(2);
+
+        if !self.final_pass {
+            return Ok(0);
+        }
+
+        let distance = if backwards { next.wrapping_sub(target) } else { target.wrapping_sub(next) } as i16;
+        let range = if backwards { 0..=126 } else { -256..=254 };
+
+        if distance % 2 != 0 || !range.contains(&distance) {
+            return Err(format!("branch to {target:06o} out of range"));
+        }
+
+        Ok((distance / 2) as Word & if backwards { 0x3F } else { 0xFF })
+    }
+
+    fn emit_words(&mut self, words: &[Word]) -> Result<(), String> {
+        if self.location & 0x1 != 0 {
+            return Err(format!("odd address {:06o}", self.location));
+        }
+
+        for word in words {
+            self.emit_bytes(&word.to_le_bytes());
+        }
+
+        Ok(())
+    }
+
+    fn emit_bytes(&mut self, bytes: &[Byte]) {
+        for byte in bytes {
+            if self.final_pass {
+                self.image.insert(self.location, *byte);
+            }
+
+            self.location = self.location.wrapping_add(1);
+        }
+    }
+}
@@ -0,0 +1,316 @@
+use crate::{cpu::{PROGRAM_COUNTER_INDEX, STACK_POINTER_INDEX}, utils::{Byte, Word}};
+
+// MACRO-11 expressions: octal numbers, decimal ones with a trailing point, ^D ^O ^B radix prefixes,
+// 'c and "cc characters, symbols and "." for the current location.
+// Operators are evaluated left to right without precedence, <> groups
+
+pub type Resolver<'a> = dyn FnMut(&str) -> Result<Word, String> + 'a;
+
+pub fn evaluate(text: &str, resolve: &mut Resolver) -> Result<Word, String> {
+    let mut scanner = Scanner::new(text);
+
+    let value = scanner.expression(resolve)?;
+
+    scanner.skip_spaces();
+
+    if let Some(char) = scanner.peek() {
+        return Err(format!("unexpected '{char}' in \"{}\"", text.trim()));
+    }
+
+    Ok(value)
+}
+
+pub fn is_symbol_char(char: char) -> bool {
+    char.is_ascii_alphanumeric() || char == '$' || char == '.'
+}
+
+struct Scanner {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Scanner {
+    fn new(text: &str) -> Self {
+        Scanner { chars: text.chars().collect(), position: 0 }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let char = self.peek();
+        self.position += 1;
+        char
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek().is_some_and(|char| char.is_whitespace()) {
+            self.position += 1;
+        }
+    }
+
+    fn expression(&mut self, resolve: &mut Resolver) -> Result<Word, String> {
+        let mut value = self.term(resolve)?;
+
+        loop {
+            self.skip_spaces();
+
+            let Some(operator) = self.peek().filter(|char| "+-*/&!".contains(*char)) else {
+                return Ok(value);
+            };
+
+            self.position += 1;
+            let operand = self.term(resolve)?;
+
+            value = match operator {
+                '+' => value.wrapping_add(operand),
+                '-' => value.wrapping_sub(operand),
+                '*' => value.wrapping_mul(operand),
+                '/' if operand == 0 => return Err("division by zero".to_string()),
+                '/' => ((value as i16).wrapping_div(operand as i16)) as Word,
+                '&' => value & operand,
+                _ => value | operand,
+            };
+        }
+    }
+
+    fn term(&mut self, resolve: &mut Resolver) -> Result<Word, String> {
+        self.skip_spaces();
+
+        match self.next() {
+            Some('-') => Ok(self.term(resolve)?.wrapping_neg()),
+            Some('+') => self.term(resolve),
+            Some('<') => {
+                let value = self.expression(resolve)?;
+
+                self.skip_spaces();
+
+                match self.next() {
+                    Some('>') => Ok(value),
+                    _ => Err("missing '>'".to_string()),
+                }
+            },
+            Some('\'') => self.next().map(|char| char as Word & 0x00FF).ok_or("missing character after '".to_string()),
+            Some('"') => {
+                let low = self.next().ok_or("missing characters after \"".to_string())?;
+                let high = self.next().ok_or("missing characters after \"".to_string())?;
+
+                Ok((low as Word & 0x00FF) | (high as Word & 0x00FF) << 8)
+            },
+            Some('^') => self.radix_term(resolve),
+            Some(char) if char.is_ascii_digit() => {
+                self.position -= 1;
+                self.number_or_local_symbol(resolve)
+            },
+            Some(char) if is_symbol_char(char) => {
+                self.position -= 1;
+                resolve(&self.symbol())
+            },
+            Some(char) => Err(format!("unexpected '{char}'")),
+            None => Err("missing term".to_string()),
+        }
+    }
+
+    fn radix_term(&mut self, resolve: &mut Resolver) -> Result<Word, String> {
+        let radix = match self.next().map(|char| char.to_ascii_uppercase()) {
+            Some('D') => 10,
+            Some('O') => 8,
+            Some('B') => 2,
+            Some('C') => return Ok(!self.term(resolve)?),
+            _ => return Err("unknown ^ operator".to_string()),
+        };
+
+        self.skip_spaces();
+
+        let digits = self.digits();
+
+        parse_number(&digits, radix)
+    }
+
+    fn number_or_local_symbol(&mut self, resolve: &mut Resolver) -> Result<Word, String> {
+        let digits = self.digits();
+
+        match self.peek() {
+            Some('$') => {
+                self.position += 1;
+                resolve(&format!("{digits}$"))
+            },
+            Some('.') => {
+                self.position += 1;
+                parse_number(&digits, 10)
+            },
+            _ => parse_number(&digits, 8),
+        }
+    }
+
+    fn digits(&mut self) -> String {
+        let start = self.position;
+
+        while self.peek().is_some_and(|char| char.is_ascii_digit()) {
+            self.position += 1;
+        }
+
+        self.chars[start..self.position].iter().collect()
+    }
+
+    fn symbol(&mut self) -> String {
+        let start = self.position;
+
+        while self.peek().is_some_and(is_symbol_char) {
+            self.position += 1;
+        }
+
+        self.chars[start..self.position].iter().collect::<String>().to_ascii_uppercase()
+    }
+}
+
+fn parse_number(digits: &str, radix: u32) -> Result<Word, String> {
+    u32::from_str_radix(digits, radix)
+        .ok()
+        .filter(|value| *value <= 0xFFFF)
+        .map(|value| value as Word)
+        .ok_or(format!("bad number {digits} in radix {radix}"))
+}
+
+// R0-R7, SP, PC or %N
+pub fn parse_register(text: &str, resolve: &mut Resolver) -> Result<Byte, String> {
+    register(text.trim(), resolve)?.ok_or(format!("{} is not a register", text.trim()))
+}
+
+fn register(text: &str, resolve: &mut Resolver) -> Result<Option<Byte>, String> {
+    let name = text.to_ascii_uppercase();
+
+    let register = match name.as_str() {
+        "SP" => Some(STACK_POINTER_INDEX),
+        "PC" => Some(PROGRAM_COUNTER_INDEX),
+        _ if name.len() == 2 && name.starts_with('R') => name[1..].parse::<Byte>().ok().filter(|index| *index < 8),
+        _ => None,
+    };
+
+    match (register, name.strip_prefix('%')) {
+        (Some(register), _) => Ok(Some(register)),
+        (None, Some(expression)) => match evaluate(expression, resolve)? {
+            value if value < 8 => Ok(Some(value as Byte)),
+            value => Err(format!("register %{value:o} out of range")),
+        },
+        (None, None) => Ok(None),
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum OperandWord {
+    Value(Word),
+    Relative(Word), // Target address, the word holds its distance from the next word
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Operand {
+    pub mode: Byte,
+    pub register: Byte,
+    pub word: Option<OperandWord>,
+}
+
+impl Operand {
+    // The 6-bit operand field of the instruction
+    pub fn field(&self) -> Word {
+        ((self.mode << 3) | self.register) as Word
+    }
+
+    // Relative operands are resolved against the address of their own word
+    pub fn word(&self, address: Word) -> Option<Word> {
+        self.word.map(|word| match word {
+            OperandWord::Value(value) => value,
+            OperandWord::Relative(target) => target.wrapping_sub(address.wrapping_add(2)),
+        })
+    }
+}
+
+pub fn parse_operand(text: &str, resolve: &mut Resolver) -> Result<Operand, String> {
+    let text = text.trim();
+
+    let operand = |mode: Byte, register: Byte, word: Option<OperandWord>| Ok(Operand { mode, register, word });
+
+    if let Some(value) = text.strip_prefix("@#") {
+        return operand(3, PROGRAM_COUNTER_INDEX, Some(OperandWord::Value(evaluate(value, resolve)?)));
+    }
+
+    if let Some(value) = text.strip_prefix('#') {
+        return operand(2, PROGRAM_COUNTER_INDEX, Some(OperandWord::Value(evaluate(value, resolve)?)));
+    }
+
+    let (deferred, text) = match text.strip_prefix('@') {
+        Some(text) => (1, text.trim_start()),
+        None => (0, text),
+    };
+
+    if let Some(inner) = text.strip_prefix("-(").and_then(|text| text.strip_suffix(')')) {
+        return operand(4 + deferred, parse_register(inner, resolve)?, None);
+    }
+
+    if let Some(inner) = text.strip_prefix('(').and_then(|text| text.strip_suffix(")+")) {
+        return operand(2 + deferred, parse_register(inner, resolve)?, None);
+    }
+
+    if let Some(inner) = text.strip_prefix('(').and_then(|text| text.strip_suffix(')')) {
+        // @(R) is @0(R)
+        return match deferred {
+            0 => operand(1, parse_register(inner, resolve)?, None),
+            _ => operand(7, parse_register(inner, resolve)?, Some(OperandWord::Value(0))),
+        };
+    }
+
+    if let Some((index, inner)) = text.strip_suffix(')').and_then(|text| text.rsplit_once('(')) {
+        return operand(6 + deferred, parse_register(inner, resolve)?, Some(OperandWord::Value(evaluate(index, resolve)?)));
+    }
+
+    if let Some(register) = register(text, resolve)? {
+        return operand(deferred, register, None);
+    }
+
+    operand(6 + deferred, PROGRAM_COUNTER_INDEX, Some(OperandWord::Relative(evaluate(text, resolve)?)))
+}
+
+// Splits at top level commas, keeping <> groups and character literals whole
+pub fn split_operands(text: &str) -> Vec<&str> {
+    let mut operands = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+    let mut chars = text.char_indices();
+
+    while let Some((index, char)) = chars.next() {
+        match char {
+            '\'' => { chars.next(); },
+            '"' => { chars.next(); chars.next(); },
+            '<' => depth += 1,
+            '>' => depth -= 1,
+            ',' if depth == 0 => {
+                operands.push(text[start..index].trim());
+                start = index + 1;
+            },
+            _ => {},
+        }
+    }
+
+    if !text[start..].trim().is_empty() || !operands.is_empty() {
+        operands.push(text[start..].trim());
+    }
+
+    operands
+}
+
+// The comment starts at the first ; outside of character literals
+pub fn strip_comment(text: &str) -> &str {
+    let mut chars = text.char_indices();
+
+    while let Some((index, char)) = chars.next() {
+        match char {
+            '\'' => { chars.next(); },
+            '"' => { chars.next(); chars.next(); },
+            ';' => return &text[..index],
+            _ => {},
+        }
+    }
+
+    text
+}
@@ -1,57 +1,490 @@
-use std::{sync::{Arc, Mutex}, thread::{self, JoinHandle}, time::Duration};
+use std::{fs, io::{self, Write}, path::Path, sync::{Arc, Mutex}, thread::{self, JoinHandle}};
 
-use crate::{cpu::CPU, mem::Memory, tty::Dl11Tty};
+use crate::{assembler::Assembler, bus::BusDevice, cpu::{model::CpuModel, profiler::Profiler, trace::{TraceConfig, Tracer}, CPU, FIRST_COMMAND, STACK_START}, line_clock::{Kw11LineClock, DEFAULT_TICK_RATE}, loader::{absolute, aout, raw, sav, symbols::SymbolTable, ImageFormat, LoadError, LoadedImage}, mem::{BusError, Memory, DEFAULT_MEMORY_SIZE}, scheduler::{real_duration, ScheduledDevice, Scheduler}, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter}, tty::Dl11Tty, utils::{Address, Byte, Word}};
+
+#[derive(Clone, Copy, Debug)]
+pub struct Pdp11Config {
+    pub model: CpuModel, // 11/70 by default, which has the FP11 but no FIS
+    pub memory_size: usize, // Bytes, from 8 KB up to 4 MB less the I/O page
+    pub line_clock_rate: u32, // Hz, 50 or 60 on real hardware
+    pub deterministic: bool, // Devices run on virtual time in the CPU thread
+    pub console: bool, // DL11 attached
+    pub line_clock: bool, // KW11-L attached
+}
+
+impl Default for Pdp11Config {
+    fn default() -> Self {
+        Pdp11Config {
+            model: CpuModel::default(),
+            memory_size: DEFAULT_MEMORY_SIZE,
+            line_clock_rate: DEFAULT_TICK_RATE,
+            deterministic: false,
+            console: true,
+            line_clock: true,
+        }
+    }
+}
 
 pub struct Pdp11 {
     memory: Arc<Mutex<Memory>>,
     cpu: CPU,
     dl11tty: Arc<Mutex<Dl11Tty>>,
+    kw11l: Arc<Mutex<Kw11LineClock>>,
+    devices: Vec<Arc<Mutex<dyn ScheduledDevice + Send>>>, // Added by the embedder
+    deterministic: bool,
+    console: bool,
+    line_clock: bool,
+    start_address: Word,
+    stack_pointer: Word,
+    load_only: bool, // Run only boots, the image asked not to be started
+    symbols: SymbolTable,
+}
+
+impl Default for Pdp11 {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Pdp11 {
     pub fn new() -> Self {
-        let memory = Memory::new();
-        let cpu = CPU::default();
+        Self::with_config(Pdp11Config::default())
+    }
+
+    pub fn with_config(config: Pdp11Config) -> Self {
+        let memory = Memory::with_size(config.memory_size);
+        let cpu = CPU::for_model(config.model);
         let dl11tty = Arc::new(Mutex::new(Dl11Tty::new()));
+        let kw11l = Arc::new(Mutex::new(Kw11LineClock::new(config.line_clock_rate)));
+
+        // Registers stay on the bus for the life of the machine, runs only move data
+        if config.console {
+            dl11tty.lock().unwrap().attach(memory.clone());
+        }
+
+        if config.line_clock {
+            kw11l.lock().unwrap().map_registers(memory.clone());
+        }
 
         Pdp11 {
-            memory: memory,
-            cpu: cpu,
-            dl11tty: dl11tty,
+            memory,
+            cpu,
+            dl11tty,
+            kw11l,
+            devices: Vec::new(),
+            deterministic: config.deterministic,
+            console: config.console,
+            line_clock: config.line_clock,
+            start_address: FIRST_COMMAND as Word,
+            stack_pointer: STACK_START as Word,
+            load_only: false,
+            symbols: SymbolTable::default(),
         }
     }
 
+    // A load-only image is booted but not started, the CPU is left stopped without a halt reason
     pub fn run(&mut self) {
-        let dl11tty_thread = self.run_tty();
+        self.boot();
+
+        if self.load_only {
+            warn!("The image asks not to be started, set a start address to run it");
+            return;
+        }
+
+        self.resume();
+    }
+
+    // PC and SP as the last loaded image wants them, the machine is not started
+    pub fn boot(&mut self) {
+        self.cpu.boot_registers_at(self.start_address, self.stack_pointer);
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn memory(&self) -> Arc<Mutex<Memory>> {
+        self.memory.clone()
+    }
+
+    // The loader is picked by extension: .lda and .bin are paper tapes, .mac is MACRO-11 source,
+    // .sav is an RT-11 save image and anything else an a.out executable
+    pub fn load(&mut self, path: impl AsRef<Path>) -> Result<LoadedImage, LoadError> {
+        let path = path.as_ref();
+
+        self.load_as(path, ImageFormat::from_path(path), None)
+    }
+
+    // Only raw images take a load address, 0 if None, the other formats say where they go
+    pub fn load_as(&mut self, path: impl AsRef<Path>, format: ImageFormat, address: Option<Word>) -> Result<LoadedImage, LoadError> {
+        if address.is_some() && format != ImageFormat::Raw {
+            return Err(LoadError::BadFormat(format!("{format} images can't be loaded at a given address")));
+        }
+
+        match format {
+            ImageFormat::Absolute => self.load_absolute(path),
+            ImageFormat::Aout => self.load_aout(path),
+            ImageFormat::Sav => self.load_sav(path),
+            ImageFormat::Macro11 => self.load_macro11(path),
+            ImageFormat::Raw => self.load_raw(path, address.unwrap_or(0)),
+        }
+    }
+
+    // Absolute Loader paper tape, the machine starts where the tape says if it says so
+    pub fn load_absolute(&mut self, path: impl AsRef<Path>) -> Result<LoadedImage, LoadError> {
+        let image = fs::read(path)?;
+        let loaded = absolute::load(&image, &mut self.memory.lock().unwrap())?;
+
+        self.apply_loaded_image(&loaded);
+
+        Ok(loaded)
+    }
+
+    // Unix a.out executable, 0411 ones need a CPU with separate I and D spaces
+    pub fn load_aout(&mut self, path: impl AsRef<Path>) -> Result<LoadedImage, LoadError> {
+        let image = fs::read(path)?;
+
+        if aout::Header::parse(&image)?.has_separate_spaces() && !self.cpu.model().has_separate_spaces() {
+            return Err(LoadError::BadFormat(format!("the {} has no separate I and D spaces", self.cpu.model())));
+        }
+
+        let loaded = aout::load(&image, &mut self.memory.lock().unwrap())?;
+
+        self.apply_loaded_image(&loaded);
+
+        Ok(loaded)
+    }
+
+    // RT-11 save image, started with the PC and SP of its job area
+    pub fn load_sav(&mut self, path: impl AsRef<Path>) -> Result<LoadedImage, LoadError> {
+        let image = fs::read(path)?;
+        let loaded = sav::load(&image, &mut self.memory.lock().unwrap())?;
+
+        self.apply_loaded_image(&loaded);
+
+        Ok(loaded)
+    }
+
+    // Memory dump from the address on, the start address stays as it was
+    pub fn load_raw(&mut self, path: impl AsRef<Path>, address: Word) -> Result<LoadedImage, LoadError> {
+        let image = fs::read(path)?;
+        let loaded = raw::load(&image, &mut self.memory.lock().unwrap(), address as Address)?;
+
+        self.apply_loaded_image(&loaded);
+
+        Ok(loaded)
+    }
+
+    // MACRO-11 source, assembled for the configured model
+    pub fn load_macro11(&mut self, path: impl AsRef<Path>) -> Result<LoadedImage, LoadError> {
+        let source = fs::read_to_string(path)?;
+        let program = Assembler::for_model(self.cpu.model()).assemble(&source).map_err(|error| LoadError::BadFormat(error.to_string()))?;
+        let loaded = program.load(&mut self.memory.lock().unwrap())?;
+
+        self.apply_loaded_image(&loaded);
+
+        Ok(loaded)
+    }
+
+    // Where boot starts the machine, overriding what the image said
+    pub fn set_start_address(&mut self, start_address: Word) {
+        self.start_address = start_address;
+        self.load_only = false;
+    }
+
+    pub fn start_address(&self) -> Word {
+        self.start_address
+    }
+
+    // Until a start address is set
+    pub fn is_load_only(&self) -> bool {
+        self.load_only
+    }
+
+    // Symbols of the last loaded image, empty if it had none
+    pub fn symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    fn apply_loaded_image(&mut self, loaded: &LoadedImage) {
+        self.load_only = loaded.load_only;
+
+        if let Some(start_address) = loaded.start_address {
+            self.start_address = start_address;
+        }
+
+        if let Some(stack_pointer) = loaded.stack_pointer {
+            self.stack_pointer = stack_pointer;
+        }
+
+        if let Some(data_space_base) = loaded.data_space_base {
+            self.cpu.map_separate_spaces(data_space_base);
+        }
+
+        self.symbols = loaded.symbols.clone();
+    }
+
+    // Continues where the machine stopped, e.g. after restoring a snapshot
+    pub fn resume(&mut self) {
+        if self.deterministic {
+            self.run_deterministic();
+        } else {
+            self.run_threaded();
+        }
+    }
+
+    // Clearing the flag stops the machine at the next instruction boundary
+    pub fn running_flag(&self) -> Arc<Mutex<bool>> {
+        self.cpu.running_flag()
+    }
+
+    // Same as clearing the running flag, for a machine run from a device or a debugger hook
+    pub fn stop(&self) {
+        self.cpu.stop();
+    }
+
+    pub fn register(&self, reg_index: Byte) -> Word {
+        self.cpu.register(reg_index)
+    }
+
+    pub fn set_register(&mut self, reg_index: Byte, value: Word) {
+        self.cpu.set_register(reg_index, value);
+    }
+
+    pub fn status_word(&self) -> Word {
+        self.cpu.status()
+    }
 
-        self.run_cpu();
+    pub fn set_status_word(&mut self, psw: Word) {
+        self.cpu.set_status(psw);
+    }
+
+    // Memory accesses take physical addresses and go through the bus like the CPU's,
+    // device registers included. Only valid while the machine is stopped
+    pub fn read_word(&self, address: Address) -> Result<Word, BusError> {
+        self.memory.lock().unwrap().read_word(address)
+    }
+
+    pub fn write_word(&mut self, address: Address, word: Word) -> Result<(), BusError> {
+        self.memory.lock().unwrap().write_word(address, word).map(|_| ())
+    }
+
+    pub fn read_byte(&self, address: Address) -> Result<Byte, BusError> {
+        self.memory.lock().unwrap().read_byte(address)
+    }
+
+    pub fn write_byte(&mut self, address: Address, byte: Byte) -> Result<(), BusError> {
+        self.memory.lock().unwrap().write_byte(address, byte).map(|_| ())
+    }
+
+    // Bytes from the address on, like a raw image
+    pub fn load_bytes(&mut self, address: Address, bytes: &[Byte]) -> Result<(), BusError> {
+        let mut memory = self.memory.lock().unwrap();
+
+        bytes.iter().try_fold(address, |address, byte| memory.write_byte(address, *byte)).map(|_| ())
+    }
+
+    // The device answers for its registers from now on and is serviced while the machine runs,
+    // on virtual time in deterministic mode and in a thread of its own otherwise
+    pub fn add_device<D: BusDevice + ScheduledDevice + Send + 'static>(&mut self, device: Arc<Mutex<D>>) {
+        self.memory.lock().unwrap().attach_device(device.clone());
+        self.devices.push(device);
+    }
+
+    // One line per executed instruction, None stops tracing
+    pub fn set_trace(&mut self, config: Option<TraceConfig>) -> io::Result<()> {
+        let tracer = match config {
+            Some(config) => Some(Tracer::create(config, self.cpu.model())?),
+            None => None,
+        };
+
+        self.cpu.set_tracer(tracer);
+
+        Ok(())
+    }
+
+    // Counts are kept from now on and reported to the path when the CPU halts, None stops profiling
+    pub fn set_profile(&mut self, report_path: Option<impl AsRef<Path>>) {
+        let profiler = report_path.map(|path| Profiler::new().with_symbols(self.symbols.clone()).with_report_path(path));
+
+        self.cpu.set_profiler(profiler);
+    }
+
+    // Typing it in the terminal stops the machine, None passes every key to the DL11.
+    // A deterministic machine reads the terminal only while one is set
+    pub fn set_escape_char(&mut self, escape_char: Option<Byte>) {
+        self.dl11tty.lock().unwrap().set_escape_char(escape_char, self.cpu.running_flag());
+    }
+
+    // Where the DL11 prints, None is the terminal
+    pub fn set_console_output(&mut self, output: Option<Box<dyn Write + Send>>) {
+        self.dl11tty.lock().unwrap().set_output(output);
+    }
+
+    pub fn has_console(&self) -> bool {
+        self.console
+    }
+
+    pub fn has_line_clock(&self) -> bool {
+        self.line_clock
+    }
+
+    // RCSR, RBUF, XCSR and XBUF
+    pub fn tty_registers(&self) -> [Word; 4] {
+        self.dl11tty.lock().unwrap().register_words()
+    }
+
+    pub fn line_clock_status(&self) -> Word {
+        self.kw11l.lock().unwrap().status_word()
+    }
+
+    pub fn line_clock_rate(&self) -> u32 {
+        self.kw11l.lock().unwrap().tick_rate()
+    }
+
+    // Input for the DL11 receiver, on top of what is typed in the terminal
+    pub fn queue_input(&self, chars: &[Byte]) {
+        self.dl11tty.lock().unwrap().queue_input(chars);
+    }
+
+    fn run_threaded(&mut self) {
+        // Devices stop as soon as the CPU is not running
+        *self.cpu.running_flag().lock().unwrap() = true;
+
+        let dl11tty_thread = self.console.then(|| self.run_tty());
+        let kw11l_thread = self.line_clock.then(|| self.run_line_clock());
+        let device_threads: Vec<_> = self.devices.iter().map(|device| self.run_device(device.clone())).collect();
+
+        self.cpu.resume(self.memory.clone());
+
+        for thread in [dl11tty_thread, kw11l_thread].into_iter().flatten().chain(device_threads) {
+            let _ = thread.join();
+        }
+
+        self.flush_console();
+    }
 
-        let _ = dl11tty_thread.join();
+    // Same program and input, same instruction trace
+    fn run_deterministic(&mut self) {
+        let mut scheduler = self.scheduled_devices();
+
+        scheduler.run(&mut self.cpu, self.memory.clone());
+
+        self.flush_console();
+    }
+
+    // Single steps run on virtual time whatever the mode, the machine is stopped afterwards
+    pub fn step(&mut self, count: u64) {
+        let mut scheduler = self.scheduled_devices();
+
+        scheduler.step(&mut self.cpu, self.memory.clone(), count);
+
+        self.flush_console();
+    }
+
+    fn scheduled_devices(&self) -> Scheduler {
+        let mut scheduler = Scheduler::new();
+
+        if self.console {
+            scheduler.add_device(self.dl11tty.clone());
+        }
+
+        if self.line_clock {
+            scheduler.add_device(self.kw11l.clone());
+        }
+
+        for device in &self.devices {
+            scheduler.add_device(device.clone());
+        }
+
+        scheduler
     }
 
-    pub fn run_async(mut self) -> JoinHandle<()> {
+    // A character the CPU wrote just before it stopped is still printed
+    fn flush_console(&mut self) {
+        if self.console {
+            self.dl11tty.lock().unwrap().flush(self.cpu.interruption_bus());
+        }
+    }
+
+    // Power-up state for the CPU and every device, memory contents are kept
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.dl11tty.lock().unwrap().reset();
+        self.kw11l.lock().unwrap().reset();
+        self.memory.lock().unwrap().reset_devices();
+    }
+
+    // The machine is handed back once it stops, so it can be snapshotted or resumed
+    pub fn run_async(mut self) -> JoinHandle<Self> {
         thread::spawn(move || {
             self.run();
+            self
         })
     }
 
+    // Only valid while the machine is stopped
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let mut writer = SnapshotWriter::new();
+
+        self.cpu.save_state(&mut writer);
+        self.memory.lock().unwrap().save_state(&mut writer);
+        self.dl11tty.lock().unwrap().save_state(&mut writer);
+        self.kw11l.lock().unwrap().save_state(&mut writer);
+
+        fs::write(path, writer.into_bytes())?;
+
+        Ok(())
+    }
+
+    // The machine has to be configured like the one the snapshot was taken on
+    pub fn restore_snapshot(&mut self, path: impl AsRef<Path>) -> Result<(), SnapshotError> {
+        let bytes = fs::read(path)?;
+        let mut reader = SnapshotReader::new(&bytes)?;
+
+        self.cpu.restore_state(&mut reader)?;
+        self.memory.lock().unwrap().restore_state(&mut reader)?;
+        self.dl11tty.lock().unwrap().restore_state(&mut reader)?;
+        self.kw11l.lock().unwrap().restore_state(&mut reader)
+    }
+
     fn run_tty(&mut self) -> JoinHandle<()> {
         let cpu_running_flag = self.cpu.running_flag();
         let interruption_bus = self.cpu.interruption_bus();
 
-        let dl11tty_memory_clone = self.memory.clone();
-        
         let dl11tty = self.dl11tty.clone();
-        
-        let dl11tty_thread = thread::spawn(move || {
-            thread::sleep(Duration::from_secs(1));
-            dl11tty.lock().unwrap().run(interruption_bus, dl11tty_memory_clone, cpu_running_flag);
-        });
 
-        dl11tty_thread
+        thread::spawn(move || {
+            dl11tty.lock().unwrap().run(interruption_bus, cpu_running_flag);
+        })
+    }
+
+    fn run_line_clock(&mut self) -> JoinHandle<()> {
+        let cpu_running_flag = self.cpu.running_flag();
+        let interruption_bus = self.cpu.interruption_bus();
+
+        let kw11l = self.kw11l.clone();
+
+        thread::spawn(move || {
+            kw11l.lock().unwrap().run(interruption_bus, cpu_running_flag);
+        })
     }
 
-    fn run_cpu(&mut self) {
-        self.cpu.run(self.memory.clone());
+    fn run_device(&self, device: Arc<Mutex<dyn ScheduledDevice + Send>>) -> JoinHandle<()> {
+        let cpu_running_flag = self.cpu.running_flag();
+        let interruption_bus = self.cpu.interruption_bus();
+
+        thread::spawn(move || {
+            let period = real_duration(device.lock().unwrap().service_period());
+
+            while *cpu_running_flag.lock().unwrap() {
+                device.lock().unwrap().service(interruption_bus.clone());
+                thread::sleep(period);
+            }
+        })
     }
 }
\ No newline at end of file
@@ -0,0 +1,57 @@
+use std::{sync::{Arc, Mutex}, time::Instant};
+
+use crate::{cpu::{commands::CommandLookup, CPU, FIRST_COMMAND}, mem::Memory, utils::Word};
+
+pub const DEFAULT_OUTER_LOOPS: Word = 0x0200;
+
+pub fn run_benchmark(outer_loops: Word) {
+    let probing = measure(CommandLookup::Probing, outer_loops);
+    let dispatch_table = measure(CommandLookup::DispatchTable, outer_loops);
+
+    println!("Probing:        {probing:>12.0} instructions/s");
+    println!("Dispatch table: {dispatch_table:>12.0} instructions/s");
+    println!("Speedup:        {:>12.2}x", dispatch_table / probing);
+}
+
+// Instructions per second for the benchmark program
+fn measure(command_lookup: CommandLookup, outer_loops: Word) -> f64 {
+    let mut cpu = CPU::default();
+    cpu.set_command_lookup(command_lookup);
+
+    let memory = make_benchmark_program(outer_loops);
+
+    let start = Instant::now();
+    cpu.run(memory);
+    let elapsed = start.elapsed();
+
+    cpu.executed_instructions() as f64 / elapsed.as_secs_f64()
+}
+
+// A mix of two-operand, one-operand and branch instructions in a double SOB loop
+fn make_benchmark_program(outer_loops: Word) -> Arc<Mutex<Memory>> {
+    let words = [
+        0x15C1, outer_loops,    // MOV #outer_loops, R1
+        // outer
+        0x15C0, 0x0100,         // MOV #400, R0
+        // inner
+        0x6002,                 // ADD R0, R2
+        0x0A83,                 // INC R3
+        0x2083,                 // CMP R2, R3
+        0x0301,                 // BEQ +1
+        0x0C42,                 // ROL R2
+        0x7804,                 // XOR R0, R4
+        0x7E07,                 // SOB R0, inner
+        0x7E4A,                 // SOB R1, outer
+        0x0000,
+    ];
+
+    let mem = Memory::new();
+
+    let mut address = FIRST_COMMAND;
+
+    for word in words {
+        address = mem.lock().unwrap().write_word(address, word).unwrap();
+    }
+
+    mem
+}
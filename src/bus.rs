@@ -0,0 +1,86 @@
+use std::{ops::Range, sync::{Arc, Mutex}};
+
+use crate::{mem::{BusError, MappedMemoryWord}, utils::{Address, Number, Word}};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AccessWidth {
+    Byte,
+    Word,
+}
+
+// A Unibus device answering for a range of the I/O page
+pub trait BusDevice {
+    // Given by 16-bit I/O page addresses, like the register constants of the devices
+    fn address_range(&self) -> Range<Address>;
+
+    // Byte accesses carry the byte in the low half, the address tells which one it is
+    fn read(&mut self, address: Address, width: AccessWidth) -> Result<Word, BusError>;
+
+    fn write(&mut self, address: Address, width: AccessWidth, data: Word) -> Result<(), BusError>;
+
+    // A read without side effects, for debuggers and monitors
+    fn peek(&self, address: Address, width: AccessWidth) -> Result<Word, BusError>;
+
+    // Bus INIT from RESET
+    fn reset(&mut self) {}
+
+    // The CPU took the interrupt at the vector address
+    fn interrupt_acknowledge(&mut self, _vector_address: Address) {}
+}
+
+pub type SharedBusDevice = Arc<Mutex<dyn BusDevice + Send>>;
+
+pub fn read_register(register: &(impl MappedMemoryWord + ?Sized), address: Address, width: AccessWidth) -> Word {
+    match width {
+        AccessWidth::Word => register.read_word(),
+        AccessWidth::Byte => register.read_byte(is_high_byte(address)).word(),
+    }
+}
+
+pub fn write_register(register: &mut (impl MappedMemoryWord + ?Sized), address: Address, width: AccessWidth, data: Word) {
+    match width {
+        AccessWidth::Word => register.write_word(data),
+        AccessWidth::Byte => register.write_byte(data.low(), is_high_byte(address)),
+    }
+}
+
+fn is_high_byte(address: Address) -> bool {
+    address & 0x1 != 0
+}
+
+// A single register mapped with Memory::map_word, the devices of the machine claim whole ranges instead
+pub struct MappedWordDevice {
+    address: Address,
+    word: Arc<Mutex<dyn MappedMemoryWord + Send + Sync>>,
+}
+
+impl MappedWordDevice {
+    pub fn new(address: Address, word: Arc<Mutex<dyn MappedMemoryWord + Send + Sync>>) -> Self {
+        MappedWordDevice { address, word }
+    }
+}
+
+impl BusDevice for MappedWordDevice {
+    fn address_range(&self) -> Range<Address> {
+        self.address..self.address + 2
+    }
+
+    fn read(&mut self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        Ok(read_register(&*self.word.lock().unwrap(), address, width))
+    }
+
+    fn write(&mut self, address: Address, width: AccessWidth, data: Word) -> Result<(), BusError> {
+        write_register(&mut *self.word.lock().unwrap(), address, width, data);
+
+        Ok(())
+    }
+
+    // Plain mapped words can't tell a peek from a read
+    fn peek(&self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        Ok(read_register(&*self.word.lock().unwrap(), address, width))
+    }
+
+    fn reset(&mut self) {
+        self.word.lock().unwrap().reset();
+    }
+}
@@ -0,0 +1,232 @@
+use std::{fs::{self, File}, path::PathBuf};
+
+use pdp11_rust::{assembly::{Pdp11, Pdp11Config}, cpu::{trace::TraceConfig, HaltReason}, gdb, loader::ImageFormat, mem::{MAX_MEMORY_SIZE, MIN_MEMORY_SIZE}, utils::Word};
+
+pub const USAGE: &str = "\
+Usage: pdp11-rust [run] [OPTIONS] [IMAGE]
+       pdp11-rust test          Run the built-in CPU tests
+       pdp11-rust benchmark     Compare the instruction dispatch strategies
+       pdp11-rust help
+
+Addresses are octal. The exit code is the low byte of R0 after a HALT,
+255 when the machine stopped on a fault, 0 when it was stopped from outside.
+
+Image:
+  --format=FORMAT         lda, aout, sav, mac or raw, by extension otherwise
+  --address=ADDR          Where a raw image is loaded, 0 by default
+  --start=ADDR            Start PC, instead of the one the image gives
+
+Machine:
+  --cpu=MODEL             11/03, 11/20, 11/40, 11/45, 11/70, 11/73 or 11/83 (11/70)
+                          FADD, FSUB, FMUL and FDIV need 11/03 or 11/40
+  --memory=SIZE           Bytes, or with a K or M suffix (248K)
+  --devices=LIST          Comma separated from dl11 and kw11l, or none (dl11,kw11l)
+  --clock-rate=HZ         KW11-L tick rate (60)
+  --deterministic         Devices run on virtual time in the CPU thread
+
+Console:
+  --console-input=FILE    Received by the DL11 before anything typed
+  --console-output=FILE   What the DL11 prints goes to the file
+
+Debugging:
+  --trace=FILE            One line per executed instruction
+  --profile=FILE          Hot spots and call graph, written when the CPU halts
+  --monitor               Start in the monitor instead of running
+  --gdb[=PORT]            Wait for gdb on localhost (1234)";
+
+pub const USAGE_EXIT_CODE: u8 = 2;
+pub const FAULT_EXIT_CODE: u8 = 255;
+
+pub enum CliCommand {
+    Run(Box<RunOptions>),
+    Test,
+    Benchmark,
+    Help,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Frontend {
+    Console, // Runs until the machine halts
+    Monitor,
+    Gdb(u16),
+}
+
+#[derive(Clone, Debug)]
+pub struct RunOptions {
+    pub image: Option<PathBuf>,
+    pub format: Option<ImageFormat>, // None picks it by extension
+    pub load_address: Option<Word>,
+    pub start_address: Option<Word>,
+    pub config: Pdp11Config,
+    pub console_input: Option<PathBuf>,
+    pub console_output: Option<PathBuf>,
+    pub trace: Option<PathBuf>,
+    pub profile: Option<PathBuf>,
+    pub frontend: Frontend,
+}
+
+impl Default for RunOptions {
+    fn default() -> Self {
+        RunOptions {
+            image: None,
+            format: None,
+            load_address: None,
+            start_address: None,
+            config: Pdp11Config::default(),
+            console_input: None,
+            console_output: None,
+            trace: None,
+            profile: None,
+            frontend: Frontend::Console,
+        }
+    }
+}
+
+// The arguments without the program name
+pub fn parse(args: impl IntoIterator<Item = String>) -> Result<CliCommand, String> {
+    let mut args = args.into_iter().peekable();
+
+    let command = match args.peek().map(String::as_str) {
+        Some("test") => CliCommand::Test,
+        Some("benchmark") => CliCommand::Benchmark,
+        Some("help") => CliCommand::Help,
+        Some("run") => {
+            args.next();
+            return parse_run_options(args);
+        },
+        _ => return parse_run_options(args),
+    };
+
+    args.next();
+
+    match args.next() {
+        Some(arg) => Err(format!("unexpected argument {arg}")),
+        None => Ok(command),
+    }
+}
+
+fn parse_run_options(args: impl Iterator<Item = String>) -> Result<CliCommand, String> {
+    let mut options = RunOptions::default();
+
+    for arg in args {
+        let (name, argument) = match arg.split_once('=') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (arg.as_str(), None),
+        };
+
+        let value = || argument.ok_or(format!("{name} needs a value"));
+
+        match name {
+            "-h" | "--help" => return Ok(CliCommand::Help),
+            "--format" => options.format = Some(value()?.parse()?),
+            "--address" => options.load_address = Some(parse_address(value()?)?),
+            "--start" => options.start_address = Some(parse_address(value()?)?),
+            "--cpu" => options.config.model = value()?.parse()?,
+            "--memory" => options.config.memory_size = parse_memory_size(value()?)?,
+            "--devices" => (options.config.console, options.config.line_clock) = parse_devices(value()?)?,
+            "--clock-rate" => options.config.line_clock_rate = value()?.parse().map_err(|_| format!("bad clock rate {arg}"))?,
+            "--deterministic" => options.config.deterministic = true,
+            "--console-input" => options.console_input = Some(PathBuf::from(value()?)),
+            "--console-output" => options.console_output = Some(PathBuf::from(value()?)),
+            "--trace" => options.trace = Some(PathBuf::from(value()?)),
+            "--profile" => options.profile = Some(PathBuf::from(value()?)),
+            "--monitor" => options.frontend = Frontend::Monitor,
+            "--gdb" => options.frontend = Frontend::Gdb(match argument {
+                Some(port) => port.parse().map_err(|_| format!("bad port {port}"))?,
+                None => gdb::DEFAULT_PORT,
+            }),
+            _ if name.starts_with('-') => return Err(format!("unknown option {name}")),
+            _ if options.image.is_some() => return Err(format!("more than one image: {arg}")),
+            _ => options.image = Some(PathBuf::from(arg)),
+        }
+    }
+
+    if options.load_address.is_some() && options.format != Some(ImageFormat::Raw) {
+        return Err("--address needs --format=raw".to_string());
+    }
+
+    Ok(CliCommand::Run(Box::new(options)))
+}
+
+fn parse_address(text: &str) -> Result<Word, String> {
+    Word::from_str_radix(text, 8).map_err(|_| format!("bad octal address {text}"))
+}
+
+// 262144, 256K and 4M alike
+fn parse_memory_size(text: &str) -> Result<usize, String> {
+    let (digits, unit) = match text.to_ascii_uppercase() {
+        upper if upper.ends_with('K') => (text[..text.len() - 1].to_string(), 1024),
+        upper if upper.ends_with('M') => (text[..text.len() - 1].to_string(), 1024 * 1024),
+        _ => (text.to_string(), 1),
+    };
+
+    let size = digits.parse::<usize>().ok().and_then(|size| size.checked_mul(unit)).ok_or(format!("bad memory size {text}"))?;
+
+    if !(MIN_MEMORY_SIZE..=MAX_MEMORY_SIZE).contains(&size) || !size.is_multiple_of(2) {
+        return Err(format!("memory size {text} is not an even size from {}K to {}K", MIN_MEMORY_SIZE / 1024, MAX_MEMORY_SIZE / 1024));
+    }
+
+    Ok(size)
+}
+
+// Console and line clock
+fn parse_devices(text: &str) -> Result<(bool, bool), String> {
+    let mut devices = (false, false);
+
+    for name in text.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+        match name.to_ascii_lowercase().as_str() {
+            "dl11" | "tty" => devices.0 = true,
+            "kw11l" | "kw11-l" | "clock" => devices.1 = true,
+            "none" => {},
+            _ => return Err(format!("unknown device {name}")),
+        }
+    }
+
+    Ok(devices)
+}
+
+impl RunOptions {
+    // Configured, loaded and ready to boot
+    pub fn build(&self) -> Result<Pdp11, String> {
+        let mut machine = Pdp11::with_config(self.config);
+
+        if let Some(image) = &self.image {
+            let format = self.format.unwrap_or(ImageFormat::from_path(image));
+
+            machine.load_as(image, format, self.load_address).map_err(|error| format!("can't load {}: {error}", image.display()))?;
+        }
+
+        if let Some(start_address) = self.start_address {
+            machine.set_start_address(start_address);
+        }
+
+        if let Some(path) = &self.console_input {
+            let input = fs::read(path).map_err(|error| format!("can't read {}: {error}", path.display()))?;
+
+            machine.queue_input(&input);
+        }
+
+        if let Some(path) = &self.console_output {
+            let output = File::create(path).map_err(|error| format!("can't create {}: {error}", path.display()))?;
+
+            machine.set_console_output(Some(Box::new(output)));
+        }
+
+        if let Some(path) = &self.trace {
+            machine.set_trace(Some(TraceConfig::new(path))).map_err(|error| format!("can't trace to {}: {error}", path.display()))?;
+        }
+
+        machine.set_profile(self.profile.as_ref());
+
+        Ok(machine)
+    }
+}
+
+// What the process exits with once the machine stopped
+pub fn exit_code(machine: &Pdp11) -> u8 {
+    match machine.cpu().halt_reason() {
+        None => 0,
+        Some(HaltReason::HaltInstruction(_)) => machine.cpu().dump_state().registers[0] as u8,
+        Some(_) => FAULT_EXIT_CODE,
+    }
+}
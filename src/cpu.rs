@@ -1,22 +1,41 @@
-use std::sync::{Arc, Mutex};
+use std::{collections::BTreeMap, sync::{Arc, Mutex}};
 
 use addressing::{adressing_from_operand, register_from_operand, AddressingMode};
+use breakpoints::{BreakCondition, Breakpoint};
 use commands::*;
 use interruptions::InterruptionBus;
+use mmu::{AddressSpace, Mmu};
+use fpu::{dec_float, Fpu};
+use model::CpuModel;
+use status::StatusRegister;
+use trace::Tracer;
+use profiler::Profiler;
 
-use crate::{mem::{MappedMemoryWord, Memory, SimpleMappedMemoryWord}, utils::*};
+use crate::{mem::{MappedMemoryWord, Memory, WatchpointHit}, utils::*};
 
 pub mod addressing;
+pub mod breakpoints;
 pub mod interpreter;
 pub mod interruptions;
 pub mod debug;
+pub mod disassembler;
 pub mod commands;
+pub mod mmu;
+pub mod fpu;
+pub mod model;
+pub mod profiler;
+pub mod snapshot;
+pub mod status;
+pub mod trace;
 
 pub const FIRST_COMMAND: Address = 0x0200;
 pub const STACK_START: Address = 0x0200;
 
 pub const FLAGS_IN_MEMORY: Address = 0xFFFE;
 
+pub const BUS_ERROR_TRAP_VECTOR: Address = 0x0004; // 4 (oct)
+pub const RESERVED_INSTRUCTION_TRAP_VECTOR: Address = 0x0008; // 10 (oct)
+
 pub const REG_COUNT: usize = 8;
 
 pub const MARK_POINTER_INDEX: Byte = 5; // Or MP
@@ -33,37 +52,119 @@ pub const PRIORITY_LOW_BIT_INDEX: Byte = 5;
 pub const PRIORITY_MIDDLE_BIT_INDEX: Byte = 6;
 pub const PRIORITY_HIGH_BIT_INDEX: Byte = 7;
 
+pub const PREVIOUS_MODE_LOW_BIT_INDEX: Byte = 12;
+pub const PREVIOUS_MODE_HIGH_BIT_INDEX: Byte = 13;
+pub const CURRENT_MODE_LOW_BIT_INDEX: Byte = 14;
+pub const CURRENT_MODE_HIGH_BIT_INDEX: Byte = 15;
+
+pub const MODE_COUNT: usize = 4;
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ProcessorMode {
+    Kernel = 0x0,
+    Supervisor = 0x1,
+    Illegal = 0x2,
+    User = 0x3,
+}
+
+impl From<Byte> for ProcessorMode {
+    fn from(byte: Byte) -> ProcessorMode {
+        match byte & 0x03 {
+            0x00 => ProcessorMode::Kernel,
+            0x01 => ProcessorMode::Supervisor,
+            0x02 => ProcessorMode::Illegal,
+            _ => ProcessorMode::User,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HaltReason {
+    HaltInstruction(Address),
+    DoubleFault { vector: Address, fault_vector: Address }, // Fault while trapping through vector
+    Breakpoint(Address),
+    Watchpoint(WatchpointHit),
+}
+
+impl std::fmt::Display for HaltReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HaltReason::HaltInstruction(address) => write!(f, "HALT instruction at {address:06o}"),
+            HaltReason::DoubleFault { vector, fault_vector } => write!(f, "double fault, trap to {fault_vector:03o} while trapping to {vector:03o}"),
+            HaltReason::Breakpoint(address) => write!(f, "breakpoint at {address:06o}"),
+            HaltReason::Watchpoint(hit) => write!(f, "{hit}"),
+        }
+    }
+}
+
 pub struct CPU {
-    status: Arc<Mutex<SimpleMappedMemoryWord>>, // Or PSW (Processor Status Word)
+    model: CpuModel,
+    status: Arc<Mutex<StatusRegister>>, // Or PSW (Processor Status Word)
     registers: [Word; REG_COUNT],
+    stack_pointers: [Word; MODE_COUNT], // Banked SP of every mode, the active one lives in registers
+    stack_pointer_mode: ProcessorMode,
     commands: Arc<Commands>,
+    command_lookup: CommandLookup,
+    executed_instructions: u64,
     running: Arc<Mutex<bool>>,
     waiting: bool,
     interruption_bus: Arc<Mutex<InterruptionBus>>,
+    mmu: Mmu,
+    fpu: Fpu,
+    command_address: Address,
+    pending_trap: Option<Address>, // Abort raised in the middle of an instruction
+    halt_reason: Option<HaltReason>,
+    breakpoints: BTreeMap<Word, Option<BreakCondition>>, // By virtual PC value
+    skip_breakpoint: bool, // The first instruction after attaching runs even if it has a breakpoint
+    tracer: Option<Tracer>,
+    profiler: Option<Profiler>,
 }
 
 // Constructors
 impl CPU {
-    pub fn new(commands: Arc<Commands>) -> Self {
+    pub fn new(model: CpuModel, commands: Arc<Commands>) -> Self {
         CPU {
-            status: Arc::new(Mutex::new(SimpleMappedMemoryWord::new())),
+            model,
+            status: Arc::new(Mutex::new(StatusRegister::new())),
             registers: [0; REG_COUNT],
-            commands: commands,
+            stack_pointers: [0; MODE_COUNT],
+            stack_pointer_mode: ProcessorMode::Kernel,
+            commands,
+            command_lookup: CommandLookup::DispatchTable,
+            executed_instructions: 0,
             running: Arc::new(Mutex::new(false)),
             waiting: false,
             interruption_bus: Arc::new(Mutex::new(InterruptionBus::new())),
+            mmu: Mmu::with_addressing(model.has_22_bit_addressing()),
+            fpu: Fpu::new(),
+            command_address: FIRST_COMMAND,
+            pending_trap: None,
+            halt_reason: None,
+            breakpoints: BTreeMap::new(),
+            skip_breakpoint: false,
+            tracer: None,
+            profiler: None,
         }
     }
+
+    pub fn for_model(model: CpuModel) -> Self {
+        Self::new(model, Arc::new(Commands::for_model(model)))
+    }
 }
 
 impl Default for CPU {
     fn default() -> Self {
-        Self::new(Arc::new(Commands::default()))
+        Self::for_model(CpuModel::default())
     }
 }
 
 // Execution
 impl CPU {
+    pub fn model(&self) -> CpuModel {
+        self.model
+    }
+
     pub fn running_flag(&self) -> Arc<Mutex<bool>> {
         self.running.clone()
     }
@@ -73,53 +174,223 @@ impl CPU {
     }
 
     pub fn run(&mut self, mem: Arc<Mutex<Memory>>) {
-        self.map_status_word(mem.clone());
+        self.boot_registers();
+
+        self.resume(mem);
+    }
+
+    // Continues from the current state, as left by a stop or a restored snapshot
+    pub fn resume(&mut self, mem: Arc<Mutex<Memory>>) {
+        self.attach(mem.clone());
+
+        while self.is_running() {
+            self.tick(mem.clone());
+        }
+
+        self.detach(mem);
+    }
+
+    pub fn boot_registers(&mut self) {
+        self.boot_registers_at(FIRST_COMMAND as Word, STACK_START as Word);
+    }
+
+    // Split I/D images run in kernel mode with relocation on
+    pub fn map_separate_spaces(&mut self, data_space_base: Address) {
+        self.mmu.map_kernel_spaces(data_space_base);
+    }
+
+    // Where the current mode reaches the 16-bit address, None if its page is not mapped
+    pub fn physical_address(&self, address: Address, space: AddressSpace) -> Option<Address> {
+        self.mmu.peek_translate(address & 0xFFFF, self.current_mode(), space)
+    }
+
+    // For loaded images that say where they start
+    pub fn boot_registers_at(&mut self, start_address: Word, stack_pointer: Word) {
+        self.set_word_reg(PROGRAM_COUNTER_INDEX, start_address);
+        self.set_word_reg(STACK_POINTER_INDEX, stack_pointer);
+    }
+
+    // Maps the CPU registers into memory and marks the CPU as running
+    pub fn attach(&mut self, mem: Arc<Mutex<Memory>>) {
+        if self.model.has_memory_mapped_psw() {
+            self.map_status_word(mem.clone());
+        }
+
+        if self.model.has_mmu() {
+            self.mmu.map_registers(&mut mem.lock().unwrap());
+        }
+
+        // Writes made while stopped, by loaders or debuggers, are not the program's
+        mem.lock().unwrap().take_watchpoint_hit();
 
         *self.running.lock().unwrap() = true;
-        self.set_word_reg(PROGRAM_COUNTER_INDEX, FIRST_COMMAND as Word);
-        self.set_word_reg(STACK_POINTER_INDEX, STACK_START as Word);
+        self.halt_reason = None;
+        self.skip_breakpoint = true;
+    }
 
-        while *self.running.lock().unwrap() {
-            trace!("tick");
+    pub fn detach(&mut self, mem: Arc<Mutex<Memory>>) {
+        if self.model.has_mmu() {
+            self.mmu.unmap_registers(&mut mem.lock().unwrap());
+        }
 
-            if !self.waiting {
-                self.step(mem.clone());
-                //self.trace_registers();
-            }
+        if self.model.has_memory_mapped_psw() {
+            self.unmap_status_word(mem.clone());
+        }
+
+        // The trace is complete whenever the machine is stopped
+        if let Some(Err(error)) = self.tracer.as_mut().map(|tracer| tracer.flush()) {
+            error!("Can't flush the trace: {error}");
+        }
+
+        if let Some(Err(error)) = self.halt_reason.and(self.profiler.as_ref()).map(|profiler| profiler.write_report()) {
+            error!("Can't write the profile: {error}");
+        }
+
+        if let Some(reason) = self.halt_reason {
+            info!("CPU halted: {reason}");
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        *self.running.lock().unwrap()
+    }
 
-            self.process_interruption_if_needed(mem.clone());
+    // One instruction, or an idle cycle while waiting, then the interrupts
+    pub fn tick(&mut self, mem: Arc<Mutex<Memory>>) {
+        trace!("tick");
+
+        if !self.waiting {
+            self.step(mem.clone());
             //self.trace_registers();
+
+            // No interrupt is taken once halted
+            if self.halt_reason.is_some() {
+                return;
+            }
         }
 
-        self.unmap_status_word(mem.clone());
+        self.process_interruption_if_needed(mem);
+        //self.trace_registers();
+    }
+
+    pub fn executed_instructions(&self) -> u64 {
+        self.executed_instructions
+    }
+
+    pub fn set_command_lookup(&mut self, command_lookup: CommandLookup) {
+        self.command_lookup = command_lookup;
+    }
+
+    // Back to the power-up state, memory and devices are left to the machine
+    pub fn reset(&mut self) {
+        self.status.lock().unwrap().write_word(0x0000u16);
+        self.registers = [0; REG_COUNT];
+        self.stack_pointers = [0; MODE_COUNT];
+        self.stack_pointer_mode = ProcessorMode::Kernel;
+        self.executed_instructions = 0;
+        *self.running.lock().unwrap() = false;
+        self.waiting = false;
+        self.interruption_bus.lock().unwrap().clear();
+        self.mmu = Mmu::with_addressing(self.model.has_22_bit_addressing());
+        self.fpu = Fpu::new();
+        self.command_address = FIRST_COMMAND;
+        self.pending_trap = None;
+        self.halt_reason = None;
+    }
+
+    pub fn halt_reason(&self) -> Option<HaltReason> {
+        self.halt_reason
+    }
+
+    // Stops at the next instruction boundary, from any thread holding the running flag works too
+    pub fn stop(&self) {
+        *self.running.lock().unwrap() = false;
+    }
+
+    pub fn register(&self, reg_index: Byte) -> Word {
+        self.registers[reg_index as usize]
+    }
+
+    pub fn set_register(&mut self, reg_index: Byte, value: Word) {
+        self.set_word_reg(reg_index, value);
+    }
+
+    pub fn status(&self) -> Word {
+        self.status_word()
+    }
+
+    pub fn set_status(&mut self, psw: Word) {
+        self.set_status_word(psw);
+    }
+
+    fn halt(&mut self, reason: HaltReason) {
+        self.halt_reason = Some(reason);
+        *self.running.lock().unwrap() = false;
     }
 
     fn step(&mut self, mem: Arc<Mutex<Memory>>) {
         let mut memory = mem.lock().unwrap();
 
-        let (address, command_word) = self.next_command(&mut memory);
+        // The PSW may have been written through memory by the previous instruction
+        self.sync_stack_pointer();
+
+        let Some((address, command_word)) = self.next_command(&mut memory) else {
+            return; // Stopped at a breakpoint
+        };
+
+        // The fetch itself failed, the instruction is never executed
+        if self.pending_trap.is_some() {
+            self.perform_pending_trap_if_any(&mut memory);
+            return;
+        }
     
         trace!("processing next instruction");
         trace!("address 0x{address:04X}");
         trace!("instruction 0x{command_word:04X}");
 
+        let trace_start = self.begin_trace(&mut memory, address, command_word);
+
         let Command(command_opcode, command_name, command_interpreter) = 
-            self.command(command_word);
+            *self.command(command_word);
 
         trace!("command 0x{command_opcode:04X} ({command_name})");  
+        self.profile_instruction(address, command_name);
         command_interpreter(self, &mut memory, command_word);
 
+        self.executed_instructions += 1;
+
+        self.perform_pending_trap_if_any(&mut memory);
+
         if self.trap_flag() {
             self.do_bpt(&mut memory, 0x0000u16);
         }
+
+        if let Some(trace_start) = trace_start {
+            self.end_trace(&mut memory, trace_start);
+        }
+
+        if let Some(hit) = memory.take_watchpoint_hit() {
+            self.halt(HaltReason::Watchpoint(hit));
+        }
     }
 
-    fn next_command(&mut self, memory: &mut Memory) -> (Address, Word) {
+    fn next_command(&mut self, memory: &mut Memory) -> Option<(Address, Word)> {
+        if self.at_breakpoint() {
+            self.halt(HaltReason::Breakpoint(self.registers[PROGRAM_COUNTER_INDEX as usize] as Address));
+            return None;
+        }
+
         let address: Address = self.get_and_increment(PROGRAM_COUNTER_INDEX, Word::size_bytes().into()).into();
 
-        let command: Word = memory.read_word(address);
+        self.command_address = address;
+        self.mmu.begin_instruction(address);
 
-        (address, command)
+        let command: Word = self.read_virtual_word(memory, address, AddressSpace::Instruction);
+
+        // Executing watched code is not reading it
+        memory.take_watchpoint_hit();
+
+        Some((address, command))
     }
 
     fn process_interruption_if_needed(&mut self, mem: Arc<Mutex<Memory>>) {
@@ -128,7 +399,21 @@ impl CPU {
 
             self.waiting = false;
             let mut memory = mem.lock().unwrap();
+            memory.acknowledge_interrupt(interruption_address);
             self.perform_trap(&mut memory, interruption_address);
+            self.perform_pending_trap_if_any(&mut memory);
+
+            if let Some(hit) = memory.take_watchpoint_hit() {
+                self.halt(HaltReason::Watchpoint(hit));
+            }
+        }
+    }
+
+    fn perform_pending_trap_if_any(&mut self, memory: &mut Memory) {
+        if let Some(trap_address) = self.pending_trap.take() {
+            trace!("processing an abort trap from address 0x{trap_address:04X}");
+
+            self.perform_trap(memory, trap_address);
         }
     }
 
@@ -137,11 +422,11 @@ impl CPU {
     }
 
     fn map_status_word(&mut self, mem: Arc<Mutex<Memory>>) {
-        mem.lock().unwrap().map_word(FLAGS_IN_MEMORY, self.status.clone());
+        mem.lock().unwrap().attach_device(self.status.clone());
     }
 
     fn unmap_status_word(&mut self, mem: Arc<Mutex<Memory>>) {
-        mem.lock().unwrap().unmap_word(FLAGS_IN_MEMORY);
+        mem.lock().unwrap().detach_device(FLAGS_IN_MEMORY);
     }
 }
 
@@ -156,11 +441,11 @@ impl CPU {
     }
 
     fn put_byte(&mut self, memory: &mut Memory, reg_index: Byte, addressing: AddressingMode, byte: Byte) {
-        self.put_operand_value_with_addressing(memory, reg_index, addressing, byte, Memory::write_byte, CPU::set_byte_reg)
+        self.put_operand_value_with_addressing(memory, reg_index, addressing, byte, CPU::write_virtual_byte, CPU::set_byte_reg)
     }
 
     fn put_word(&mut self, memory: &mut Memory, reg_index: Byte, addressing: AddressingMode, word: Word) {
-        self.put_operand_value_with_addressing(memory, reg_index, addressing, word, Memory::write_word, CPU::set_word_reg)
+        self.put_operand_value_with_addressing(memory, reg_index, addressing, word, CPU::write_virtual_word, CPU::set_word_reg)
     }
 }
 
@@ -175,11 +460,11 @@ impl CPU {
     }
 
     fn get_byte(&mut self, memory: &Memory, reg_index: Byte, addressing: AddressingMode) -> Byte {
-        self.get_operand_value_with_addressing(memory, reg_index, addressing, Memory::read_byte, Self::get_byte_from_reg)
+        self.get_operand_value_with_addressing(memory, reg_index, addressing, Self::read_virtual_byte, Self::get_byte_from_reg)
     }
 
     fn get_word(&mut self, memory: &Memory, reg_index: Byte, addressing: AddressingMode) -> Word {
-        self.get_operand_value_with_addressing(memory, reg_index, addressing, Memory::read_word, Self::get_word_from_reg)
+        self.get_operand_value_with_addressing(memory, reg_index, addressing, Self::read_virtual_word, Self::get_word_from_reg)
     }
 }
 
@@ -190,6 +475,45 @@ impl CPU {
     }
 }
 
+// Breakpoints
+impl CPU {
+    // Replaces any breakpoint at the address
+    pub fn add_breakpoint(&mut self, address: Word) {
+        self.breakpoints.insert(address, None);
+    }
+
+    pub fn add_conditional_breakpoint(&mut self, address: Word, condition: BreakCondition) {
+        self.breakpoints.insert(address, Some(condition));
+    }
+
+    pub fn remove_breakpoint(&mut self, address: Word) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> Vec<Breakpoint> {
+        self.breakpoints.iter().map(|(address, condition)| Breakpoint { address: *address, condition: *condition }).collect()
+    }
+
+    // Conditions see the registers before the instruction is fetched
+    fn at_breakpoint(&mut self) -> bool {
+        let skip = std::mem::take(&mut self.skip_breakpoint);
+
+        if skip || self.breakpoints.is_empty() {
+            return false;
+        }
+
+        match self.breakpoints.get(&self.registers[PROGRAM_COUNTER_INDEX as usize]) {
+            Some(Some(condition)) => condition.holds(&self.registers, self.status_word()),
+            Some(None) => true,
+            None => false,
+        }
+    }
+}
+
 // Registers
 impl CPU {
     fn get_word_from_reg(&mut self, reg_index: Byte) -> Word {
@@ -214,11 +538,13 @@ impl CPU {
     }
 
     fn increment_reg(&mut self, reg_index: Byte, by: Word) {
-        self.registers[reg_index as usize] += by;
+        let reg = &mut self.registers[reg_index as usize];
+        *reg = reg.wrapping_add(by);
     }
 
     fn decrement_reg(&mut self, reg_index: Byte, by: Word) {
-        self.registers[reg_index as usize] -= by;
+        let reg = &mut self.registers[reg_index as usize];
+        *reg = reg.wrapping_sub(by);
     }
 
     fn set_byte_reg(&mut self, reg_index: Byte, value: Byte) {
@@ -228,26 +554,55 @@ impl CPU {
     fn set_word_reg(&mut self, reg_index: Byte, value: Word) {
         self.registers[reg_index as usize] = value;
     }
+
+    fn get_mode_stack_pointer(&self, mode: ProcessorMode) -> Word {
+        if mode == self.stack_pointer_mode {
+            return self.registers[STACK_POINTER_INDEX as usize];
+        }
+
+        self.stack_pointers[mode as usize]
+    }
+
+    fn set_mode_stack_pointer(&mut self, mode: ProcessorMode, value: Word) {
+        if mode == self.stack_pointer_mode {
+            self.registers[STACK_POINTER_INDEX as usize] = value;
+        } else {
+            self.stack_pointers[mode as usize] = value;
+        }
+    }
+
+    fn sync_stack_pointer(&mut self) {
+        let mode = self.current_mode();
+
+        if mode == self.stack_pointer_mode {
+            return;
+        }
+
+        self.stack_pointers[self.stack_pointer_mode as usize] = self.registers[STACK_POINTER_INDEX as usize];
+        self.registers[STACK_POINTER_INDEX as usize] = self.stack_pointers[mode as usize];
+        self.stack_pointer_mode = mode;
+    }
 }
 
 // Float registers
 impl CPU {
-    fn get_float_from_reg(&mut self, memory: &Memory, reg_index: Byte) -> f32 {
-        let address = self.get_word_from_reg(reg_index);
+    // DEC F value at the given offset from the address held by the register
+    fn get_float_from_reg(&mut self, memory: &Memory, reg_index: Byte, offset: Word) -> u64 {
+        let address = self.get_word_from_reg(reg_index).wrapping_add(offset);
 
-        let hi_word = memory.read_word((address + 2).into());
-        let lo_word = memory.read_word(address.into());
+        let hi_word = self.read_virtual_word(memory, address.into(), AddressSpace::Data);
+        let lo_word = self.read_virtual_word(memory, address.wrapping_add(2).into(), AddressSpace::Data);
 
-        f32::from_bits(long_word(lo_word, hi_word))
+        dec_float::from_words(&[hi_word, lo_word])
     }
 
-    fn set_float_by_reg(&mut self, memory: &mut Memory, reg_index: Byte, value: f32) {
-        let address = self.get_word_from_reg(reg_index);
+    fn set_float_by_reg(&mut self, memory: &mut Memory, reg_index: Byte, offset: Word, value: u64) {
+        let address = self.get_word_from_reg(reg_index).wrapping_add(offset);
 
-        let long_word_value = value.to_bits();
+        let words = dec_float::to_words(value, dec_float::Precision::Single);
 
-        memory.write_word((address + 2).into(), long_word_value.high());
-        memory.write_word(address.into(), long_word_value.low());
+        self.write_virtual_word(memory, address.into(), AddressSpace::Data, words[0]);
+        self.write_virtual_word(memory, address.wrapping_add(2).into(), AddressSpace::Data, words[1]);
     }
 }
 
@@ -332,12 +687,38 @@ impl CPU {
             .set_n_bit(2, high)
     }
 
+    fn current_mode(&self) -> ProcessorMode {
+        let low = self.get_flag(CURRENT_MODE_LOW_BIT_INDEX);
+        let high = self.get_flag(CURRENT_MODE_HIGH_BIT_INDEX);
+
+        0x00u8
+            .set_n_bit(0, low)
+            .set_n_bit(1, high)
+            .into()
+    }
+
+    fn previous_mode(&self) -> ProcessorMode {
+        let low = self.get_flag(PREVIOUS_MODE_LOW_BIT_INDEX);
+        let high = self.get_flag(PREVIOUS_MODE_HIGH_BIT_INDEX);
+
+        0x00u8
+            .set_n_bit(0, low)
+            .set_n_bit(1, high)
+            .into()
+    }
+
+    fn is_kernel_mode(&self) -> bool {
+        self.current_mode() == ProcessorMode::Kernel
+    }
+
     fn status_word(&self) -> Word {
         self.status.lock().unwrap().read_word()
     }
 
     fn set_status_word(&mut self, new_psw: Word) {
         self.status.lock().unwrap().write_word(new_psw);
+
+        self.sync_stack_pointer();
     } 
 
     fn get_flag(&self, n: Byte) -> bool {
@@ -361,6 +742,6 @@ fn assert_pc(reg_index: &Byte) {
     assert!(*reg_index == PROGRAM_COUNTER_INDEX);
 }
 
-fn assert_even_reg(reg_index: &Byte) {
-    assert!((*reg_index & 0x01) == 0x00);
+fn is_even_reg(reg_index: Byte) -> bool {
+    (reg_index & 0x01) == 0x00
 }
@@ -1,6 +1,6 @@
 use crate::mem::Memory;
 
-use super::{ Address, Byte, Number, Word, CPU, PROGRAM_COUNTER_INDEX };
+use super::{ mmu::{space_for_register, AddressSpace}, Address, Byte, Number, Word, CPU, PROGRAM_COUNTER_INDEX };
 
 // Addressing
 impl CPU {
@@ -9,29 +9,45 @@ impl CPU {
     }
 
     pub (in super) fn get_autoincrement_address(&mut self, _memory: &Memory, reg_index: Byte, increment_by: Byte) -> Address {
+        self.mmu.record_register_change(reg_index, increment_by as i8);
+
         self.get_and_increment(reg_index, increment_by.into()).into()
     }
 
     pub (in super) fn get_autoincrement_deferred_address(&mut self, memory: &Memory, reg_index: Byte, _increment_by: Byte) -> Address {
-        memory.read_word(self.get_and_increment(reg_index, Word::size_bytes().into()).into()).into()
+        self.mmu.record_register_change(reg_index, Word::size_bytes() as i8);
+
+        let pointer = self.get_and_increment(reg_index, Word::size_bytes().into()).into();
+
+        self.read_virtual_word(memory, pointer, space_for_register(reg_index)).into()
     }
 
     pub (in super) fn get_autodecrement_address(&mut self, _memory: &Memory, reg_index: Byte, increment_by: Byte) -> Address {
+        self.mmu.record_register_change(reg_index, -(increment_by as i8));
+
         self.decrement_and_get(reg_index, increment_by.into()).into()
     }
 
     pub (in super) fn get_autodecrement_deferred_address(&mut self, memory: &Memory, reg_index: Byte, _increment_by: Byte) -> Address {
-        memory.read_word(self.decrement_and_get(reg_index, Word::size_bytes().into()).into()).into()
+        self.mmu.record_register_change(reg_index, -(Word::size_bytes() as i8));
+
+        let pointer = self.decrement_and_get(reg_index, Word::size_bytes().into()).into();
+
+        self.read_virtual_word(memory, pointer, space_for_register(reg_index)).into()
     }
 
     pub (in super) fn get_index_address(&mut self, memory: &Memory, reg_index: Byte, _increment_by: Byte) -> Address {
-        let n = memory.read_word(self.get_and_increment(PROGRAM_COUNTER_INDEX, Word::size_bytes().into()).into());
+        let index_address = self.get_and_increment(PROGRAM_COUNTER_INDEX, Word::size_bytes().into()).into();
+
+        let n = self.read_virtual_word(memory, index_address, AddressSpace::Instruction);
 
-        (n + self.get_word_from_reg(reg_index)).into()
+        n.wrapping_add(self.get_word_from_reg(reg_index)).into()
     }
 
     pub (in super) fn get_index_deferred_address(&mut self, memory: &Memory, reg_index: Byte, increment_by: Byte) -> Address {
-        memory.read_word(self.get_index_address(memory, reg_index, increment_by)).into()
+        let pointer = self.get_index_address(memory, reg_index, increment_by);
+
+        self.read_virtual_word(memory, pointer, AddressSpace::Data).into()
     }
 
     pub (in super) fn get_immediate_address(&mut self, _memory: &Memory, reg_index: Byte, _increment_by: Byte) -> Address {
@@ -64,27 +80,34 @@ impl CPU {
         reg_index: Byte, 
         addressing: AddressingMode, 
         data: N, 
-        write_memory: impl Fn(&mut Memory, Address, N) -> usize, 
+        write_memory: impl Fn(&mut CPU, &mut Memory, Address, AddressSpace, N), 
         set_register: impl Fn(&mut CPU, Byte, N),
     ) {
         match addressing {
             AddressingMode::Register => self.put_addressing_register(reg_index, data, set_register),
-            _ => self.put_operand_value(memory, write_memory, Self::get_addressing_func(addressing), reg_index, data),
+            _ => self.put_operand_value(memory, write_memory, Self::get_addressing_func(addressing), operand_space(addressing), reg_index, data),
         }
     }
 
     fn put_operand_value<T, N: Number<T>>(
         &mut self, 
         memory: &mut Memory, 
-        write_memory: impl Fn(&mut Memory, Address, N) -> usize, 
+        write_memory: impl Fn(&mut CPU, &mut Memory, Address, AddressSpace, N), 
         get_address: impl Fn(&mut CPU, &Memory, Byte, Byte) -> Address,
+        space: AddressSpace,
         reg_index: Byte,
         value: N
     ) {
-        write_memory(memory, get_address(self, memory, reg_index, N::size_bytes()), value);
+        let address = get_address(self, memory, reg_index, N::size_bytes());
+
+        write_memory(self, memory, address, space, value);
     }
 
     fn put_addressing_register<T, N: Number<T>>(&mut self, reg_index: Byte, data: N, set_register: impl Fn(&mut CPU, Byte, N)) {
+        if self.pending_trap.is_some() {
+            return;
+        }
+
         set_register(self, reg_index, data);
     }
 }
@@ -96,23 +119,26 @@ impl CPU {
         memory: &Memory, 
         reg_index: Byte, 
         addressing: AddressingMode, 
-        read_memory: impl Fn(&Memory, Address) -> N, 
+        read_memory: impl Fn(&mut CPU, &Memory, Address, AddressSpace) -> N, 
         get_register: impl Fn(&mut CPU, Byte) -> N
     ) -> N {
         match addressing {
             AddressingMode::Register => self.get_addressing_register(reg_index, get_register),
-            _ => self.get_operand_value(memory, read_memory, Self::get_addressing_func(addressing), reg_index),
+            _ => self.get_operand_value(memory, read_memory, Self::get_addressing_func(addressing), operand_space(addressing), reg_index),
         }
     }
 
     fn get_operand_value<T, N: Number<T>>(
         &mut self, 
         memory: &Memory, 
-        read_memory: impl Fn(&Memory, Address) -> N, 
+        read_memory: impl Fn(&mut CPU, &Memory, Address, AddressSpace) -> N, 
         get_address: impl Fn(&mut CPU, &Memory, Byte, Byte) -> Address,
+        space: AddressSpace,
         reg_index: Byte
     ) -> N {
-        read_memory(memory, get_address(self, memory, reg_index, N::size_bytes()))
+        let address = get_address(self, memory, reg_index, N::size_bytes());
+
+        read_memory(self, memory, address, space)
     }
 
     fn get_addressing_register<T, N: Number<T>>(&mut self, reg_index: Byte, get_register: impl Fn(&mut CPU, Byte) -> N) -> N {
@@ -135,18 +161,42 @@ impl CPU {
 pub (in super) fn adressing_from_operand(operand: Byte) -> AddressingMode {
     let mode = operand >> 3 & 0x07;
 
-    if register_from_operand(operand) == PROGRAM_COUNTER_INDEX {
+    // Only modes 2, 3, 6 and 7 have a PC specific meaning
+    if register_from_operand(operand) == PROGRAM_COUNTER_INDEX && matches!(mode, 0x02 | 0x03 | 0x06 | 0x07) {
         return (mode << 3 | 0x07).into();
     }
 
     mode.into()
 }
 
+pub (in super) fn is_register_operand(operand: Byte) -> bool {
+    operand >> 3 & 0x07 == AddressingMode::Register as Byte
+}
+
+// Index, index deferred, immediate and absolute operands take a word from the instruction stream
+pub (in super) fn has_index_word(operand: Byte) -> bool {
+    matches!(
+        adressing_from_operand(operand),
+        AddressingMode::Index | AddressingMode::IndexDeferred |
+        AddressingMode::Immediate | AddressingMode::Absolute |
+        AddressingMode::Relative | AddressingMode::RelativeDeferred
+    )
+}
+
 pub (in super) fn register_from_operand(operand: Byte) -> Byte {
     operand & 0x07
 }
 
+// Immediate operands are part of the instruction stream, everything else is data
+pub (in super) fn operand_space(addressing: AddressingMode) -> AddressSpace {
+    match addressing {
+        AddressingMode::Immediate => AddressSpace::Instruction,
+        _ => AddressSpace::Data,
+    }
+}
+
 #[repr(u8)]
+#[derive(Clone, Copy)]
 pub (in super) enum AddressingMode {
     Register = 0x0,
     RegisterDeferred = 0x1,
@@ -0,0 +1,103 @@
+use std::fmt;
+
+use crate::utils::{Byte, Word};
+
+use super::{PROGRAM_COUNTER_INDEX, REG_COUNT, STACK_POINTER_INDEX};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ConditionOperand {
+    Register(Byte),
+    Status,
+}
+
+// Unsigned, as addresses and PSW bits compare
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    // Longest operators first so that <= is not taken for <
+    const OPERATORS: [(&'static str, Comparison); 6] = [
+        ("==", Comparison::Equal),
+        ("!=", Comparison::NotEqual),
+        ("<=", Comparison::LessOrEqual),
+        (">=", Comparison::GreaterOrEqual),
+        ("<", Comparison::Less),
+        (">", Comparison::Greater),
+    ];
+
+    // "R0==5" gives ("R0", Equal, "5"), the operands are left to the caller to parse
+    pub fn split(text: &str) -> Option<(&str, Comparison, &str)> {
+        Self::OPERATORS.iter().find_map(|(operator, comparison)| {
+            text.split_once(operator).map(|(left, right)| (left.trim(), *comparison, right.trim()))
+        })
+    }
+
+    pub fn operator(&self) -> &'static str {
+        Self::OPERATORS.iter().find(|(_, comparison)| comparison == self).map_or("", |(operator, _)| operator)
+    }
+
+    fn holds(&self, left: Word, right: Word) -> bool {
+        match self {
+            Comparison::Equal => left == right,
+            Comparison::NotEqual => left != right,
+            Comparison::Less => left < right,
+            Comparison::LessOrEqual => left <= right,
+            Comparison::Greater => left > right,
+            Comparison::GreaterOrEqual => left >= right,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct BreakCondition {
+    pub operand: ConditionOperand,
+    pub comparison: Comparison,
+    pub value: Word,
+}
+
+impl BreakCondition {
+    pub fn holds(&self, registers: &[Word; REG_COUNT], status: Word) -> bool {
+        let operand = match self.operand {
+            ConditionOperand::Register(register) => registers[register as usize],
+            ConditionOperand::Status => status,
+        };
+
+        self.comparison.holds(operand, self.value)
+    }
+}
+
+impl fmt::Display for BreakCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.operand {
+            ConditionOperand::Register(STACK_POINTER_INDEX) => write!(f, "SP")?,
+            ConditionOperand::Register(PROGRAM_COUNTER_INDEX) => write!(f, "PC")?,
+            ConditionOperand::Register(register) => write!(f, "R{register}")?,
+            ConditionOperand::Status => write!(f, "PSW")?,
+        }
+
+        write!(f, "{}{:06o}", self.comparison.operator(), self.value)
+    }
+}
+
+// Stops before the instruction at the virtual PC address is fetched, if the condition holds
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Breakpoint {
+    pub address: Word,
+    pub condition: Option<BreakCondition>,
+}
+
+impl fmt::Display for Breakpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.condition {
+            Some(condition) => write!(f, "{:06o} if {condition}", self.address),
+            None => write!(f, "{:06o}", self.address),
+        }
+    }
+}
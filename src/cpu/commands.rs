@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use crate::{ mem::Memory, utils::{Byte, Number, Word} };
 
-use super::CPU;
+use super::{model::CpuModel, CPU};
 
 // https://www.teach.cs.toronto.edu/~ajr/258/pdp11.pdf
 // https://en.wikipedia.org/wiki/PDP-11_architecture
@@ -57,6 +57,13 @@ pub const O_2_MASK: Word = 0xF000;
  */
 pub const B_MASK: Word = 0xFF00;   
 
+/**
+ * Floating point accumulator command opcode mask
+ * 1111111100000000
+ * FEDCBA9876543210
+ */
+pub const F_MASK: Word = 0xFF00;
+
 /**
  * Operand mask
  * 0000000000111111
@@ -71,6 +78,13 @@ pub const O_MASK: Word = 0x003F;
  */
 pub const REG_MASK: Word = 0x0007;
 
+/**
+ * Floating point accumulator mask
+ * 0000000000000011
+ * FEDCBA9876543210
+ */
+pub const AC_MASK: Word = 0x0003;
+
 // For Two-operand instructions
 
 pub fn dst_operand(command: Word) -> Byte {
@@ -101,8 +115,42 @@ pub fn adr_operand(command: Word) -> Byte {
     dst_operand(command)
 }
 
+// For floating point accumulator instructions
+
+pub fn fp_ac_operand(command: Word) -> Byte {
+    ((command >> 6) & AC_MASK).low()
+}
+
+#[derive(Clone, Copy)]
 pub struct Command(pub Word, pub &'static str, pub fn(&mut CPU, &mut Memory, Word));
 
+// How the operands of a command are written, shared by the assembler and the disassembler
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Syntax {
+    None, // HALT
+    Register, // RTS R
+    Priority, // SPL N
+    ConditionCodes, // SEC, CLZ, SCC
+    Operand, // CLR DST
+    Mark, // MARK N
+    RegisterOperand, // JSR R,DST
+    OperandRegister, // MUL SRC,R
+    SubtractOneBranch, // SOB R,LABEL
+    TwoOperands, // MOV SRC,DST
+    Branch, // BNE LABEL
+    Trap, // EMT N
+    AccumulatorSource, // LDF SRC,AC
+    AccumulatorDestination, // STF AC,DST
+}
+
+pub const OPCODE_COUNT: usize = 1 << 16;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CommandLookup {
+    DispatchTable,
+    Probing,
+}
+
 pub struct Commands {
     pub o_0_commands: HashMap<Word, Command>,
     pub p_commands: HashMap<Word, Command>,
@@ -111,21 +159,44 @@ pub struct Commands {
     pub o_1_5_commands: HashMap<Word, Command>,
     pub o_2_commands: HashMap<Word, Command>,
     pub b_commands: HashMap<Word, Command>,
+    pub f_commands: HashMap<Word, Command>,
+    dispatch_commands: Vec<Command>,
+    dispatch_table: Vec<u16>, // Index into dispatch_commands for every instruction word
 }
 
 impl Default for Commands {
     fn default() -> Self {
+        Self::for_model(CpuModel::default())
+    }
+}
+
+impl Commands {
+    pub fn for_model(model: CpuModel) -> Self {
+        let mut commands = Self::full_set();
+
+        commands.remove_unsupported(model);
+        commands.compile();
+
+        commands
+    }
+
+    // Every instruction of every supported model
+    fn full_set() -> Self {
         Self { 
-            // TODO: OPTIONAL: MFPI, MFPD, MTPI, MTPD, RESET
             o_0_commands: HashMap::from([
                 command(0x0000, "HALT", CPU::do_halt),
                 command(0x0001, "WAIT", CPU::do_wait),
-                command(0x0005, "RESET", CPU::do_nop), // TODO
+                command(0x0005, "RESET", CPU::do_reset),
                 command(0x00A0, "NOP", CPU::do_nop),
                 command(0x0002, "RTI", CPU::do_rti),
                 command(0x0003, "BPT", CPU::do_bpt),
                 command(0x0004, "IOT", CPU::do_iot),
                 command(0x0006, "RTT", CPU::do_rtt),
+                command(0xF000, "CFCC", CPU::do_cfcc),
+                command(0xF001, "SETF", CPU::do_setf),
+                command(0xF002, "SETI", CPU::do_seti),
+                command(0xF009, "SETD", CPU::do_setd),
+                command(0xF00A, "SETL", CPU::do_setl),
             ]), 
             p_commands: HashMap::from([
                 command(0x0098, "SPL", CPU::do_spl),
@@ -168,6 +239,19 @@ impl Default for Commands {
                 command(0x00C0, "SWAB", CPU::do_swab),
                 command(0x0DC0, "SXT", CPU::do_sxt),
                 command(0x0D00, "MARK", CPU::do_mark),
+                command(0x0D40, "MFPI", CPU::do_mfpi),
+                command(0x8D40, "MFPD", CPU::do_mfpd),
+                command(0x0D80, "MTPI", CPU::do_mtpi),
+                command(0x8D80, "MTPD", CPU::do_mtpd),
+                command(0x8D00, "MTPS", CPU::do_mtps),
+                command(0x8DC0, "MFPS", CPU::do_mfps),
+                command(0xF040, "LDFPS", CPU::do_ldfps),
+                command(0xF080, "STFPS", CPU::do_stfps),
+                command(0xF0C0, "STST", CPU::do_stst),
+                command(0xF100, "CLRF/CLRD", CPU::do_clrf),
+                command(0xF140, "TSTF/TSTD", CPU::do_tstf),
+                command(0xF180, "ABSF/ABSD", CPU::do_absf),
+                command(0xF1C0, "NEGF/NEGD", CPU::do_negf),
             ]), 
             o_1_5_commands: HashMap::from([
                 command(0x7000, "MUL", CPU::do_mul),
@@ -211,46 +295,206 @@ impl Default for Commands {
                 command(0x8900, "TRAP", CPU::do_trap),
                 command(0x8800, "EMT", CPU::do_emt),
             ]),
+            f_commands: HashMap::from([
+                command(0xF200, "MULF/MULD", CPU::do_mulf),
+                command(0xF300, "MODF/MODD", CPU::do_modf),
+                command(0xF400, "ADDF/ADDD", CPU::do_addf),
+                command(0xF500, "LDF/LDD", CPU::do_ldf),
+                command(0xF600, "SUBF/SUBD", CPU::do_subf),
+                command(0xF700, "CMPF/CMPD", CPU::do_cmpf),
+                command(0xF800, "STF/STD", CPU::do_stf),
+                command(0xF900, "DIVF/DIVD", CPU::do_divf),
+                command(0xFA00, "STEXP", CPU::do_stexp),
+                command(0xFB00, "STCFI/STCFL/STCDI/STCDL", CPU::do_stcfi),
+                command(0xFC00, "STCFD/STCDF", CPU::do_stcfd),
+                command(0xFD00, "LDEXP", CPU::do_ldexp),
+                command(0xFE00, "LDCIF/LDCID/LDCLF/LDCLD", CPU::do_ldcif),
+                command(0xFF00, "LDCDF/LDCFD", CPU::do_ldcdf),
+            ]),
+            dispatch_commands: Vec::new(),
+            dispatch_table: Vec::new(),
+        }
+    }
+
+    fn remove_unsupported(&mut self, model: CpuModel) {
+        let mut unsupported: Vec<Word> = Vec::new();
+
+        if !model.has_eis() {
+            unsupported.extend([0x7000, 0x7200, 0x7400, 0x7600]);
+        }
+
+        if !model.has_fis() {
+            unsupported.extend([0x7A00, 0x7A08, 0x7A10, 0x7A18]);
+        }
+
+        if !model.has_extended_base_set() {
+            unsupported.extend([0x0DC0, 0x7800, 0x7E00, 0x0D00, 0x0006]);
+        }
+
+        if !model.has_processor_status_instructions() {
+            unsupported.extend([0x8D00, 0x8DC0]);
+        }
+
+        if !model.has_spl() {
+            unsupported.push(0x0098);
+        }
+
+        if !model.has_previous_instruction_space() {
+            unsupported.extend([0x0D40, 0x0D80]);
+        }
+
+        if !model.has_previous_data_space() {
+            unsupported.extend([0x8D40, 0x8D80]);
         }
+
+        let fpu = model.has_fpu();
+
+        for table in self.tables_mut() {
+            table.retain(|opcode, _| !unsupported.contains(opcode) && (fpu || opcode & 0xF000 != 0xF000));
+        }
+    }
+
+    // Every instruction of every model, for tools working on code of any machine
+    pub fn all_models() -> Self {
+        let mut commands = Self::full_set();
+
+        commands.compile();
+
+        commands
+    }
+
+    // In probing order
+    fn tables(&self) -> [(&HashMap<Word, Command>, Syntax); 8] {
+        [
+            (&self.o_0_commands, Syntax::None),
+            (&self.p_commands, Syntax::Register),
+            (&self.c_commands, Syntax::ConditionCodes),
+            (&self.o_1_commands, Syntax::Operand),
+            (&self.o_1_5_commands, Syntax::RegisterOperand),
+            (&self.o_2_commands, Syntax::TwoOperands),
+            (&self.b_commands, Syntax::Branch),
+            (&self.f_commands, Syntax::AccumulatorSource),
+        ]
+    }
+
+    fn tables_mut(&mut self) -> [&mut HashMap<Word, Command>; 8] {
+        [
+            &mut self.o_0_commands,
+            &mut self.p_commands,
+            &mut self.c_commands,
+            &mut self.o_1_commands,
+            &mut self.o_1_5_commands,
+            &mut self.o_2_commands,
+            &mut self.b_commands,
+            &mut self.f_commands,
+        ]
+    }
+}
+
+// Commands and their syntax
+impl Commands {
+    pub fn with_syntax(&self) -> impl Iterator<Item = (&Command, Syntax)> {
+        self.tables().into_iter().flat_map(|(table, syntax)| {
+            table.values().map(move |command| (command, command_syntax(command, syntax)))
+        })
+    }
+
+    pub fn syntax(&self, command: &Command) -> Syntax {
+        self.tables().into_iter()
+            .find(|(table, _)| table.get(&command.0).is_some_and(|known| known.1 == command.1))
+            .map_or(Syntax::None, |(_, syntax)| command_syntax(command, syntax))
+    }
+}
+
+// The tables group commands by opcode mask, a few of them write their operands differently
+fn command_syntax(command: &Command, table_syntax: Syntax) -> Syntax {
+    match (table_syntax, command.0) {
+        (Syntax::Register, 0x0098) => Syntax::Priority,
+        (Syntax::Operand, 0x0D00) => Syntax::Mark,
+        (Syntax::RegisterOperand, 0x7000 | 0x7200 | 0x7400 | 0x7600) => Syntax::OperandRegister,
+        (Syntax::RegisterOperand, 0x7E00) => Syntax::SubtractOneBranch,
+        (Syntax::Branch, 0x8800 | 0x8900) => Syntax::Trap,
+        (Syntax::AccumulatorSource, 0xF800 | 0xFA00 | 0xFB00 | 0xFC00) => Syntax::AccumulatorDestination,
+        (syntax, _) => syntax,
     }
 }
 
-pub const UNKNOWN_COMMAND: Command = Command(0xFFFF, "UNKNOWN", CPU::do_panic);
+pub const UNKNOWN_COMMAND: Command = Command(0xFFFF, "UNKNOWN", CPU::do_reserved);
 
 fn command(opcode: Word, name: &'static str, interpretation: fn(&mut CPU, &mut Memory, Word)) -> (Word, Command) {
     (opcode, Command(opcode, name, interpretation))
 }
 
-impl CPU {
-    pub (in super) fn command(&self, command_word: Word) -> &Command {
-        if let Some(command) = self.commands.o_0_commands.get(&(command_word & O_0_MASK)) {
+impl Commands {
+    pub fn decode(&self, command_word: Word) -> &Command {
+        &self.dispatch_commands[self.dispatch_table[command_word as usize] as usize]
+    }
+
+    // Resolves every instruction word once, the probing order settles overlaps like NOP and CL*
+    fn compile(&mut self) {
+        let mut dispatch_commands: Vec<Command> = Vec::new();
+        let mut dispatch_table = Vec::with_capacity(OPCODE_COUNT);
+
+        for command_word in 0..OPCODE_COUNT {
+            let command = *self.probe(command_word as Word);
+
+            let index = match dispatch_commands.iter().position(|known| known.0 == command.0 && known.1 == command.1) {
+                Some(index) => index,
+                None => {
+                    dispatch_commands.push(command);
+                    dispatch_commands.len() - 1
+                }
+            };
+
+            dispatch_table.push(index as u16);
+        }
+
+        self.dispatch_commands = dispatch_commands;
+        self.dispatch_table = dispatch_table;
+    }
+
+    pub fn probe(&self, command_word: Word) -> &Command {
+        if let Some(command) = self.o_0_commands.get(&(command_word & O_0_MASK)) {
             return command;
         }
 
-        if let Some(command) = self.commands.p_commands.get(&(command_word & P_MASK)) {
+        if let Some(command) = self.p_commands.get(&(command_word & P_MASK)) {
             return command;
         }
 
-        if let Some(command) = self.commands.c_commands.get(&(command_word & C_MASK)) {
+        if let Some(command) = self.c_commands.get(&(command_word & C_MASK)) {
             return command;
         }
 
-        if let Some(command) = self.commands.o_1_commands.get(&(command_word & O_1_MASK)) {
+        if let Some(command) = self.o_1_commands.get(&(command_word & O_1_MASK)) {
             return command;
         }
 
-        if let Some(command) = self.commands.o_1_5_commands.get(&(command_word & O_1_5_MASK)) {
+        if let Some(command) = self.o_1_5_commands.get(&(command_word & O_1_5_MASK)) {
             return command;
         }
 
-        if let Some(command) = self.commands.o_2_commands.get(&(command_word & O_2_MASK)) {
+        if let Some(command) = self.o_2_commands.get(&(command_word & O_2_MASK)) {
             return command;
         }
 
-        if let Some(command) = self.commands.b_commands.get(&(command_word & B_MASK)) {
+        if let Some(command) = self.b_commands.get(&(command_word & B_MASK)) {
+            return command;
+        }
+
+        if let Some(command) = self.f_commands.get(&(command_word & F_MASK)) {
             return command;
         }
 
         &UNKNOWN_COMMAND
     }
 }
+
+impl CPU {
+    pub (in super) fn command(&self, command_word: Word) -> &Command {
+        match self.command_lookup {
+            CommandLookup::DispatchTable => self.commands.decode(command_word),
+            CommandLookup::Probing => self.commands.probe(command_word),
+        }
+    }
+}
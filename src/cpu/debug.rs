@@ -1,22 +1,26 @@
 use crate::mem::MappedMemoryWord;
 
-use super::{ Word, CPU, REG_COUNT };
+use super::{ Byte, HaltReason, Word, CPU, MODE_COUNT, REG_COUNT };
 
 #[derive(Debug)]
 pub struct CPUStateDump {
     pub status: Word,
     pub registers: [Word; REG_COUNT],
+    pub stack_pointers: [Word; MODE_COUNT],
     pub running: bool,
     pub waiting: bool,
+    pub halt_reason: Option<HaltReason>,
 }
 
 impl CPU {
     pub fn dump_state(&self) -> CPUStateDump {
         CPUStateDump {
             status: self.status.lock().unwrap().read_word(),
-            registers: self.registers.clone(),
+            registers: self.registers,
+            stack_pointers: std::array::from_fn(|mode| self.get_mode_stack_pointer((mode as Byte).into())),
             running: *self.running.lock().unwrap(),
             waiting: self.waiting,
+            halt_reason: self.halt_reason,
         }
     }
 
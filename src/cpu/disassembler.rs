@@ -0,0 +1,262 @@
+use std::{fmt, sync::Arc};
+
+use crate::{loader::symbols::SymbolTable, mem::Memory, utils::{Address, Byte, Word}};
+
+use super::{addressing::{adressing_from_operand, has_index_word, register_from_operand, AddressingMode}, commands::{branch_offset, dst_operand, fp_ac_operand, low_reg_operand, reg_operand, src_operand, Commands, Syntax, UNKNOWN_COMMAND}, model::CpuModel, PROGRAM_COUNTER_INDEX, STACK_POINTER_INDEX};
+
+// Symbols are only used for addresses this close above them
+pub const MAX_SYMBOL_OFFSET: Word = 0x0200;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Radix {
+    #[default]
+    Octal,
+    Hexadecimal,
+}
+
+#[derive(Clone, Debug)]
+pub struct Disassembly {
+    pub address: Word,
+    pub words: Vec<Word>, // The instruction and its operand words
+    pub text: String,
+}
+
+impl Disassembly {
+    pub fn next_address(&self) -> Word {
+        self.address.wrapping_add(2 * self.words.len() as Word)
+    }
+}
+
+impl fmt::Display for Disassembly {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let words = self.words.iter().map(|word| format!("{word:06o}")).collect::<Vec<_>>().join(" ");
+
+        write!(f, "{:06o}  {words:20} {}", self.address, self.text)
+    }
+}
+
+pub struct Disassembler {
+    commands: Arc<Commands>,
+    symbols: SymbolTable,
+    radix: Radix,
+}
+
+impl Default for Disassembler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Disassembler {
+    // Knows the instructions of every model
+    pub fn new() -> Self {
+        Self::with_commands(Arc::new(Commands::all_models()))
+    }
+
+    pub fn for_model(model: CpuModel) -> Self {
+        Self::with_commands(Arc::new(Commands::for_model(model)))
+    }
+
+    pub fn with_commands(commands: Arc<Commands>) -> Self {
+        Disassembler {
+            commands,
+            symbols: SymbolTable::default(),
+            radix: Radix::default(),
+        }
+    }
+
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    pub fn with_radix(mut self, radix: Radix) -> Self {
+        self.radix = radix;
+        self
+    }
+
+    // Words are read without side effects, device registers included
+    pub fn disassemble_memory(&self, memory: &Memory, address: Word) -> Disassembly {
+        self.disassemble(address, |address| memory.peek_word(address as Address).ok())
+    }
+
+    // Every instruction starting in the range
+    pub fn disassemble_range(&self, memory: &Memory, start: Word, end: Word) -> Vec<Disassembly> {
+        let mut result = Vec::new();
+        let mut address = start;
+
+        while address < end {
+            let disassembly = self.disassemble_memory(memory, address);
+
+            if disassembly.words.is_empty() {
+                break;
+            }
+
+            address = disassembly.next_address();
+            result.push(disassembly);
+
+            if address == 0 {
+                break; // Wrapped around
+            }
+        }
+
+        result
+    }
+
+    // Unreadable words end the instruction early, an unreadable instruction gives no words at all
+    pub fn disassemble(&self, address: Word, fetch: impl Fn(Word) -> Option<Word>) -> Disassembly {
+        let Some(command_word) = fetch(address) else {
+            return Disassembly { address, words: Vec::new(), text: "?".to_string() };
+        };
+
+        let mut decoder = Decoder {
+            disassembler: self,
+            address,
+            words: vec![command_word],
+            fetch: &fetch,
+        };
+
+        let text = decoder.instruction(command_word);
+
+        Disassembly { address, words: decoder.words, text }
+    }
+
+    fn number(&self, value: Word) -> String {
+        match self.radix {
+            Radix::Octal => format!("{value:o}"),
+            Radix::Hexadecimal => format!("0x{value:X}"),
+        }
+    }
+
+    // Branch targets, relative and absolute operands
+    fn address(&self, value: Word) -> String {
+        match self.symbols.lookup(value) {
+            Some((symbol, 0)) => symbol.name.clone(),
+            Some((symbol, offset)) if offset < MAX_SYMBOL_OFFSET => format!("{}+{}", symbol.name, self.number(offset)),
+            _ => self.number(value),
+        }
+    }
+}
+
+struct Decoder<'a> {
+    disassembler: &'a Disassembler,
+    address: Word,
+    words: Vec<Word>,
+    fetch: &'a dyn Fn(Word) -> Option<Word>,
+}
+
+impl Decoder<'_> {
+    fn instruction(&mut self, command_word: Word) -> String {
+        let commands = &self.disassembler.commands;
+        let command = commands.decode(command_word);
+
+        if command.1 == UNKNOWN_COMMAND.1 {
+            return format!(".WORD {}", self.disassembler.number(command_word));
+        }
+
+        let name = command.1.split('/').next().unwrap_or(command.1);
+        // Mode 0 operands are accumulators, except general registers for the integer and exponent conversions
+        let floating = command.0 >= 0xF100 && !matches!(command.0, 0xFA00 | 0xFB00 | 0xFD00 | 0xFE00);
+
+        match commands.syntax(command) {
+            Syntax::None => name.to_string(),
+            Syntax::Register => format!("{name} {}", register_name(low_reg_operand(command_word))),
+            Syntax::Priority => format!("{name} {}", command_word & 0x7),
+            Syntax::ConditionCodes => condition_codes(command_word),
+            Syntax::Operand => format!("{name} {}", self.operand(dst_operand(command_word), floating)),
+            Syntax::Mark => format!("{name} {}", self.disassembler.number(command_word & 0x3F)),
+            Syntax::Trap => format!("{name} {}", self.disassembler.number(command_word & 0xFF)),
+            Syntax::RegisterOperand => {
+                format!("{name} {},{}", register_name(reg_operand(command_word)), self.operand(dst_operand(command_word), false))
+            },
+            Syntax::OperandRegister => {
+                format!("{name} {},{}", self.operand(src_operand(command_word), false), register_name(reg_operand(command_word)))
+            },
+            Syntax::TwoOperands => {
+                let source = self.operand(src_operand(command_word), false);
+                format!("{name} {source},{}", self.operand(dst_operand(command_word), false))
+            },
+            Syntax::AccumulatorSource => {
+                format!("{name} {},AC{}", self.operand(dst_operand(command_word), floating), fp_ac_operand(command_word))
+            },
+            Syntax::AccumulatorDestination => {
+                format!("{name} AC{},{}", fp_ac_operand(command_word), self.operand(dst_operand(command_word), floating))
+            },
+            Syntax::Branch => {
+                let target = self.address.wrapping_add(2).wrapping_add(branch_offset(command_word));
+                format!("{name} {}", self.disassembler.address(target))
+            },
+            Syntax::SubtractOneBranch => {
+                let target = self.address.wrapping_add(2).wrapping_sub((command_word & 0x3F) << 1);
+                format!("{name} {},{}", register_name(reg_operand(command_word)), self.disassembler.address(target))
+            },
+        }
+    }
+
+    fn operand(&mut self, operand: Byte, floating: bool) -> String {
+        let register = register_from_operand(operand);
+        let name = register_name(register);
+
+        let word = match has_index_word(operand) {
+            true => match self.next_word() {
+                Some(word) => word,
+                None => return "?".to_string(),
+            },
+            false => 0,
+        };
+        let word_address = self.address.wrapping_add(2 * (self.words.len() as Word - 1));
+
+        let disassembler = self.disassembler;
+
+        match adressing_from_operand(operand) {
+            AddressingMode::Register if floating => format!("AC{register}"),
+            AddressingMode::Register => name,
+            AddressingMode::RegisterDeferred => format!("({name})"),
+            AddressingMode::Autoicrement => format!("({name})+"),
+            AddressingMode::AutoicrementDeferred => format!("@({name})+"),
+            AddressingMode::Autodecrement => format!("-({name})"),
+            AddressingMode::AutodecrementDeferred => format!("@-({name})"),
+            AddressingMode::Index => format!("{}({name})", disassembler.number(word)),
+            AddressingMode::IndexDeferred => format!("@{}({name})", disassembler.number(word)),
+            AddressingMode::Immediate => format!("#{}", disassembler.number(word)),
+            AddressingMode::Absolute => format!("@#{}", disassembler.address(word)),
+            AddressingMode::Relative => disassembler.address(word_address.wrapping_add(2).wrapping_add(word)),
+            AddressingMode::RelativeDeferred => format!("@{}", disassembler.address(word_address.wrapping_add(2).wrapping_add(word))),
+        }
+    }
+
+    fn next_word(&mut self) -> Option<Word> {
+        let address = self.address.wrapping_add(2 * self.words.len() as Word);
+        let word = (self.fetch)(address)?;
+
+        self.words.push(word);
+
+        Some(word)
+    }
+}
+
+fn register_name(register: Byte) -> String {
+    match register {
+        STACK_POINTER_INDEX => "SP".to_string(),
+        PROGRAM_COUNTER_INDEX => "PC".to_string(),
+        _ => format!("R{register}"),
+    }
+}
+
+// SEC, CLZ, SCC and combinations like SEC!SEV
+fn condition_codes(command_word: Word) -> String {
+    let set = command_word & 0x0010 != 0;
+    let flags = command_word & 0x000F;
+
+    if flags == 0xF {
+        return if set { "SCC" } else { "CCC" }.to_string();
+    }
+
+    let prefix = if set { "SE" } else { "CL" };
+
+    [(0x1, 'C'), (0x2, 'V'), (0x4, 'Z'), (0x8, 'N')].iter()
+        .filter(|(bit, _)| flags & bit != 0)
+        .map(|(_, flag)| format!("{prefix}{flag}"))
+        .collect::<Vec<_>>()
+        .join("!")
+}
@@ -0,0 +1,615 @@
+use std::cmp::Ordering;
+
+use dec_float::{FloatResult, Precision};
+
+use crate::{mem::Memory, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter}, utils::{long_word, Address, Byte, LongWord, Number, Word}};
+
+use super::{addressing::{adressing_from_operand, operand_space, register_from_operand, AddressingMode}, commands::{adr_operand, fp_ac_operand}, mmu::AddressSpace, CPU};
+
+pub mod dec_float;
+
+// FP11 floating point processor
+// https://bitsavers.org/pdf/dec/pdp11/1145/EK-FP11-MM-004_FP11-B_Maintenance_Manual.pdf
+
+pub const FPU_TRAP_VECTOR: Address = 0x00A4; // 244 (oct)
+
+pub const AC_COUNT: usize = 6;
+
+pub const FPS_CARRY_BIT: Byte = 0; // Or FC
+pub const FPS_OVERFLOW_BIT: Byte = 1; // Or FV
+pub const FPS_ZERO_BIT: Byte = 2; // Or FZ
+pub const FPS_NEGATIVE_BIT: Byte = 3; // Or FN
+pub const FPS_TRUNCATE_BIT: Byte = 5; // Or FT
+pub const FPS_LONG_BIT: Byte = 6; // Or FL
+pub const FPS_DOUBLE_BIT: Byte = 7; // Or FD
+pub const FPS_CONVERSION_INTERRUPT_BIT: Byte = 8; // Or FIC
+pub const FPS_OVERFLOW_INTERRUPT_BIT: Byte = 9; // Or FIV
+pub const FPS_UNDERFLOW_INTERRUPT_BIT: Byte = 10; // Or FIU
+pub const FPS_UNDEFINED_INTERRUPT_BIT: Byte = 11; // Or FIUV
+pub const FPS_INTERRUPT_DISABLE_BIT: Byte = 14; // Or FID
+pub const FPS_ERROR_BIT: Byte = 15; // Or FER
+
+/**
+ * FPS software writable bits
+ * 1100111111101111
+ * FEDCBA9876543210
+ */
+pub const FPS_WRITABLE_MASK: Word = 0xCFEF;
+
+// Floating exception codes (FEC)
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum FpuException {
+    OpcodeError = 0x2,
+    DivideByZero = 0x4,
+    IntegerConversion = 0x6,
+    Overflow = 0x8,
+    Underflow = 0xA,
+    UndefinedVariable = 0xC,
+}
+
+pub struct Fpu {
+    accumulators: [u64; AC_COUNT],
+    status: Word, // Or FPS
+    exception_code: Word, // Or FEC
+    exception_address: Word, // Or FEA
+}
+
+impl Fpu {
+    pub fn new() -> Self {
+        Fpu {
+            accumulators: [0; AC_COUNT],
+            status: 0x0000,
+            exception_code: 0x0000,
+            exception_address: 0x0000,
+        }
+    }
+
+    pub fn status(&self) -> Word {
+        self.status
+    }
+
+    pub fn accumulators(&self) -> [u64; AC_COUNT] {
+        self.accumulators
+    }
+
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        for accumulator in self.accumulators {
+            writer.write_u64(accumulator);
+        }
+
+        writer.write_word(self.status);
+        writer.write_word(self.exception_code);
+        writer.write_word(self.exception_address);
+    }
+
+    pub fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        for accumulator in self.accumulators.iter_mut() {
+            *accumulator = reader.read_u64()?;
+        }
+
+        self.status = reader.read_word()?;
+        self.exception_code = reader.read_word()?;
+        self.exception_address = reader.read_word()?;
+
+        Ok(())
+    }
+
+    fn precision(&self) -> Precision {
+        if self.status.get_n_bit(FPS_DOUBLE_BIT) { Precision::Double } else { Precision::Single }
+    }
+
+    // Precision of the "other" format used by the converting load and store
+    fn converted_precision(&self) -> Precision {
+        match self.precision() {
+            Precision::Single => Precision::Double,
+            Precision::Double => Precision::Single,
+        }
+    }
+
+    fn truncate(&self) -> bool {
+        self.status.get_n_bit(FPS_TRUNCATE_BIT)
+    }
+
+    fn long_integer(&self) -> bool {
+        self.status.get_n_bit(FPS_LONG_BIT)
+    }
+
+    fn set_status_bit(&mut self, n: Byte, value: bool) {
+        self.status = self.status.set_n_bit(n, value);
+    }
+
+    fn set_condition_codes(&mut self, negative: bool, zero: bool, overflow: bool, carry: bool) {
+        self.set_status_bit(FPS_NEGATIVE_BIT, negative);
+        self.set_status_bit(FPS_ZERO_BIT, zero);
+        self.set_status_bit(FPS_OVERFLOW_BIT, overflow);
+        self.set_status_bit(FPS_CARRY_BIT, carry);
+    }
+
+    fn set_float_condition_codes(&mut self, value: u64, overflow: bool) {
+        self.set_condition_codes(dec_float::is_negative(value), dec_float::is_zero(value), overflow, false);
+    }
+
+    // Opcode errors and division by zero can only be masked with FID
+    fn exception_enabled(&self, exception: FpuException) -> bool {
+        match exception {
+            FpuException::OpcodeError | FpuException::DivideByZero => true,
+            FpuException::IntegerConversion => self.status.get_n_bit(FPS_CONVERSION_INTERRUPT_BIT),
+            FpuException::Overflow => self.status.get_n_bit(FPS_OVERFLOW_INTERRUPT_BIT),
+            FpuException::Underflow => self.status.get_n_bit(FPS_UNDERFLOW_INTERRUPT_BIT),
+            FpuException::UndefinedVariable => self.status.get_n_bit(FPS_UNDEFINED_INTERRUPT_BIT),
+        }
+    }
+}
+
+impl Default for Fpu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Where a floating point instruction operand lives
+#[derive(Clone, Copy)]
+enum FpuOperand {
+    Register(Byte), // Accumulator for float operands, general register for integer ones
+    Memory(Address, AddressSpace, usize),
+}
+
+// Mode
+impl CPU {
+    pub fn do_cfcc(&mut self, _memory: &mut Memory, _command: Word) {
+        self.copy_fpu_condition_codes();
+    }
+
+    pub fn do_setf(&mut self, _memory: &mut Memory, _command: Word) {
+        self.fpu.set_status_bit(FPS_DOUBLE_BIT, false);
+    }
+
+    pub fn do_setd(&mut self, _memory: &mut Memory, _command: Word) {
+        self.fpu.set_status_bit(FPS_DOUBLE_BIT, true);
+    }
+
+    pub fn do_seti(&mut self, _memory: &mut Memory, _command: Word) {
+        self.fpu.set_status_bit(FPS_LONG_BIT, false);
+    }
+
+    pub fn do_setl(&mut self, _memory: &mut Memory, _command: Word) {
+        self.fpu.set_status_bit(FPS_LONG_BIT, true);
+    }
+
+    pub fn do_ldfps(&mut self, memory: &mut Memory, command: Word) {
+        let word = self.get_word_by_operand(memory, adr_operand(command));
+
+        self.fpu.status = word & FPS_WRITABLE_MASK;
+    }
+
+    pub fn do_stfps(&mut self, memory: &mut Memory, command: Word) {
+        let status = self.fpu.status;
+
+        self.put_word_by_operand(memory, adr_operand(command), status);
+    }
+
+    pub fn do_stst(&mut self, memory: &mut Memory, command: Word) {
+        let exception_code = self.fpu.exception_code;
+        let exception_address = self.fpu.exception_address;
+
+        match self.fpu_operand(memory, adr_operand(command), 4) {
+            FpuOperand::Register(reg_index) => self.set_word_reg(reg_index, exception_code),
+            FpuOperand::Memory(address, space, words) => {
+                self.write_virtual_word(memory, address, space, exception_code);
+
+                if words > 1 {
+                    self.write_virtual_word(memory, address + 2, space, exception_address);
+                }
+            },
+        }
+    }
+}
+
+// Single operand
+impl CPU {
+    pub fn do_clrf(&mut self, memory: &mut Memory, command: Word) {
+        let precision = self.fpu.precision();
+        let location = self.fpu_operand(memory, adr_operand(command), precision.size_bytes());
+
+        if self.write_float(memory, location, precision, 0) {
+            self.fpu.set_condition_codes(false, true, false, false);
+        }
+    }
+
+    pub fn do_tstf(&mut self, memory: &mut Memory, command: Word) {
+        let precision = self.fpu.precision();
+        let location = self.fpu_operand(memory, adr_operand(command), precision.size_bytes());
+
+        let Some(value) = self.read_float(memory, location, precision) else { return; };
+
+        self.fpu.set_float_condition_codes(value, false);
+    }
+
+    pub fn do_absf(&mut self, memory: &mut Memory, command: Word) {
+        self.modify_float(memory, command, dec_float::abs);
+    }
+
+    pub fn do_negf(&mut self, memory: &mut Memory, command: Word) {
+        self.modify_float(memory, command, dec_float::negate);
+    }
+
+    fn modify_float(&mut self, memory: &mut Memory, command: Word, modify: fn(u64) -> u64) {
+        let precision = self.fpu.precision();
+        let location = self.fpu_operand(memory, adr_operand(command), precision.size_bytes());
+
+        let Some(value) = self.read_float(memory, location, precision) else { return; };
+
+        let result = modify(value);
+
+        if self.write_float(memory, location, precision, result) {
+            self.fpu.set_float_condition_codes(result, false);
+        }
+    }
+}
+
+// Accumulator & source
+impl CPU {
+    pub fn do_ldf(&mut self, memory: &mut Memory, command: Word) {
+        let precision = self.fpu.precision();
+
+        let Some(value) = self.get_float_by_operand(memory, adr_operand(command), precision) else { return; };
+
+        self.fpu.accumulators[fp_ac_operand(command) as usize] = value;
+        self.fpu.set_float_condition_codes(value, false);
+    }
+
+    pub fn do_stf(&mut self, memory: &mut Memory, command: Word) {
+        let precision = self.fpu.precision();
+        let value = self.fpu.accumulators[fp_ac_operand(command) as usize];
+
+        let location = self.fpu_operand(memory, adr_operand(command), precision.size_bytes());
+
+        self.write_float(memory, location, precision, value);
+    }
+
+    pub fn do_addf(&mut self, memory: &mut Memory, command: Word) {
+        self.perform_fpu_arithmetic(memory, command, dec_float::add);
+    }
+
+    pub fn do_subf(&mut self, memory: &mut Memory, command: Word) {
+        self.perform_fpu_arithmetic(memory, command, dec_float::sub);
+    }
+
+    pub fn do_mulf(&mut self, memory: &mut Memory, command: Word) {
+        self.perform_fpu_arithmetic(memory, command, dec_float::mul);
+    }
+
+    pub fn do_divf(&mut self, memory: &mut Memory, command: Word) {
+        let precision = self.fpu.precision();
+
+        let Some(divisor) = self.get_float_by_operand(memory, adr_operand(command), precision) else { return; };
+
+        if dec_float::is_zero(divisor) {
+            self.raise_fpu_exception(FpuException::DivideByZero);
+            return;
+        }
+
+        self.store_accumulator_result(command, |accumulator, precision, truncate| {
+            dec_float::div(accumulator, divisor, precision, truncate)
+        });
+    }
+
+    pub fn do_cmpf(&mut self, memory: &mut Memory, command: Word) {
+        let precision = self.fpu.precision();
+
+        let Some(value) = self.get_float_by_operand(memory, adr_operand(command), precision) else { return; };
+
+        let accumulator = self.fpu.accumulators[fp_ac_operand(command) as usize];
+
+        let ordering = dec_float::compare(value, dec_float::truncate_to(accumulator, precision));
+
+        self.fpu.set_condition_codes(ordering == Ordering::Less, ordering == Ordering::Equal, false, false);
+    }
+
+    pub fn do_modf(&mut self, memory: &mut Memory, command: Word) {
+        let precision = self.fpu.precision();
+        let ac = fp_ac_operand(command) as usize;
+
+        let Some(value) = self.get_float_by_operand(memory, adr_operand(command), precision) else { return; };
+
+        let accumulator = dec_float::truncate_to(self.fpu.accumulators[ac], precision);
+
+        let (integer_part, fractional_part) = dec_float::modf(accumulator, value, precision, self.fpu.truncate());
+
+        let (fraction, exception) = self.finish_float_result(fractional_part);
+
+        // An odd accumulator only receives the fractional part
+        if ac & 0x1 == 0 {
+            self.fpu.accumulators[ac | 0x1] = integer_part.bits;
+        }
+        self.fpu.accumulators[ac] = fraction;
+
+        if let Some(exception) = exception {
+            self.raise_fpu_exception(exception);
+        }
+    }
+
+    fn perform_fpu_arithmetic(&mut self, memory: &mut Memory, command: Word, operation: fn(u64, u64, Precision, bool) -> FloatResult) {
+        let precision = self.fpu.precision();
+
+        let Some(value) = self.get_float_by_operand(memory, adr_operand(command), precision) else { return; };
+
+        self.store_accumulator_result(command, |accumulator, precision, truncate| {
+            operation(accumulator, value, precision, truncate)
+        });
+    }
+
+    fn store_accumulator_result(&mut self, command: Word, operation: impl Fn(u64, Precision, bool) -> FloatResult) {
+        let precision = self.fpu.precision();
+        let ac = fp_ac_operand(command) as usize;
+
+        let accumulator = dec_float::truncate_to(self.fpu.accumulators[ac], precision);
+
+        let (result, exception) = self.finish_float_result(operation(accumulator, precision, self.fpu.truncate()));
+
+        self.fpu.accumulators[ac] = result;
+
+        if let Some(exception) = exception {
+            self.raise_fpu_exception(exception);
+        }
+    }
+}
+
+// Conversions
+impl CPU {
+    pub fn do_ldcdf(&mut self, memory: &mut Memory, command: Word) {
+        let precision = self.fpu.precision();
+
+        let Some(value) = self.get_float_by_operand(memory, adr_operand(command), self.fpu.converted_precision()) else { return; };
+
+        self.store_accumulator_result(command, |_, _, truncate| {
+            dec_float::round_to(value, precision, truncate)
+        });
+    }
+
+    pub fn do_stcfd(&mut self, memory: &mut Memory, command: Word) {
+        let precision = self.fpu.converted_precision();
+        let accumulator = dec_float::truncate_to(self.fpu.accumulators[fp_ac_operand(command) as usize], self.fpu.precision());
+
+        let (result, exception) = self.finish_float_result(dec_float::round_to(accumulator, precision, self.fpu.truncate()));
+
+        let location = self.fpu_operand(memory, adr_operand(command), precision.size_bytes());
+
+        if !self.write_float(memory, location, precision, result) {
+            return;
+        }
+
+        if let Some(exception) = exception {
+            self.raise_fpu_exception(exception);
+        }
+    }
+
+    pub fn do_ldcif(&mut self, memory: &mut Memory, command: Word) {
+        let Some(integer) = self.get_fpu_integer_by_operand(memory, adr_operand(command)) else { return; };
+
+        self.store_accumulator_result(command, |_, precision, truncate| {
+            dec_float::from_integer(integer, precision, truncate)
+        });
+    }
+
+    pub fn do_stcfi(&mut self, memory: &mut Memory, command: Word) {
+        let precision = self.fpu.precision();
+        let accumulator = dec_float::truncate_to(self.fpu.accumulators[fp_ac_operand(command) as usize], precision);
+
+        let (min, max) = if self.fpu.long_integer() {
+            (i32::MIN as i64, i32::MAX as i64)
+        } else {
+            (i16::MIN as i64, i16::MAX as i64)
+        };
+
+        let (integer, error) = match dec_float::to_integer(accumulator) {
+            Some(integer) if integer >= min && integer <= max => (integer, false),
+            _ => (0, true),
+        };
+
+        if !self.put_fpu_integer_by_operand(memory, adr_operand(command), integer) {
+            return;
+        }
+
+        self.fpu.set_condition_codes(integer < 0, integer == 0, false, error);
+        self.copy_fpu_condition_codes();
+
+        if error {
+            self.raise_fpu_exception(FpuException::IntegerConversion);
+        }
+    }
+
+    pub fn do_ldexp(&mut self, memory: &mut Memory, command: Word) {
+        let exponent = self.get_word_by_operand(memory, adr_operand(command)) as i16;
+
+        if self.pending_trap.is_some() {
+            return;
+        }
+
+        self.store_accumulator_result(command, |accumulator, _, _| {
+            dec_float::with_exponent(accumulator, exponent as i32 + dec_float::EXPONENT_BIAS)
+        });
+    }
+
+    pub fn do_stexp(&mut self, memory: &mut Memory, command: Word) {
+        let accumulator = self.fpu.accumulators[fp_ac_operand(command) as usize];
+
+        let exponent = (dec_float::exponent(accumulator) - dec_float::EXPONENT_BIAS) as Word;
+
+        self.put_word_by_operand(memory, adr_operand(command), exponent);
+
+        self.fpu.set_condition_codes(exponent.is_negative(), exponent.is_zero(), false, false);
+        self.copy_fpu_condition_codes();
+    }
+}
+
+// Operands
+impl CPU {
+    fn fpu_operand(&mut self, memory: &Memory, operand: Byte, length: Byte) -> FpuOperand {
+        let reg_index = register_from_operand(operand);
+        let addressing = adressing_from_operand(operand);
+
+        let words = match addressing {
+            AddressingMode::Register => return FpuOperand::Register(reg_index),
+            AddressingMode::Immediate => 1, // Only the first word follows the instruction
+            _ => (length / 2) as usize,
+        };
+
+        let address = Self::get_addressing_func(addressing)(self, memory, reg_index, length);
+
+        FpuOperand::Memory(address, operand_space(addressing), words)
+    }
+
+    fn get_float_by_operand(&mut self, memory: &Memory, operand: Byte, precision: Precision) -> Option<u64> {
+        let location = self.fpu_operand(memory, operand, precision.size_bytes());
+
+        self.read_float(memory, location, precision)
+    }
+
+    fn read_float(&mut self, memory: &Memory, location: FpuOperand, precision: Precision) -> Option<u64> {
+        let value = match location {
+            FpuOperand::Register(ac) => {
+                if ac as usize >= AC_COUNT {
+                    self.raise_fpu_exception(FpuException::OpcodeError);
+                    return None;
+                }
+
+                dec_float::truncate_to(self.fpu.accumulators[ac as usize], precision)
+            },
+            FpuOperand::Memory(address, space, words) => {
+                let words: Vec<Word> = (0..words)
+                    .map(|i| self.read_virtual_word(memory, address + 2 * i, space))
+                    .collect();
+
+                if self.pending_trap.is_some() {
+                    return None;
+                }
+
+                dec_float::from_words(&words)
+            },
+        };
+
+        if dec_float::is_undefined(value) && self.fpu.exception_enabled(FpuException::UndefinedVariable) {
+            self.raise_fpu_exception(FpuException::UndefinedVariable);
+            return None;
+        }
+
+        Some(value)
+    }
+
+    // Returns false when nothing has been written
+    fn write_float(&mut self, memory: &mut Memory, location: FpuOperand, precision: Precision, value: u64) -> bool {
+        match location {
+            FpuOperand::Register(ac) => {
+                if ac as usize >= AC_COUNT {
+                    self.raise_fpu_exception(FpuException::OpcodeError);
+                    return false;
+                }
+
+                self.fpu.accumulators[ac as usize] = dec_float::truncate_to(value, precision);
+            },
+            FpuOperand::Memory(address, space, words) => {
+                for (i, word) in dec_float::to_words(value, precision).into_iter().take(words).enumerate() {
+                    self.write_virtual_word(memory, address + 2 * i, space, word);
+                }
+            },
+        }
+
+        self.pending_trap.is_none()
+    }
+
+    // A register or an immediate long integer only supplies its high word
+    fn get_fpu_integer_by_operand(&mut self, memory: &Memory, operand: Byte) -> Option<i64> {
+        let long_integer = self.fpu.long_integer();
+
+        let location = self.fpu_operand(memory, operand, if long_integer { 4 } else { 2 });
+
+        let words = match location {
+            FpuOperand::Register(reg_index) => vec![self.get_word_from_reg(reg_index)],
+            FpuOperand::Memory(address, space, words) => (0..words)
+                .map(|i| self.read_virtual_word(memory, address + 2 * i, space))
+                .collect(),
+        };
+
+        if self.pending_trap.is_some() {
+            return None;
+        }
+
+        if !long_integer {
+            return Some(words[0] as i16 as i64);
+        }
+
+        let low = words.get(1).copied().unwrap_or(0x0000);
+
+        Some(long_word(low, words[0]) as i32 as i64)
+    }
+
+    fn put_fpu_integer_by_operand(&mut self, memory: &mut Memory, operand: Byte, integer: i64) -> bool {
+        let long_integer = self.fpu.long_integer();
+        let long = integer as LongWord;
+
+        let words = if long_integer { vec![long.high(), long.low()] } else { vec![long.low()] };
+
+        match self.fpu_operand(memory, operand, if long_integer { 4 } else { 2 }) {
+            FpuOperand::Register(reg_index) => self.set_word_reg(reg_index, words[0]),
+            FpuOperand::Memory(address, space, count) => {
+                for (i, word) in words.into_iter().take(count).enumerate() {
+                    self.write_virtual_word(memory, address + 2 * i, space, word);
+                }
+            },
+        }
+
+        self.pending_trap.is_none()
+    }
+}
+
+// Exceptions
+impl CPU {
+    // Masked overflow and underflow results become an exact zero
+    fn finish_float_result(&mut self, result: FloatResult) -> (u64, Option<FpuException>) {
+        let exception = if result.overflow {
+            Some(FpuException::Overflow)
+        } else if result.underflow {
+            Some(FpuException::Underflow)
+        } else {
+            None
+        };
+
+        let enabled = exception.map(|exception| self.fpu.exception_enabled(exception));
+
+        let value = match enabled {
+            Some(false) => 0,
+            _ => result.bits,
+        };
+
+        self.fpu.set_float_condition_codes(value, result.overflow);
+
+        (value, exception.filter(|_| enabled == Some(true)))
+    }
+
+    fn raise_fpu_exception(&mut self, exception: FpuException) {
+        if !self.fpu.exception_enabled(exception) {
+            return;
+        }
+
+        trace!("fpu exception {exception:?}");
+
+        self.fpu.set_status_bit(FPS_ERROR_BIT, true);
+        self.fpu.exception_code = exception as Word;
+        self.fpu.exception_address = self.command_address as Word;
+
+        if !self.fpu.status.get_n_bit(FPS_INTERRUPT_DISABLE_BIT) && self.pending_trap.is_none() {
+            self.pending_trap = Some(FPU_TRAP_VECTOR);
+        }
+    }
+
+    fn copy_fpu_condition_codes(&mut self) {
+        let status = self.fpu.status;
+
+        self.update_carry_flag(status.get_n_bit(FPS_CARRY_BIT));
+        self.update_overflow_flag(status.get_n_bit(FPS_OVERFLOW_BIT));
+        self.update_zero_flag(status.get_n_bit(FPS_ZERO_BIT));
+        self.update_negative_flag(status.get_n_bit(FPS_NEGATIVE_BIT));
+    }
+}
@@ -0,0 +1,313 @@
+use std::cmp::Ordering;
+
+use crate::utils::{Byte, Word};
+
+// DEC F (32-bit) and D (64-bit) floating point formats
+//
+// Both are kept in a u64 with the first (lowest addressed) word in bits 63-48:
+// sign in bit 63, excess-128 exponent in bits 62-55 and the fraction below it.
+// The fraction is normalized to 0.1xxx (binary) with the leading bit hidden,
+// an exponent of zero means zero regardless of the fraction.
+
+pub const SIGN_BIT: u64 = 1 << 63;
+pub const EXPONENT_SHIFT: u32 = 55;
+pub const EXPONENT_MASK: u64 = 0xFF << EXPONENT_SHIFT;
+pub const FRACTION_MASK: u64 = (1 << EXPONENT_SHIFT) - 1;
+pub const HIDDEN_BIT: u64 = 1 << EXPONENT_SHIFT;
+
+pub const EXPONENT_BIAS: i32 = 0x80;
+pub const MAX_EXPONENT: i32 = 0xFF;
+
+/**
+ * Bits of an F value inside the u64 representation
+ */
+pub const SINGLE_MASK: u64 = 0xFFFF_FFFF_0000_0000;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Precision {
+    Single,
+    Double,
+}
+
+impl Precision {
+    pub fn size_bytes(&self) -> Byte {
+        match self {
+            Precision::Single => 4,
+            Precision::Double => 8,
+        }
+    }
+
+    pub fn size_words(&self) -> usize {
+        (self.size_bytes() / 2) as usize
+    }
+
+    // Significant bits including the hidden one
+    fn fraction_bits(&self) -> u32 {
+        match self {
+            Precision::Single => 24,
+            Precision::Double => 56,
+        }
+    }
+
+    fn mask(&self) -> u64 {
+        match self {
+            Precision::Single => SINGLE_MASK,
+            Precision::Double => u64::MAX,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FloatResult {
+    pub bits: u64,
+    pub overflow: bool,
+    pub underflow: bool,
+}
+
+impl FloatResult {
+    fn exact(bits: u64) -> Self {
+        FloatResult { bits, overflow: false, underflow: false }
+    }
+}
+
+pub fn from_words(words: &[Word]) -> u64 {
+    words.iter()
+        .chain(std::iter::repeat(&0x0000u16))
+        .take(4)
+        .fold(0u64, |bits, word| bits << 16 | *word as u64)
+}
+
+pub fn to_words(bits: u64, precision: Precision) -> Vec<Word> {
+    (0..precision.size_words())
+        .map(|i| (bits >> (48 - 16 * i)) as Word)
+        .collect()
+}
+
+pub fn is_zero(bits: u64) -> bool {
+    bits & EXPONENT_MASK == 0
+}
+
+pub fn is_negative(bits: u64) -> bool {
+    bits & SIGN_BIT != 0
+}
+
+// -0 is the "undefined variable" of the FP11
+pub fn is_undefined(bits: u64) -> bool {
+    is_zero(bits) && is_negative(bits)
+}
+
+pub fn exponent(bits: u64) -> i32 {
+    ((bits & EXPONENT_MASK) >> EXPONENT_SHIFT) as i32
+}
+
+pub fn negate(bits: u64) -> u64 {
+    if is_zero(bits) {
+        return 0;
+    }
+
+    bits ^ SIGN_BIT
+}
+
+pub fn abs(bits: u64) -> u64 {
+    if is_zero(bits) {
+        return 0;
+    }
+
+    bits & !SIGN_BIT
+}
+
+pub fn truncate_to(bits: u64, precision: Precision) -> u64 {
+    bits & precision.mask()
+}
+
+// Fraction with the hidden bit restored, left aligned to bit 63
+fn significand(bits: u64) -> u128 {
+    (((bits & FRACTION_MASK) | HIDDEN_BIT) << 8) as u128
+}
+
+/**
+ * Normalizes, rounds (or truncates) and packs `fraction / 2^point * 2^(exponent - 128)`
+ */
+fn round_and_pack(negative: bool, exponent: i32, fraction: u128, point: i32, precision: Precision, truncate: bool) -> FloatResult {
+    if fraction == 0 {
+        return FloatResult::exact(0);
+    }
+
+    let top = 127 - fraction.leading_zeros() as i32;
+    let mut exponent = exponent + top + 1 - point;
+
+    let mut normalized = if top > 63 { fraction >> (top - 63) } else { fraction << (63 - top) };
+
+    let dropped = 64 - precision.fraction_bits();
+
+    if !truncate {
+        normalized += 1 << (dropped - 1);
+
+        if normalized >> 64 != 0 {
+            normalized >>= 1;
+            exponent += 1;
+        }
+    }
+
+    let kept = (normalized as u64) >> dropped << dropped;
+
+    let bits = (if negative { SIGN_BIT } else { 0 })
+        | ((exponent & MAX_EXPONENT) as u64) << EXPONENT_SHIFT
+        | ((kept >> 8) & FRACTION_MASK);
+
+    FloatResult {
+        bits,
+        overflow: exponent > MAX_EXPONENT,
+        underflow: exponent < 1,
+    }
+}
+
+pub fn round_to(bits: u64, precision: Precision, truncate: bool) -> FloatResult {
+    if is_zero(bits) {
+        return FloatResult::exact(0);
+    }
+
+    round_and_pack(is_negative(bits), exponent(bits), significand(bits), 64, precision, truncate)
+}
+
+pub fn add(a: u64, b: u64, precision: Precision, truncate: bool) -> FloatResult {
+    if is_zero(b) {
+        return round_to(a, precision, truncate);
+    }
+
+    if is_zero(a) {
+        return round_to(b, precision, truncate);
+    }
+
+    let (larger, smaller) = if compare_magnitude(a, b) == Ordering::Less { (b, a) } else { (a, b) };
+
+    let shift = (exponent(larger) - exponent(smaller)) as u32;
+
+    let larger_fraction = significand(larger) << 32;
+    let smaller_fraction = if shift < 96 { (significand(smaller) << 32) >> shift } else { 0 };
+
+    let fraction = if is_negative(a) == is_negative(b) {
+        larger_fraction + smaller_fraction
+    } else {
+        larger_fraction - smaller_fraction
+    };
+
+    round_and_pack(is_negative(larger), exponent(larger), fraction, 96, precision, truncate)
+}
+
+pub fn sub(a: u64, b: u64, precision: Precision, truncate: bool) -> FloatResult {
+    add(a, negate(b), precision, truncate)
+}
+
+pub fn mul(a: u64, b: u64, precision: Precision, truncate: bool) -> FloatResult {
+    if is_zero(a) || is_zero(b) {
+        return FloatResult::exact(0);
+    }
+
+    let fraction = significand(a) * significand(b);
+    let exponent = exponent(a) + exponent(b) - EXPONENT_BIAS;
+
+    round_and_pack(is_negative(a) != is_negative(b), exponent, fraction, 128, precision, truncate)
+}
+
+// The divisor must not be zero
+pub fn div(a: u64, b: u64, precision: Precision, truncate: bool) -> FloatResult {
+    if is_zero(a) {
+        return FloatResult::exact(0);
+    }
+
+    let fraction = (significand(a) << 64) / significand(b);
+    let exponent = exponent(a) - exponent(b) + EXPONENT_BIAS;
+
+    round_and_pack(is_negative(a) != is_negative(b), exponent, fraction, 64, precision, truncate)
+}
+
+/**
+ * Multiplies and splits the product into its integer and fractional parts (MODF)
+ */
+pub fn modf(a: u64, b: u64, precision: Precision, truncate: bool) -> (FloatResult, FloatResult) {
+    if is_zero(a) || is_zero(b) {
+        return (FloatResult::exact(0), FloatResult::exact(0));
+    }
+
+    let negative = is_negative(a) != is_negative(b);
+    let product = mul(a, b, precision, truncate);
+
+    if product.overflow || product.underflow {
+        return (FloatResult::exact(0), product);
+    }
+
+    let integer_bits = exponent(product.bits) - EXPONENT_BIAS;
+
+    if integer_bits <= 0 {
+        return (FloatResult::exact(0), product);
+    }
+
+    if integer_bits >= precision.fraction_bits() as i32 {
+        return (product, FloatResult::exact(0));
+    }
+
+    let fraction = significand(product.bits);
+    let fraction_point = 64 - integer_bits;
+
+    let integer_part = fraction >> fraction_point << fraction_point;
+    let fractional_part = fraction - integer_part;
+
+    (
+        round_and_pack(negative, EXPONENT_BIAS, integer_part, 64 - integer_bits, precision, true),
+        round_and_pack(negative, EXPONENT_BIAS, fractional_part, 64 - integer_bits, precision, truncate),
+    )
+}
+
+pub fn from_integer(value: i64, precision: Precision, truncate: bool) -> FloatResult {
+    round_and_pack(value < 0, EXPONENT_BIAS, value.unsigned_abs() as u128, 0, precision, truncate)
+}
+
+/**
+ * Integer part truncated toward zero, None if it doesn't fit into 63 bits
+ */
+pub fn to_integer(bits: u64) -> Option<i64> {
+    let integer_bits = exponent(bits) - EXPONENT_BIAS;
+
+    if is_zero(bits) || integer_bits <= 0 {
+        return Some(0);
+    }
+
+    if integer_bits > 63 {
+        return None;
+    }
+
+    let magnitude = (significand(bits) >> (64 - integer_bits)) as i64;
+
+    Some(if is_negative(bits) { -magnitude } else { magnitude })
+}
+
+pub fn with_exponent(bits: u64, exponent: i32) -> FloatResult {
+    if is_zero(bits) {
+        return FloatResult::exact(0);
+    }
+
+    FloatResult {
+        bits: (bits & !EXPONENT_MASK) | ((exponent & MAX_EXPONENT) as u64) << EXPONENT_SHIFT,
+        overflow: exponent > MAX_EXPONENT,
+        underflow: exponent < 1,
+    }
+}
+
+pub fn compare(a: u64, b: u64) -> Ordering {
+    match (is_zero(a), is_zero(b)) {
+        (true, true) => Ordering::Equal,
+        (true, false) => if is_negative(b) { Ordering::Greater } else { Ordering::Less },
+        (false, true) => if is_negative(a) { Ordering::Less } else { Ordering::Greater },
+        (false, false) => match (is_negative(a), is_negative(b)) {
+            (false, true) => Ordering::Greater,
+            (true, false) => Ordering::Less,
+            (false, false) => compare_magnitude(a, b),
+            (true, true) => compare_magnitude(b, a),
+        },
+    }
+}
+
+fn compare_magnitude(a: u64, b: u64) -> Ordering {
+    (a & !SIGN_BIT).cmp(&(b & !SIGN_BIT))
+}
@@ -1,21 +1,38 @@
-use crate::{ mem::{self, Memory}, utils::{has_carry, LongWord, Number, Word }};
+use crate::{ mem::Memory, utils::{has_carry, LongWord, Number, Word }};
 
-use super::{ adr_operand, assert_even_reg, branch_offset, commands::{ dst_operand, src_operand }, has_signed_overflow, long_word, low_reg_operand, make_word, reg_operand, word_has_carry, Address, Byte, CARRY_FLAG_INDEX, CPU, MARK_POINTER_INDEX, NEGATIVE_FLAG_INDEX, OVERFLOW_FLAG_INDEX, PROGRAM_COUNTER_INDEX, STACK_POINTER_INDEX, ZERO_FLAG_INDEX };
+use super::{ fpu::{dec_float::{self, FloatResult, Precision}, FPU_TRAP_VECTOR}, addressing::{adressing_from_operand, has_index_word, is_register_operand, register_from_operand, AddressingMode}, mmu::AddressSpace, adr_operand, branch_offset, is_even_reg, BUS_ERROR_TRAP_VECTOR, commands::{ dst_operand, src_operand }, has_signed_overflow, long_word, low_reg_operand, make_word, reg_operand, word_has_carry, Address, Byte, CARRY_FLAG_INDEX, CPU, HaltReason, MARK_POINTER_INDEX, NEGATIVE_FLAG_INDEX, OVERFLOW_FLAG_INDEX, PREVIOUS_MODE_HIGH_BIT_INDEX, PREVIOUS_MODE_LOW_BIT_INDEX, PROGRAM_COUNTER_INDEX, ProcessorMode, RESERVED_INSTRUCTION_TRAP_VECTOR, STACK_POINTER_INDEX, ZERO_FLAG_INDEX };
 
 // Zero-oparand
 impl CPU {
     pub fn do_nop(&mut self, _memory: &mut Memory, _command: Word) { /* NO-OP */ }
 
-    pub fn do_halt(&mut self, _memory: &mut Memory, _command: Word) {
-        *self.running.lock().unwrap() = false;
+    pub fn do_halt(&mut self, memory: &mut Memory, _command: Word) {
+        if !self.is_kernel_mode() {
+            self.perform_trap(memory, BUS_ERROR_TRAP_VECTOR);
+            return;
+        }
+
+        self.halt(HaltReason::HaltInstruction(self.command_address));
     }
 
     pub fn do_wait(&mut self, _memory: &mut Memory, _command: Word) {
         self.waiting = true;
     }
 
-    pub fn do_panic(&mut self, _memory: &mut Memory, _command: Word) {
-        panic!("CPU panic!")
+    // Asserts INIT on the bus, a no-op outside of kernel mode
+    pub fn do_reset(&mut self, memory: &mut Memory, _command: Word) {
+        if !self.is_kernel_mode() {
+            return;
+        }
+
+        memory.reset_devices();
+        self.interruption_bus.lock().unwrap().clear();
+        self.mmu.reset();
+    }
+
+    // Reserved opcodes and illegal operand forms
+    pub fn do_reserved(&mut self, memory: &mut Memory, _command: Word) {
+        self.perform_trap(memory, RESERVED_INSTRUCTION_TRAP_VECTOR);
     }
 
     pub fn do_rti(&mut self, memory: &mut Memory, _command: Word) {
@@ -25,7 +42,18 @@ impl CPU {
 
         let new_psw = self.pop_stack(memory);
 
-        self.set_status_word(new_psw);
+        self.profile_return();
+
+        if self.is_kernel_mode() {
+            self.set_status_word(new_psw);
+            return;
+        }
+
+        // Outside of kernel mode the priority is kept and the mode can't become more privileged
+        let old_psw = self.status_word();
+        let protected_psw = (new_psw & 0x001F) | ((old_psw | new_psw) & 0xF800) | (old_psw & 0x00E0);
+
+        self.set_status_word(protected_psw);
     }
 
     pub fn do_rtt(&mut self, memory: &mut Memory, command: Word) {
@@ -44,6 +72,10 @@ impl CPU {
 // Set priority & some Control Flow & Float commands
 impl CPU {
     pub fn do_spl(&mut self, _memory: &mut Memory, command: Word) {
+        if !self.is_kernel_mode() {
+            return;
+        }
+
         self.update_priority(command.low());
     }
 
@@ -57,62 +89,66 @@ impl CPU {
         let stack_value = self.pop_stack(memory);
 
         self.set_word_reg(reg, stack_value);
+        self.profile_return();
     }
 
     pub fn do_fadd(&mut self, memory: &mut Memory, command: Word) {
-        let reg = low_reg_operand(command);
-
-        let src_float = self.get_float_from_reg(memory, reg);
-
-        self.increment_reg(reg, 2 * Word::size_bytes().word());
-
-        let dst_float = self.get_float_from_reg(memory, reg);
-
-        let result = src_float + dst_float;
-
-        self.set_float_by_reg(memory, reg, result);
+        self.perform_fis_operation(memory, command, dec_float::add);
     }
 
     pub fn do_fsub(&mut self, memory: &mut Memory, command: Word) {
-        let reg = low_reg_operand(command);
-
-        let src_float = self.get_float_from_reg(memory, reg);
-
-        self.increment_reg(reg, 2 * Word::size_bytes().word());
-
-        let dst_float = self.get_float_from_reg(memory, reg);
-
-        let result = dst_float - src_float;
-
-        self.set_float_by_reg(memory, reg, result);
+        self.perform_fis_operation(memory, command, dec_float::sub);
     }
 
     pub fn do_fmul(&mut self, memory: &mut Memory, command: Word) {
-        let reg = low_reg_operand(command);
-
-        let src_float = self.get_float_from_reg(memory, reg);
+        self.perform_fis_operation(memory, command, dec_float::mul);
+    }
 
-        self.increment_reg(reg, 2 * Word::size_bytes().word());
+    pub fn do_fdiv(&mut self, memory: &mut Memory, command: Word) {
+        let reg = low_reg_operand(command);
 
-        let dst_float = self.get_float_from_reg(memory, reg);
+        let divisor = self.get_float_from_reg(memory, reg, 0x0000);
 
-        let result = dst_float * src_float;
+        // N, V and C set, Z cleared
+        if dec_float::is_zero(divisor) {
+            self.update_status_flags(0x0000u16, true, true);
+            self.update_zero_flag(false);
+            self.update_negative_flag(true);
+            self.perform_trap(memory, FPU_TRAP_VECTOR);
+            return;
+        }
 
-        self.set_float_by_reg(memory, reg, result);
+        self.perform_fis_operation(memory, command, dec_float::div);
     }
 
-    pub fn do_fdiv(&mut self, memory: &mut Memory, command: Word) {
+    // [(R)+4, (R)+6] <- [(R)+4, (R)+6] op [(R), (R)+2], then R <- R+4
+    fn perform_fis_operation(&mut self, memory: &mut Memory, command: Word, operation: fn(u64, u64, Precision, bool) -> FloatResult) {
         let reg = low_reg_operand(command);
 
-        let src_float = self.get_float_from_reg(memory, reg);
+        let src_float = self.get_float_from_reg(memory, reg, 0x0000);
+        let dst_float = self.get_float_from_reg(memory, reg, 0x0004);
 
-        self.increment_reg(reg, 2 * Word::size_bytes().word());
+        if self.pending_trap.is_some() {
+            return;
+        }
 
-        let dst_float = self.get_float_from_reg(memory, reg);
+        let result = operation(dst_float, src_float, Precision::Single, false);
 
-        let result = dst_float / src_float;
+        // Operands and the register are left untouched on exceptions
+        if result.overflow || result.underflow {
+            self.update_status_flags(0x0000u16, false, true);
+            self.update_zero_flag(false);
+            self.update_negative_flag(result.underflow);
+            self.perform_trap(memory, FPU_TRAP_VECTOR);
+            return;
+        }
 
-        self.set_float_by_reg(memory, reg, result);
+        self.set_float_by_reg(memory, reg, 0x0004, result.bits);
+        self.increment_reg(reg, 2 * Word::size_bytes().word());
+
+        self.update_status_flags(0x0000u16, false, false);
+        self.update_zero_flag(dec_float::is_zero(result.bits));
+        self.update_negative_flag(dec_float::is_negative(result.bits));
     }
 }
 
@@ -206,7 +242,7 @@ impl CPU {
 
         let word = self.get_word_by_operand(memory, operand);
 
-        let sub = word as LongWord - 0x00000001u32;
+        let sub = (word as LongWord).wrapping_sub(0x00000001u32);
 
         let result = sub as Word;
 
@@ -220,7 +256,7 @@ impl CPU {
 
         let byte = self.get_byte_by_operand(memory, operand);
 
-        let sub = byte as Word - 0x0001u16;
+        let sub = (byte as Word).wrapping_sub(0x0001u16);
 
         let result = sub as Byte;
 
@@ -262,7 +298,7 @@ impl CPU {
 
         let word = self.get_word_by_operand(memory, operand);
 
-        let sub = word as LongWord - if self.carry_flag() { 0x00000001u32 } else { 0x00000000u32 };
+        let sub = (word as LongWord).wrapping_sub(if self.carry_flag() { 0x00000001u32 } else { 0x00000000u32 });
 
         let result = sub as Word;
 
@@ -276,7 +312,7 @@ impl CPU {
 
         let byte = self.get_byte_by_operand(memory, operand);
 
-        let sub = byte as Word - if self.carry_flag() { 0x0001u16 } else { 0x0000u16 };
+        let sub = (byte as Word).wrapping_sub(if self.carry_flag() { 0x0001u16 } else { 0x0000u16 });
 
         let result = sub as Byte;
 
@@ -494,7 +530,12 @@ impl CPU {
     pub fn do_jmp(&mut self, memory: &mut Memory, command: Word) {
         let operand = adr_operand(command);
 
-        let address = self.get_operand_address(memory, operand);
+        if is_register_operand(operand) {
+            self.do_reserved(memory, command);
+            return;
+        }
+
+        let address = self.get_jump_address(memory, operand);
 
         self.set_word_reg(PROGRAM_COUNTER_INDEX, address as u16);
     }
@@ -520,6 +561,92 @@ impl CPU {
     }
 }
 
+// Processor status (LSI-11)
+impl CPU {
+    pub fn do_mtps(&mut self, memory: &mut Memory, command: Word) {
+        let byte = self.get_byte_by_operand(memory, adr_operand(command));
+
+        if self.pending_trap.is_some() {
+            return;
+        }
+
+        // The T bit can't be set this way
+        let psw = self.status_word();
+        let new_psw = (psw & 0xFF10) | (byte as Word & 0x00EF);
+
+        self.set_status_word(new_psw);
+    }
+
+    pub fn do_mfps(&mut self, memory: &mut Memory, command: Word) {
+        let byte = self.status_word().low();
+
+        self.put_byte_by_operand(memory, adr_operand(command), byte);
+
+        self.update_status_flags_bitwise(byte);
+    }
+}
+
+// Previous address space
+impl CPU {
+    pub fn do_mfpi(&mut self, memory: &mut Memory, command: Word) {
+        self.move_from_previous_space(memory, command, AddressSpace::Instruction);
+    }
+
+    pub fn do_mfpd(&mut self, memory: &mut Memory, command: Word) {
+        self.move_from_previous_space(memory, command, AddressSpace::Data);
+    }
+
+    pub fn do_mtpi(&mut self, memory: &mut Memory, command: Word) {
+        self.move_to_previous_space(memory, command, AddressSpace::Instruction);
+    }
+
+    pub fn do_mtpd(&mut self, memory: &mut Memory, command: Word) {
+        self.move_to_previous_space(memory, command, AddressSpace::Data);
+    }
+
+    fn move_from_previous_space(&mut self, memory: &mut Memory, command: Word, space: AddressSpace) {
+        let operand = adr_operand(command);
+        let previous_mode = self.previous_mode();
+
+        let word = match adressing_from_operand(operand) {
+            AddressingMode::Register if register_from_operand(operand) == STACK_POINTER_INDEX => {
+                self.get_mode_stack_pointer(previous_mode)
+            },
+            AddressingMode::Register => self.get_word_from_reg(register_from_operand(operand)),
+            _ => {
+                let address = self.get_operand_address(memory, operand);
+
+                self.read_virtual_word_in_mode(memory, address, previous_mode, space)
+            },
+        };
+
+        self.push_stack(memory, word);
+
+        self.update_status_flags_bitwise(word);
+    }
+
+    fn move_to_previous_space(&mut self, memory: &mut Memory, command: Word, space: AddressSpace) {
+        let operand = adr_operand(command);
+        let previous_mode = self.previous_mode();
+
+        let word = self.pop_stack(memory);
+
+        match adressing_from_operand(operand) {
+            AddressingMode::Register if register_from_operand(operand) == STACK_POINTER_INDEX => {
+                self.set_mode_stack_pointer(previous_mode, word);
+            },
+            AddressingMode::Register => self.set_word_reg(register_from_operand(operand), word),
+            _ => {
+                let address = self.get_operand_address(memory, operand);
+
+                self.write_virtual_word_in_mode(memory, address, previous_mode, space, word);
+            },
+        };
+
+        self.update_status_flags_bitwise(word);
+    }
+}
+
 // One-and-a-half-operand
 impl CPU {
     pub fn do_mul(&mut self, memory: &mut Memory, command: Word) {
@@ -542,7 +669,10 @@ impl CPU {
     pub fn do_div(&mut self, memory: &mut Memory, command: Word) {
         let dst = reg_operand(command);
 
-        assert_even_reg(&dst);
+        if !is_even_reg(dst) {
+            self.do_reserved(memory, command);
+            return;
+        }
 
         let dst_hi = dst | 0x01u8;
 
@@ -611,8 +741,7 @@ impl CPU {
     pub fn do_ashc(&mut self, memory: &mut Memory, command: Word) {
         let dst = reg_operand(command);
 
-        assert_even_reg(&dst);
-
+        // An odd register is shifted as both halves and keeps the low word
         let dst_hi = dst | 0x01u8;
 
         let src_value = self.get_word_by_operand(memory, adr_operand(command));
@@ -643,8 +772,8 @@ impl CPU {
             (partially_shifted, shifted)
         };
 
-        self.set_word_reg(dst, result.low());
         self.set_word_reg(dst_hi, result.high());
+        self.set_word_reg(dst, result.low());
 
         let carry = if left_shift {
             intermediate_result.is_negative()
@@ -687,23 +816,54 @@ impl CPU {
 
         let operand = adr_operand(command);
 
+        if is_register_operand(operand) {
+            self.do_reserved(memory, command);
+            return;
+        }
+
+        // The destination comes first, PC has to be past its index word when it is pushed
+        let address = self.get_jump_address(memory, operand);
         let reg_value = self.get_word_from_reg(reg);
-        let address = self.get_operand_address(memory, operand);
 
         self.push_stack(memory, reg_value);
 
         let pc_value = self.get_word_from_reg(PROGRAM_COUNTER_INDEX);
         self.set_word_reg(reg, pc_value);
         self.set_word_reg(PROGRAM_COUNTER_INDEX, address as Word);
+        self.profile_call(address as Word);
+    }
+}
+
+// Jump address
+impl CPU {
+    fn get_jump_address(&mut self, memory: &Memory, operand: Byte) -> Address {
+        let address = self.get_operand_address(memory, operand);
+
+        let reg = register_from_operand(operand);
+        let autoincrement = matches!(adressing_from_operand(operand), AddressingMode::Autoicrement);
+
+        if autoincrement && self.model.jumps_to_incremented_register() {
+            return self.get_word_from_reg(reg).into();
+        }
+
+        address
     }
 }
 
 // Two-operand
 impl CPU {
     pub fn do_mov(&mut self, memory: &mut Memory, command: Word) {
-        let word_to_move = self.get_word_by_operand(memory, src_operand(command));
+        let src = src_operand(command);
+        let dst = dst_operand(command);
+
+        let mut word_to_move = self.get_word_by_operand(memory, src);
+
+        // Later models read PC as a source after the destination's index word is fetched
+        if src == PROGRAM_COUNTER_INDEX && has_index_word(dst) && !self.model.stores_pc_before_index_word() {
+            word_to_move = word_to_move.wrapping_add(Word::size_bytes().word());
+        }
 
-        self.put_word_by_operand(memory, dst_operand(command), word_to_move);
+        self.put_word_by_operand(memory, dst, word_to_move);
 
         self.update_status_flags_bitwise(word_to_move);
     }
@@ -737,7 +897,7 @@ impl CPU {
         let dst_value = self.get_word_by_operand(memory, dst);
         let src_value = self.get_word_by_operand(memory, src_operand(command));
 
-        let sub = dst_value as LongWord - src_value as LongWord;
+        let sub = (dst_value as LongWord).wrapping_sub(src_value as LongWord);
 
         let result = sub as Word;
 
@@ -750,7 +910,7 @@ impl CPU {
         let dst_value = self.get_word_by_operand(memory, dst_operand(command));
         let src_value = self.get_word_by_operand(memory, src_operand(command));
 
-        let sub = src_value as LongWord - dst_value as LongWord;
+        let sub = (src_value as LongWord).wrapping_sub(dst_value as LongWord);
 
         let result = sub as Word;
 
@@ -761,7 +921,7 @@ impl CPU {
         let dst_value = self.get_byte_by_operand(memory, dst_operand(command));
         let src_value = self.get_byte_by_operand(memory, src_operand(command));
 
-        let sub = src_value as Word - dst_value as Word;
+        let sub = (src_value as Word).wrapping_sub(dst_value as Word);
 
         let result = sub as Byte;
 
@@ -846,7 +1006,7 @@ impl CPU {
 
         let pc = self.get_word_from_reg(PROGRAM_COUNTER_INDEX);
 
-        let result = pc + offset;
+        let result = pc.wrapping_add(offset);
 
         self.set_word_reg(PROGRAM_COUNTER_INDEX, result);
     }
@@ -959,16 +1119,35 @@ impl CPU {
 // Perform trap
 impl CPU {
     pub (in super) fn perform_trap(&mut self, memory: &mut Memory, trap_address: Address) {
+        // An abort raised earlier in the same instruction is serviced first
+        let trap_address = self.pending_trap.take().unwrap_or(trap_address);
+
         let pc_value = self.get_word_from_reg(PROGRAM_COUNTER_INDEX);
         let psw_value = self.status_word();
 
+        let new_pc = self.read_virtual_word_in_mode(memory, trap_address, ProcessorMode::Kernel, AddressSpace::Data);
+        let new_psw = self.read_virtual_word_in_mode(memory, trap_address + 2, ProcessorMode::Kernel, AddressSpace::Data);
+
+        // The old state is saved on the stack of the mode the vector switches to
+        let old_mode = self.current_mode() as Byte;
+
+        self.set_status_word(new_psw
+            .set_n_bit(PREVIOUS_MODE_LOW_BIT_INDEX, old_mode.get_n_bit(0))
+            .set_n_bit(PREVIOUS_MODE_HIGH_BIT_INDEX, old_mode.get_n_bit(1)));
+
         self.push_stack(memory, psw_value);
         self.push_stack(memory, pc_value);
 
-        let new_pc = memory.read_word(trap_address);
-        let new_psw = memory.read_word(trap_address + 2);
-
         self.set_word_reg(PROGRAM_COUNTER_INDEX, new_pc);
-        self.set_status_word(new_psw);
+
+        // Reading the vector or pushing the old state failed, there is nowhere left to trap to
+        if let Some(fault_vector) = self.pending_trap.take() {
+            error!("double fault while trapping to 0x{trap_address:04X}");
+
+            self.halt(HaltReason::DoubleFault { vector: trap_address, fault_vector });
+            return;
+        }
+
+        self.profile_call(new_pc);
     }
 }
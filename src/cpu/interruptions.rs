@@ -1,5 +1,7 @@
 
-use super::{blocking_queue::BlockingQueue, Address, Byte};
+use crate::snapshot::{SnapshotError, SnapshotReader, SnapshotWriter};
+
+use super::{blocking_queue::BlockingQueue, Address, Byte, Word};
 
 pub struct InterruptionBus {
     interruption_br4: BlockingQueue<Address>,
@@ -8,6 +10,12 @@ pub struct InterruptionBus {
     interruption_br7: BlockingQueue<Address>,
 }
 
+impl Default for InterruptionBus {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl InterruptionBus {
     pub fn new() -> Self {
         InterruptionBus {
@@ -31,6 +39,66 @@ impl InterruptionBus {
         }
     }
 
+    // A device dropped its request before the CPU took it
+    pub fn withdraw(&mut self, vector_address: Address, priority: Byte) {
+        match priority {
+            0x04 => self.interruption_br4.remove(&vector_address),
+            0x05 => self.interruption_br5.remove(&vector_address),
+            0x06 => self.interruption_br6.remove(&vector_address),
+            0x07 => self.interruption_br7.remove(&vector_address),
+            _ => panic!(),
+        }
+    }
+
+    // Drops every pending request, as after bus INIT
+    pub fn clear(&mut self) {
+        for queue in [&self.interruption_br4, &self.interruption_br5, &self.interruption_br6, &self.interruption_br7] {
+            while queue.pop().is_some() {}
+        }
+    }
+
+    // Pending requests as (priority, vector address), highest priority first
+    pub fn pending(&self) -> Vec<(Byte, Address)> {
+        let queues = [
+            (0x07, &self.interruption_br7),
+            (0x06, &self.interruption_br6),
+            (0x05, &self.interruption_br5),
+            (0x04, &self.interruption_br4),
+        ];
+
+        queues.into_iter()
+            .flat_map(|(priority, queue)| queue.pending().into_iter().map(move |vector_address| (priority, vector_address)))
+            .collect()
+    }
+
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        let pending = self.pending();
+
+        writer.write_word(pending.len() as Word);
+
+        for (priority, vector_address) in pending {
+            writer.write_byte(priority);
+            writer.write_word(vector_address as Word);
+        }
+    }
+
+    pub fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.clear();
+
+        for _ in 0..reader.read_word()? {
+            let priority = reader.read_byte()?;
+            let vector_address = reader.read_word()? as Address;
+
+            if !(0x04..=0x07).contains(&priority) {
+                return Err(SnapshotError::Mismatch(format!("interrupt priority {priority}")));
+            }
+
+            self.interrupt(vector_address, priority);
+        }
+
+        Ok(())
+    }
+
     pub fn next_interruption_if_any(&self, priority: Byte) -> Option<Address> {
         assert!(priority <= 0x07);
 
@@ -58,6 +126,6 @@ impl InterruptionBus {
             return Some(l4_interruption);
         };
 
-        return None;
+        None
     }
 }
\ No newline at end of file
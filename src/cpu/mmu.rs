@@ -0,0 +1,545 @@
+use std::{ops::Range, sync::{Arc, Mutex}};
+
+use crate::{bus::{read_register, write_register, AccessWidth, BusDevice}, mem::{unibus_physical_address, UNIBUS_IO_PAGE_START, unmapped_physical_address, BusError, MappedMemoryWord, Memory}, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter}, utils::{Address, Byte, Number, Word}};
+
+use super::{ProcessorMode, BUS_ERROR_TRAP_VECTOR, CPU, PROGRAM_COUNTER_INDEX};
+
+// KT11 memory management unit
+// https://bitsavers.org/pdf/dec/pdp11/1145/EK-KT11C-MM-001_KT11-C_Memory_Management_Unit_Maintenance_Manual.pdf
+
+pub const MMU_TRAP_VECTOR: Address = 0x00A8; // 250 (oct)
+
+pub const SR0_ADDRESS: Address = 0xFF7A; // 177572 (oct)
+pub const SR1_ADDRESS: Address = 0xFF7C; // 177574 (oct)
+pub const SR2_ADDRESS: Address = 0xFF7E; // 177576 (oct)
+pub const SR3_ADDRESS: Address = 0xF54E; // 172516 (oct)
+
+// First I-space PDR of every mode, D-space PDRs follow at +0x10, PARs at +0x20 and +0x30
+pub const KERNEL_PAGE_REGISTERS_ADDRESS: Address = 0xF4C0; // 172300 (oct)
+pub const SUPERVISOR_PAGE_REGISTERS_ADDRESS: Address = 0xF480; // 172200 (oct)
+pub const USER_PAGE_REGISTERS_ADDRESS: Address = 0xFF80; // 177600 (oct)
+
+pub const PAGE_COUNT: usize = 8;
+
+pub const SR0_ENABLE_BIT: Byte = 0x00;
+pub const SR0_ABORT_READ_ONLY_BIT: Byte = 0x0D;
+pub const SR0_ABORT_PAGE_LENGTH_BIT: Byte = 0x0E;
+pub const SR0_ABORT_NON_RESIDENT_BIT: Byte = 0x0F;
+
+pub const SR3_22_BIT_MAPPING_BIT: Byte = 0x04;
+
+pub const PDR_EXPANSION_DOWN_BIT: Byte = 0x03;
+pub const PDR_WRITTEN_BIT: Byte = 0x06;
+
+/**
+ * SR0 abort flags mask
+ * 1110000000000000
+ * FEDCBA9876543210
+ */
+pub const SR0_ABORT_MASK: Word = 0xE000;
+
+/**
+ * Page descriptor software writable bits
+ * 0111111100001110
+ * FEDCBA9876543210
+ */
+pub const PDR_WRITABLE_MASK: Word = 0x7F0E;
+
+// Full 8 KB page, read/write
+pub const PDR_FULL_READ_WRITE: Word = 0x7F06;
+
+/**
+ * Page address field in 64-byte blocks (18-bit)
+ * 0000111111111111
+ * FEDCBA9876543210
+ */
+pub const PAR_MASK: Word = 0x0FFF;
+
+/**
+ * Page address field in 64-byte blocks (22-bit)
+ * 1111111111111111
+ * FEDCBA9876543210
+ */
+pub const PAR_22_BIT_MASK: Word = 0xFFFF;
+
+/**
+ * SR3 software writable bits, D-space enables and 22-bit mapping
+ * 0000000000010111
+ * FEDCBA9876543210
+ */
+pub const SR3_22_BIT_WRITABLE_MASK: Word = 0x0017;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AddressSpace {
+    Instruction = 0x0,
+    Data = 0x1,
+}
+
+// Access control field (PDR bits 2-1)
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum AccessControl {
+    NonResident,
+    ReadOnly,
+    ReadWrite,
+}
+
+impl From<Word> for AccessControl {
+    fn from(pdr: Word) -> AccessControl {
+        match (pdr >> 1) & 0x0003 {
+            0x1 => AccessControl::ReadOnly,
+            0x3 => AccessControl::ReadWrite,
+            _ => AccessControl::NonResident,
+        }
+    }
+}
+
+pub struct MmuRegister {
+    word: Word,
+    writable_mask: Word,
+    cleared_on_write_mask: Word,
+}
+
+impl MmuRegister {
+    pub fn new(writable_mask: Word, cleared_on_write_mask: Word) -> Self {
+        MmuRegister {
+            word: 0x0000u16,
+            writable_mask,
+            cleared_on_write_mask,
+        }
+    }
+
+    // Internal update by the MMU itself, bypassing the software write mask
+    pub fn set(&mut self, word: Word) {
+        self.word = word;
+    }
+}
+
+impl MappedMemoryWord for MmuRegister {
+    fn read_word(&self) -> Word {
+        self.word
+    }
+
+    fn write_word(&mut self, word: Word) {
+        let kept = self.word & !self.writable_mask;
+        let written = word & self.writable_mask;
+
+        self.word = (kept | written) & !self.cleared_on_write_mask;
+    }
+}
+
+type SharedMmuRegister = Arc<Mutex<MmuRegister>>;
+
+// A contiguous run of MMU registers answering on the I/O page as one device
+struct MmuRegisterBlock {
+    start: Address,
+    registers: Vec<SharedMmuRegister>,
+}
+
+impl MmuRegisterBlock {
+    fn register(&self, address: Address) -> &SharedMmuRegister {
+        &self.registers[(address - self.start) / 2]
+    }
+}
+
+impl BusDevice for MmuRegisterBlock {
+    fn address_range(&self) -> Range<Address> {
+        self.start..self.start + 2 * self.registers.len()
+    }
+
+    fn read(&mut self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        self.peek(address, width)
+    }
+
+    fn write(&mut self, address: Address, width: AccessWidth, data: Word) -> Result<(), BusError> {
+        write_register(&mut *self.register(address).lock().unwrap(), address, width, data);
+
+        Ok(())
+    }
+
+    // Reading a register has no side effects
+    fn peek(&self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        Ok(read_register(&*self.register(address).lock().unwrap(), address, width))
+    }
+}
+
+struct PageRegisters {
+    pdr: [[SharedMmuRegister; PAGE_COUNT]; 2],
+    par: [[SharedMmuRegister; PAGE_COUNT]; 2],
+}
+
+impl PageRegisters {
+    fn new(par_mask: Word) -> Self {
+        PageRegisters {
+            pdr: std::array::from_fn(|_| std::array::from_fn(|_| Arc::new(Mutex::new(MmuRegister::new(PDR_WRITABLE_MASK, 0x0000u16.set_n_bit(PDR_WRITTEN_BIT, true)))))),
+            par: std::array::from_fn(|_| std::array::from_fn(|_| Arc::new(Mutex::new(MmuRegister::new(par_mask, 0x0000u16))))),
+        }
+    }
+
+    fn registers_with_offsets(&self) -> Vec<(Address, SharedMmuRegister)> {
+        let mut result = Vec::new();
+
+        for space in [AddressSpace::Instruction, AddressSpace::Data] {
+            for page in 0..PAGE_COUNT {
+                let offset = (space as Address) * 0x10 + page * 2;
+
+                result.push((offset, self.pdr[space as usize][page].clone()));
+                result.push((0x20 + offset, self.par[space as usize][page].clone()));
+            }
+        }
+
+        result
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MmuAbort {
+    pub flags: Word,
+}
+
+pub struct Mmu {
+    sr0: SharedMmuRegister,
+    sr1: SharedMmuRegister,
+    sr2: SharedMmuRegister,
+    sr3: SharedMmuRegister,
+    kernel: PageRegisters,
+    supervisor: PageRegisters,
+    user: PageRegisters,
+}
+
+impl Mmu {
+    pub fn new() -> Self {
+        Self::with_addressing(false)
+    }
+
+    // 22-bit capable units have wider PARs and the mapping enable in SR3
+    pub fn with_addressing(has_22_bit_addressing: bool) -> Self {
+        let (par_mask, sr3_mask) = if has_22_bit_addressing {
+            (PAR_22_BIT_MASK, SR3_22_BIT_WRITABLE_MASK)
+        } else {
+            (PAR_MASK, 0x0007)
+        };
+
+        Mmu {
+            sr0: Arc::new(Mutex::new(MmuRegister::new(0xE101, 0x0000))),
+            sr1: Arc::new(Mutex::new(MmuRegister::new(0x0000, 0x0000))),
+            sr2: Arc::new(Mutex::new(MmuRegister::new(0x0000, 0x0000))),
+            sr3: Arc::new(Mutex::new(MmuRegister::new(sr3_mask, 0x0000))),
+            kernel: PageRegisters::new(par_mask),
+            supervisor: PageRegisters::new(par_mask),
+            user: PageRegisters::new(par_mask),
+        }
+    }
+
+    pub fn map_registers(&self, memory: &mut Memory) {
+        for block in self.register_blocks() {
+            memory.attach_device(Arc::new(Mutex::new(block)));
+        }
+    }
+
+    pub fn unmap_registers(&self, memory: &mut Memory) {
+        for block in self.register_blocks() {
+            memory.detach_device(block.start);
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        for (_, register) in self.registers_with_addresses() {
+            writer.write_word(register.lock().unwrap().read_word());
+        }
+    }
+
+    pub fn restore_state(&self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        for (_, register) in self.registers_with_addresses() {
+            register.lock().unwrap().set(reader.read_word()?);
+        }
+
+        Ok(())
+    }
+
+    fn registers_with_addresses(&self) -> Vec<(Address, SharedMmuRegister)> {
+        let mut result = vec![
+            (SR0_ADDRESS, self.sr0.clone()),
+            (SR1_ADDRESS, self.sr1.clone()),
+            (SR2_ADDRESS, self.sr2.clone()),
+            (SR3_ADDRESS, self.sr3.clone()),
+        ];
+
+        let modes = [
+            (KERNEL_PAGE_REGISTERS_ADDRESS, &self.kernel),
+            (SUPERVISOR_PAGE_REGISTERS_ADDRESS, &self.supervisor),
+            (USER_PAGE_REGISTERS_ADDRESS, &self.user),
+        ];
+
+        for (base, registers) in modes {
+            for (offset, register) in registers.registers_with_offsets() {
+                result.push((base + offset, register));
+            }
+        }
+
+        result
+    }
+
+    // SR0-SR2 run into the user page registers, the kernel ones follow the supervisor ones, SR3 is alone
+    fn register_blocks(&self) -> Vec<MmuRegisterBlock> {
+        let mut registers = self.registers_with_addresses();
+        registers.sort_by_key(|(address, _)| *address);
+
+        let mut blocks: Vec<MmuRegisterBlock> = Vec::new();
+
+        for (address, register) in registers {
+            match blocks.last_mut() {
+                Some(block) if block.address_range().end == address => block.registers.push(register),
+                _ => blocks.push(MmuRegisterBlock { start: address, registers: vec![register] }),
+            }
+        }
+
+        blocks
+    }
+
+    // RESET turns relocation off, the page registers keep their value
+    pub fn reset(&self) {
+        self.sr0.lock().unwrap().set(0x0000);
+        self.sr3.lock().unwrap().set(0x0000);
+    }
+
+    // Kernel I space from physical 0 and D space from the base, page 7 of both on the I/O page
+    pub fn map_kernel_spaces(&self, data_space_base: Address) {
+        let io_page_par = (UNIBUS_IO_PAGE_START >> 6) as Word;
+        let page_blocks = 0x2000 >> 6;
+
+        for page in 0..PAGE_COUNT {
+            let bases = [0, data_space_base >> 6];
+
+            for space in [AddressSpace::Instruction, AddressSpace::Data] {
+                let par = if page == PAGE_COUNT - 1 { io_page_par } else { (bases[space as usize] + page * page_blocks) as Word };
+
+                self.kernel.pdr[space as usize][page].lock().unwrap().set(PDR_FULL_READ_WRITE);
+                self.kernel.par[space as usize][page].lock().unwrap().set(par);
+            }
+        }
+
+        let mut sr3 = self.sr3.lock().unwrap();
+        let sr3_word = sr3.read_word();
+        sr3.set(sr3_word.set_n_bit(2, true));
+
+        let mut sr0 = self.sr0.lock().unwrap();
+        let sr0_word = sr0.read_word();
+        sr0.set(sr0_word.set_n_bit(SR0_ENABLE_BIT, true));
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.sr0.lock().unwrap().read_word().get_n_bit(SR0_ENABLE_BIT)
+    }
+
+    // SR0-SR2 keep the state of the first abort until software clears the abort flags
+    pub fn frozen(&self) -> bool {
+        (self.sr0.lock().unwrap().read_word() & SR0_ABORT_MASK) != 0
+    }
+
+    pub fn begin_instruction(&self, address: Address) {
+        if self.frozen() {
+            return;
+        }
+
+        self.sr1.lock().unwrap().set(0x0000);
+        self.sr2.lock().unwrap().set(address as Word);
+    }
+
+    // SR1 holds up to two general register modifications of the current instruction
+    pub fn record_register_change(&self, reg_index: Byte, change: i8) {
+        if reg_index == PROGRAM_COUNTER_INDEX || self.frozen() {
+            return;
+        }
+
+        let record = ((change as Byte & 0x1F) << 3 | reg_index) as Word;
+
+        let mut sr1 = self.sr1.lock().unwrap();
+        let current = sr1.read_word();
+
+        if current == 0x0000 {
+            sr1.set(record);
+        } else {
+            sr1.set(current | record << 8);
+        }
+    }
+
+    pub fn translate(&self, address: Address, mode: ProcessorMode, space: AddressSpace, write: bool) -> Result<Address, MmuAbort> {
+        self.map(address, mode, space, write, true)
+    }
+
+    // Where a read would go, for debuggers: SR0 is left alone when the page can't be reached
+    pub fn peek_translate(&self, address: Address, mode: ProcessorMode, space: AddressSpace) -> Option<Address> {
+        self.map(address, mode, space, false, false).ok()
+    }
+
+    fn map(&self, address: Address, mode: ProcessorMode, space: AddressSpace, write: bool, record_abort: bool) -> Result<Address, MmuAbort> {
+        if !self.enabled() {
+            return Ok(unmapped_physical_address(address));
+        }
+
+        let page = (address >> 13) & 0x7;
+        let abort = |reason_bit, space| match record_abort {
+            true => self.abort(reason_bit, mode, space, page),
+            false => MmuAbort { flags: 0x0000u16.set_n_bit(reason_bit, true) },
+        };
+
+        let Some(registers) = self.page_registers(mode) else {
+            return Err(abort(SR0_ABORT_NON_RESIDENT_BIT, space));
+        };
+
+        let space = self.effective_space(mode, space);
+
+        let mut pdr_register = registers.pdr[space as usize][page].lock().unwrap();
+        let pdr = pdr_register.read_word();
+        let par = registers.par[space as usize][page].lock().unwrap().read_word();
+
+        let access = AccessControl::from(pdr);
+
+        if access == AccessControl::NonResident {
+            return Err(abort(SR0_ABORT_NON_RESIDENT_BIT, space));
+        }
+
+        let block = ((address >> 6) & 0x7F) as Word;
+        let page_length = (pdr >> 8) & 0x7F;
+
+        let out_of_page = if pdr.get_n_bit(PDR_EXPANSION_DOWN_BIT) {
+            block < page_length
+        } else {
+            block > page_length
+        };
+
+        if out_of_page {
+            return Err(abort(SR0_ABORT_PAGE_LENGTH_BIT, space));
+        }
+
+        if write && access == AccessControl::ReadOnly {
+            return Err(abort(SR0_ABORT_READ_ONLY_BIT, space));
+        }
+
+        if write {
+            pdr_register.set(pdr.set_n_bit(PDR_WRITTEN_BIT, true));
+        }
+
+        if self.mapping_22_bit() {
+            return Ok(((par as Address) << 6) + (address & 0x1FFF));
+        }
+
+        Ok(unibus_physical_address((((par & PAR_MASK) as Address) << 6) + (address & 0x1FFF)))
+    }
+
+    fn mapping_22_bit(&self) -> bool {
+        self.sr3.lock().unwrap().read_word().get_n_bit(SR3_22_BIT_MAPPING_BIT)
+    }
+
+    fn page_registers(&self, mode: ProcessorMode) -> Option<&PageRegisters> {
+        match mode {
+            ProcessorMode::Kernel => Some(&self.kernel),
+            ProcessorMode::Supervisor => Some(&self.supervisor),
+            ProcessorMode::User => Some(&self.user),
+            ProcessorMode::Illegal => None,
+        }
+    }
+
+    // Without D-space enabled in SR3 data references use the I-space registers
+    fn effective_space(&self, mode: ProcessorMode, space: AddressSpace) -> AddressSpace {
+        let sr3 = self.sr3.lock().unwrap().read_word();
+
+        let d_space_enabled = match mode {
+            ProcessorMode::User => sr3.get_n_bit(0),
+            ProcessorMode::Supervisor => sr3.get_n_bit(1),
+            ProcessorMode::Kernel => sr3.get_n_bit(2),
+            ProcessorMode::Illegal => false,
+        };
+
+        if d_space_enabled { space } else { AddressSpace::Instruction }
+    }
+
+    fn abort(&self, reason_bit: Byte, mode: ProcessorMode, space: AddressSpace, page: usize) -> MmuAbort {
+        let flags = 0x0000u16.set_n_bit(reason_bit, true);
+
+        if !self.frozen() {
+            let mut sr0 = self.sr0.lock().unwrap();
+            let enable = sr0.read_word() & 0x0001;
+
+            sr0.set(flags | (mode as Word) << 5 | (space as Word) << 4 | (page as Word) << 1 | enable);
+        }
+
+        MmuAbort { flags }
+    }
+}
+
+impl Default for Mmu {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Virtual memory access
+impl CPU {
+    pub (in super) fn read_virtual_word(&mut self, memory: &Memory, address: Address, space: AddressSpace) -> Word {
+        self.read_virtual_word_in_mode(memory, address, self.current_mode(), space)
+    }
+
+    pub (in super) fn read_virtual_byte(&mut self, memory: &Memory, address: Address, space: AddressSpace) -> Byte {
+        match self.translate(address, self.current_mode(), space, false) {
+            Some(physical) => self.bus_result(memory.read_byte(physical)).unwrap_or(0x00u8),
+            None => 0x00u8,
+        }
+    }
+
+    pub (in super) fn write_virtual_word(&mut self, memory: &mut Memory, address: Address, space: AddressSpace, word: Word) {
+        self.write_virtual_word_in_mode(memory, address, self.current_mode(), space, word);
+    }
+
+    pub (in super) fn write_virtual_byte(&mut self, memory: &mut Memory, address: Address, space: AddressSpace, byte: Byte) {
+        if let Some(physical) = self.translate(address, self.current_mode(), space, true) {
+            self.bus_result(memory.write_byte(physical, byte));
+        }
+    }
+
+    pub (in super) fn read_virtual_word_in_mode(&mut self, memory: &Memory, address: Address, mode: ProcessorMode, space: AddressSpace) -> Word {
+        match self.translate(address, mode, space, false) {
+            Some(physical) => self.bus_result(memory.read_word(physical)).unwrap_or(0x0000u16),
+            None => 0x0000u16,
+        }
+    }
+
+    pub (in super) fn write_virtual_word_in_mode(&mut self, memory: &mut Memory, address: Address, mode: ProcessorMode, space: AddressSpace, word: Word) {
+        if let Some(physical) = self.translate(address, mode, space, true) {
+            self.bus_result(memory.write_word(physical, word));
+        }
+    }
+
+    // Once an abort is pending the rest of the instruction has no memory effects
+    fn translate(&mut self, address: Address, mode: ProcessorMode, space: AddressSpace, write: bool) -> Option<Address> {
+        if self.pending_trap.is_some() {
+            return None;
+        }
+
+        match self.mmu.translate(address & 0xFFFF, mode, space, write) {
+            Ok(physical) => Some(physical),
+            Err(abort) => {
+                trace!("mmu abort 0x{:04X} at 0x{address:04X}", abort.flags);
+
+                self.pending_trap = Some(MMU_TRAP_VECTOR);
+                None
+            }
+        }
+    }
+
+    fn bus_result<T>(&mut self, result: Result<T, BusError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(error) => {
+                trace!("bus error: {error}");
+
+                self.pending_trap = Some(BUS_ERROR_TRAP_VECTOR);
+                None
+            }
+        }
+    }
+}
+
+pub (in super) fn space_for_register(reg_index: Byte) -> AddressSpace {
+    if reg_index == PROGRAM_COUNTER_INDEX { AddressSpace::Instruction } else { AddressSpace::Data }
+}
@@ -0,0 +1,131 @@
+use std::{fmt, str::FromStr};
+
+// Processor models and what sets them apart
+// https://gunkies.org/wiki/PDP-11_Family_Differences
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum CpuModel {
+    Pdp1103, // LSI-11 with the KEV11 EIS/FIS option
+    Pdp1120,
+    Pdp1140, // With the KE11-E/F EIS/FIS options and the KT11-D MMU
+    Pdp1145,
+    // The default, and so what CPU::default() builds. It has the FP11 but not FIS,
+    // whose FADD, FSUB, FMUL and FDIV trap to 10 here: ask for the 11/40 or 11/03 for those
+    #[default]
+    Pdp1170,
+    Pdp1173, // Q-bus J-11 with the FPF11
+    Pdp1183,
+}
+
+impl CpuModel {
+    pub const ALL: [CpuModel; 7] = [
+        CpuModel::Pdp1103,
+        CpuModel::Pdp1120,
+        CpuModel::Pdp1140,
+        CpuModel::Pdp1145,
+        CpuModel::Pdp1170,
+        CpuModel::Pdp1173,
+        CpuModel::Pdp1183,
+    ];
+
+    fn is_j11(&self) -> bool {
+        matches!(self, CpuModel::Pdp1173 | CpuModel::Pdp1183)
+    }
+
+    // MUL, DIV, ASH, ASHC
+    pub fn has_eis(&self) -> bool {
+        *self != CpuModel::Pdp1120
+    }
+
+    // FADD, FSUB, FMUL, FDIV
+    pub fn has_fis(&self) -> bool {
+        matches!(self, CpuModel::Pdp1103 | CpuModel::Pdp1140)
+    }
+
+    // FP11 floating point processor
+    pub fn has_fpu(&self) -> bool {
+        matches!(self, CpuModel::Pdp1145 | CpuModel::Pdp1170) || self.is_j11()
+    }
+
+    pub fn has_mmu(&self) -> bool {
+        matches!(self, CpuModel::Pdp1140 | CpuModel::Pdp1145 | CpuModel::Pdp1170) || self.is_j11()
+    }
+
+    // The MMU can relocate to 22-bit physical addresses, up to 4 MB
+    pub fn has_22_bit_addressing(&self) -> bool {
+        *self == CpuModel::Pdp1170 || self.is_j11()
+    }
+
+    // SXT, XOR, SOB, MARK and RTT came with the 11/35-11/40 generation
+    pub fn has_extended_base_set(&self) -> bool {
+        *self != CpuModel::Pdp1120
+    }
+
+    // MTPS and MFPS
+    pub fn has_processor_status_instructions(&self) -> bool {
+        *self == CpuModel::Pdp1103 || self.is_j11()
+    }
+
+    // The LSI-11 reaches the PSW through MTPS and MFPS only
+    pub fn has_memory_mapped_psw(&self) -> bool {
+        *self != CpuModel::Pdp1103
+    }
+
+    pub fn has_spl(&self) -> bool {
+        matches!(self, CpuModel::Pdp1145 | CpuModel::Pdp1170) || self.is_j11()
+    }
+
+    // MFPI and MTPI
+    pub fn has_previous_instruction_space(&self) -> bool {
+        self.has_mmu()
+    }
+
+    // Separate I and D page registers in the MMU
+    pub fn has_separate_spaces(&self) -> bool {
+        matches!(self, CpuModel::Pdp1145 | CpuModel::Pdp1170) || self.is_j11()
+    }
+
+    // MFPD and MTPD
+    pub fn has_previous_data_space(&self) -> bool {
+        self.has_separate_spaces()
+    }
+
+    // JMP (R)+ and JSR reg,(R)+ jump to the incremented register on the 11/20
+    pub fn jumps_to_incremented_register(&self) -> bool {
+        *self == CpuModel::Pdp1120
+    }
+
+    // MOV PC,X(R), MOV PC,@#A and alike store PC+2 on the 11/20 and PC+4 elsewhere
+    pub fn stores_pc_before_index_word(&self) -> bool {
+        *self == CpuModel::Pdp1120
+    }
+}
+
+impl fmt::Display for CpuModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            CpuModel::Pdp1103 => "11/03",
+            CpuModel::Pdp1120 => "11/20",
+            CpuModel::Pdp1140 => "11/40",
+            CpuModel::Pdp1145 => "11/45",
+            CpuModel::Pdp1170 => "11/70",
+            CpuModel::Pdp1173 => "11/73",
+            CpuModel::Pdp1183 => "11/83",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for CpuModel {
+    type Err = String;
+
+    // Accepts "11/70", "1170" and "pdp-11/70" alike
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits: String = s.chars().filter(char::is_ascii_digit).collect();
+
+        CpuModel::ALL.into_iter()
+            .find(|model| model.to_string().replace('/', "") == digits)
+            .ok_or(format!("unknown CPU model {s}"))
+    }
+}
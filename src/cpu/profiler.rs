@@ -0,0 +1,234 @@
+use std::{collections::HashMap, fs, io, path::{Path, PathBuf}};
+
+use crate::{loader::symbols::SymbolTable, utils::{Address, Word}};
+
+use super::CPU;
+
+pub const HOT_SPOT_COUNT: usize = 20;
+
+// Counts of one routine, entered by JSR or through a trap or interrupt vector
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct FunctionProfile {
+    pub calls: u64,
+    pub inclusive: u64, // Instructions until it returned, callees included
+    pub exclusive: u64, // Instructions of its own
+}
+
+struct Frame {
+    entry: Word,
+    entered_at: u64, // Instruction count at the call
+}
+
+pub struct Profiler {
+    instructions: u64,
+    pc_counts: HashMap<Word, u64>,
+    command_counts: HashMap<&'static str, u64>,
+    functions: HashMap<Option<Word>, FunctionProfile>, // None is the code outside of any call
+    calls: HashMap<(Option<Word>, Word), u64>, // Caller and callee entries
+    stack: Vec<Frame>,
+    symbols: SymbolTable,
+    report_path: Option<PathBuf>,
+}
+
+impl Default for Profiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Profiler {
+    pub fn new() -> Self {
+        Profiler {
+            instructions: 0,
+            pc_counts: HashMap::new(),
+            command_counts: HashMap::new(),
+            functions: HashMap::new(),
+            calls: HashMap::new(),
+            stack: Vec::new(),
+            symbols: SymbolTable::default(),
+            report_path: None,
+        }
+    }
+
+    // Names for addresses in the report
+    pub fn with_symbols(mut self, symbols: SymbolTable) -> Self {
+        self.symbols = symbols;
+        self
+    }
+
+    // Written whenever the CPU halts
+    pub fn with_report_path(mut self, path: impl AsRef<Path>) -> Self {
+        self.report_path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    pub fn instructions(&self) -> u64 {
+        self.instructions
+    }
+
+    pub fn pc_count(&self, address: Word) -> u64 {
+        self.pc_counts.get(&address).copied().unwrap_or(0)
+    }
+
+    pub fn command_count(&self, name: &str) -> u64 {
+        self.command_counts.get(name).copied().unwrap_or(0)
+    }
+
+    // Routines still running count up to now, None is the top level
+    pub fn function(&self, entry: Option<Word>) -> FunctionProfile {
+        self.function_profiles().get(&entry).copied().unwrap_or_default()
+    }
+
+    pub fn call_count(&self, caller: Option<Word>, callee: Word) -> u64 {
+        self.calls.get(&(caller, callee)).copied().unwrap_or(0)
+    }
+
+    pub fn write_report(&self) -> io::Result<()> {
+        match &self.report_path {
+            Some(path) => fs::write(path, self.report()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn report(&self) -> String {
+        let mut lines = vec![format!("Profile of {} instructions", self.instructions)];
+
+        lines.push(String::new());
+        lines.push("Hot spots".to_string());
+        lines.push(format!("{:>12} {:>7}  {:6}  {}", "count", "%", "address", "location"));
+
+        let mut pc_counts: Vec<_> = self.pc_counts.iter().collect();
+        pc_counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        for (address, count) in pc_counts.into_iter().take(HOT_SPOT_COUNT) {
+            lines.push(format!("{count:>12} {}  {address:06o}   {}", self.percent(*count), self.symbols.describe(*address)));
+        }
+
+        lines.push(String::new());
+        lines.push("Instructions".to_string());
+        lines.push(format!("{:>12} {:>7}  {}", "count", "%", "name"));
+
+        let mut command_counts: Vec<_> = self.command_counts.iter().collect();
+        command_counts.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        for (name, count) in command_counts {
+            lines.push(format!("{count:>12} {}  {name}", self.percent(*count)));
+        }
+
+        lines.push(String::new());
+        lines.push("Functions".to_string());
+        lines.push(format!("{:>12} {:>12} {:>12}  {}", "calls", "inclusive", "exclusive", "function"));
+
+        let mut functions: Vec<_> = self.function_profiles().into_iter().collect();
+        functions.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(&b.0)));
+
+        for (entry, profile) in functions {
+            lines.push(format!("{:>12} {:>12} {:>12}  {}", profile.calls, profile.inclusive, profile.exclusive, self.function_name(entry)));
+        }
+
+        lines.push(String::new());
+        lines.push("Calls".to_string());
+        lines.push(format!("{:>12}  {}", "count", "caller -> callee"));
+
+        let mut calls: Vec<_> = self.calls.iter().collect();
+        calls.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+
+        for ((caller, callee), count) in calls {
+            lines.push(format!("{count:>12}  {} -> {}", self.function_name(*caller), self.function_name(Some(*callee))));
+        }
+
+        lines.join("\n") + "\n"
+    }
+
+    fn count(&mut self, address: Word, command_name: &'static str) {
+        self.instructions += 1;
+
+        *self.pc_counts.entry(address).or_default() += 1;
+        *self.command_counts.entry(command_name).or_default() += 1;
+
+        let current = self.stack.last().map(|frame| frame.entry);
+        self.functions.entry(current).or_default().exclusive += 1;
+    }
+
+    fn enter(&mut self, entry: Word) {
+        let caller = self.stack.last().map(|frame| frame.entry);
+
+        *self.calls.entry((caller, entry)).or_default() += 1;
+        self.functions.entry(Some(entry)).or_default().calls += 1;
+
+        self.stack.push(Frame { entry, entered_at: self.instructions });
+    }
+
+    // Returns without a call, like an RTI starting a process, are ignored
+    fn leave(&mut self) {
+        let Some(frame) = self.stack.pop() else {
+            return;
+        };
+
+        // Recursive calls are inside the outermost one already
+        if self.stack.iter().all(|outer| outer.entry != frame.entry) {
+            self.functions.entry(Some(frame.entry)).or_default().inclusive += self.instructions - frame.entered_at;
+        }
+    }
+
+    fn function_profiles(&self) -> HashMap<Option<Word>, FunctionProfile> {
+        let mut profiles = self.functions.clone();
+
+        for (index, frame) in self.stack.iter().enumerate() {
+            if self.stack[..index].iter().all(|outer| outer.entry != frame.entry) {
+                profiles.entry(Some(frame.entry)).or_default().inclusive += self.instructions - frame.entered_at;
+            }
+        }
+
+        if self.instructions > 0 {
+            profiles.entry(None).or_default().inclusive = self.instructions;
+        }
+
+        profiles
+    }
+
+    fn function_name(&self, entry: Option<Word>) -> String {
+        match entry {
+            Some(entry) => self.symbols.describe(entry),
+            None => "(top level)".to_string(),
+        }
+    }
+
+    fn percent(&self, count: u64) -> String {
+        format!("{:>6.2}%", 100.0 * count as f64 / self.instructions.max(1) as f64)
+    }
+}
+
+impl CPU {
+    pub fn set_profiler(&mut self, profiler: Option<Profiler>) {
+        self.profiler = profiler;
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn take_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub(super) fn profile_instruction(&mut self, address: Address, command_name: &'static str) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.count(address as Word, command_name);
+        }
+    }
+
+    // JSR, and traps and interrupts through their vectors
+    pub(super) fn profile_call(&mut self, entry: Word) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.enter(entry);
+        }
+    }
+
+    // RTS, RTI and RTT
+    pub(super) fn profile_return(&mut self) {
+        if let Some(profiler) = &mut self.profiler {
+            profiler.leave();
+        }
+    }
+}
@@ -0,0 +1,61 @@
+use crate::{mem::MappedMemoryWord, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter}};
+
+use super::{model::CpuModel, Byte, CPU};
+
+// Taken between instructions, so there is never an abort or a trap in flight
+impl CPU {
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_byte(self.model_index());
+        writer.write_word(self.status_word());
+
+        for register in self.registers {
+            writer.write_word(register);
+        }
+
+        for stack_pointer in self.stack_pointers {
+            writer.write_word(stack_pointer);
+        }
+
+        writer.write_byte(self.stack_pointer_mode as Byte);
+        writer.write_bool(self.waiting);
+        writer.write_u64(self.executed_instructions);
+
+        self.mmu.save_state(writer);
+        self.fpu.save_state(writer);
+        self.interruption_bus.lock().unwrap().save_state(writer);
+    }
+
+    pub fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let model_index = reader.read_byte()?;
+
+        if model_index != self.model_index() {
+            let model = CpuModel::ALL.get(model_index as usize).map_or("unknown".to_string(), CpuModel::to_string);
+
+            return Err(SnapshotError::Mismatch(format!("snapshot of an {model}, restoring into an {}", self.model)));
+        }
+
+        self.status.lock().unwrap().write_word(reader.read_word()?);
+
+        for register in self.registers.iter_mut() {
+            *register = reader.read_word()?;
+        }
+
+        for stack_pointer in self.stack_pointers.iter_mut() {
+            *stack_pointer = reader.read_word()?;
+        }
+
+        self.stack_pointer_mode = reader.read_byte()?.into();
+        self.waiting = reader.read_bool()?;
+        self.executed_instructions = reader.read_u64()?;
+        self.pending_trap = None;
+        self.halt_reason = None;
+
+        self.mmu.restore_state(reader)?;
+        self.fpu.restore_state(reader)?;
+        self.interruption_bus.lock().unwrap().restore_state(reader)
+    }
+
+    fn model_index(&self) -> Byte {
+        CpuModel::ALL.iter().position(|model| *model == self.model).unwrap() as Byte
+    }
+}
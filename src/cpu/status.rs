@@ -0,0 +1,54 @@
+use std::ops::Range;
+
+use crate::{bus::{read_register, write_register, AccessWidth, BusDevice}, mem::{BusError, MappedMemoryWord}, utils::{Address, Word}};
+
+use super::FLAGS_IN_MEMORY;
+
+// The PSW, on the bus at FLAGS_IN_MEMORY for the models that have it there
+pub struct StatusRegister {
+    word: Word,
+}
+
+impl StatusRegister {
+    pub fn new() -> Self {
+        StatusRegister {
+            word: 0x0000u16,
+        }
+    }
+}
+
+impl Default for StatusRegister {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MappedMemoryWord for StatusRegister {
+    fn read_word(&self) -> Word {
+        self.word
+    }
+
+    fn write_word(&mut self, word: Word) {
+        self.word = word;
+    }
+}
+
+impl BusDevice for StatusRegister {
+    fn address_range(&self) -> Range<Address> {
+        FLAGS_IN_MEMORY..FLAGS_IN_MEMORY + 2
+    }
+
+    fn read(&mut self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        self.peek(address, width)
+    }
+
+    fn write(&mut self, address: Address, width: AccessWidth, data: Word) -> Result<(), BusError> {
+        write_register(self, address, width, data);
+
+        Ok(())
+    }
+
+    fn peek(&self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        Ok(read_register(self, address, width))
+    }
+}
@@ -0,0 +1,202 @@
+use std::{fs::{self, File}, io::{self, BufWriter, Write}, ops::Range, path::{Path, PathBuf}};
+
+use crate::{mem::{Memory, MemoryWrite}, utils::{Address, Word}};
+
+use super::{disassembler::{Disassembler, Disassembly}, mmu::AddressSpace, model::CpuModel, CPU, PROGRAM_COUNTER_INDEX, REG_COUNT, STACK_POINTER_INDEX};
+
+// One tab separated line per executed instruction, all numbers octal:
+// PC, instruction words, mnemonic, registers changed other than the PC, PSW after, memory written.
+// Registers are R0=000001, memory writes W00001000=000001 for words and B00001001=001 for bytes,
+// at physical addresses padded to the 8 digits of the 22-bit space
+pub const TRACE_HEADER: &str = "# PC\tWORDS\tINSTRUCTION\tREGISTERS\tPSW\tMEMORY";
+
+#[derive(Clone, Debug)]
+pub struct TraceConfig {
+    pub path: PathBuf,
+    pub pc_range: Option<Range<Word>>, // Only instructions starting in the range are traced
+    pub max_lines: Option<u64>, // Per file, a full file is rotated to path.1, path.1 to path.2 and so on
+    pub max_files: usize, // Rotated files kept besides the current one
+}
+
+impl TraceConfig {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        TraceConfig {
+            path: path.as_ref().to_path_buf(),
+            pc_range: None,
+            max_lines: None,
+            max_files: 1,
+        }
+    }
+}
+
+pub struct Tracer {
+    config: TraceConfig,
+    disassembler: Disassembler,
+    writer: Option<BufWriter<File>>, // None once writing failed
+    lines: u64, // In the current file
+}
+
+impl Tracer {
+    pub fn create(config: TraceConfig, model: CpuModel) -> io::Result<Self> {
+        let writer = Some(open_trace_file(&config.path)?);
+
+        Ok(Tracer {
+            config,
+            disassembler: Disassembler::for_model(model),
+            writer,
+            lines: 0,
+        })
+    }
+
+    pub fn config(&self) -> &TraceConfig {
+        &self.config
+    }
+
+    pub fn traces(&self, address: Word) -> bool {
+        self.writer.is_some() && self.config.pc_range.as_ref().is_none_or(|range| range.contains(&address))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            Some(writer) => writer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if let Err(error) = self.try_write_line(line) {
+            error!("Trace to {} stopped: {error}", self.config.path.display());
+            self.writer = None;
+        }
+    }
+
+    fn try_write_line(&mut self, line: &str) -> io::Result<()> {
+        if self.config.max_lines.is_some_and(|max_lines| self.lines >= max_lines) {
+            self.rotate()?;
+        }
+
+        if let Some(writer) = &mut self.writer {
+            writeln!(writer, "{line}")?;
+            self.lines += 1;
+        }
+
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut writer) = self.writer.take() {
+            writer.flush()?;
+        }
+
+        let path = &self.config.path;
+
+        if self.config.max_files > 0 {
+            for index in (1..self.config.max_files).rev() {
+                let from = rotated_path(path, index);
+
+                if from.exists() {
+                    fs::rename(from, rotated_path(path, index + 1))?;
+                }
+            }
+
+            fs::rename(path, rotated_path(path, 1))?;
+        }
+
+        self.writer = Some(open_trace_file(path)?);
+        self.lines = 0;
+
+        Ok(())
+    }
+}
+
+fn open_trace_file(path: &Path) -> io::Result<BufWriter<File>> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    writeln!(writer, "{TRACE_HEADER}")?;
+
+    Ok(writer)
+}
+
+pub fn rotated_path(path: &Path, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(format!(".{index}"));
+
+    PathBuf::from(name)
+}
+
+// The machine before the instruction
+pub(super) struct TraceStart {
+    disassembly: Disassembly,
+    registers: [Word; REG_COUNT],
+}
+
+impl CPU {
+    pub fn set_tracer(&mut self, tracer: Option<Tracer>) {
+        self.tracer = tracer;
+    }
+
+    pub fn tracer_mut(&mut self) -> Option<&mut Tracer> {
+        self.tracer.as_mut()
+    }
+
+    // Called once the instruction is fetched, its operand words are read the way it will read them
+    pub(super) fn begin_trace(&mut self, memory: &mut Memory, address: Address, command_word: Word) -> Option<TraceStart> {
+        let tracer = self.tracer.as_ref()?;
+        let address = address as Word;
+
+        if !tracer.traces(address) {
+            return None;
+        }
+
+        let mode = self.current_mode();
+        let disassembly = tracer.disassembler.disassemble(address, |word_address| match word_address == address {
+            true => Some(command_word),
+            false => self.mmu.translate(word_address as Address, mode, AddressSpace::Instruction, false).ok()
+                .and_then(|physical| memory.peek_word(physical).ok()),
+        });
+
+        let mut registers = self.registers;
+        registers[PROGRAM_COUNTER_INDEX as usize] = address;
+
+        memory.start_write_log();
+
+        Some(TraceStart { disassembly, registers })
+    }
+
+    pub(super) fn end_trace(&mut self, memory: &mut Memory, start: TraceStart) {
+        let writes = memory.take_write_log();
+        let status = self.status_word();
+
+        let Some(tracer) = self.tracer.as_mut() else {
+            return;
+        };
+
+        let words = start.disassembly.words.iter().map(|word| format!("{word:06o}")).collect::<Vec<_>>().join(" ");
+
+        let registers = (0..REG_COUNT)
+            .filter(|register| *register != PROGRAM_COUNTER_INDEX as usize && self.registers[*register] != start.registers[*register])
+            .map(|register| format!("{}={:06o}", register_name(register), self.registers[register]))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        let writes = writes.iter().map(format_write).collect::<Vec<_>>().join(" ");
+
+        let line = format!("{:06o}\t{words}\t{}\t{registers}\tPSW={status:06o}\t{writes}", start.disassembly.address, start.disassembly.text);
+
+        tracer.write_line(&line);
+    }
+}
+
+fn register_name(register: usize) -> String {
+    match register as u8 {
+        STACK_POINTER_INDEX => "SP".to_string(),
+        _ => format!("R{register}"),
+    }
+}
+
+fn format_write(write: &MemoryWrite) -> String {
+    match write.byte {
+        true => format!("B{:08o}={:03o}", write.address, write.value),
+        false => format!("W{:08o}={:06o}", write.address, write.value),
+    }
+}
@@ -0,0 +1,451 @@
+use std::{io::{self, BufReader, Read, Write}, net::{TcpListener, TcpStream}, sync::{atomic::{AtomicBool, Ordering}, Arc}, thread, time::Duration};
+
+use crate::{assembly::Pdp11, cpu::{mmu::AddressSpace, HaltReason, PROGRAM_COUNTER_INDEX, REG_COUNT}, mem::WatchKind, utils::{Address, Byte, Word}};
+
+// GDB remote serial protocol. Registers are numbered R0-R7 then PS, all 16 bits little-endian.
+// Addresses are the 16-bit virtual ones of the current mode, as the program sees them: breakpoints are
+// on PCs, memory packets and watchpoints go through the D space, the I space unless split I/D is on.
+// With the MMU off the top 8 KB are the I/O page
+
+pub const DEFAULT_PORT: u16 = 1234;
+
+pub const INTERRUPT_CHAR: Byte = 0x03; // Ctrl-C from the debugger
+
+const PS_REGISTER: usize = REG_COUNT;
+const REGISTER_COUNT: usize = REG_COUNT + 1;
+
+const INTERRUPT_POLL_PERIOD: Duration = Duration::from_millis(50);
+
+// Signals of the stop replies
+const SIGINT: Byte = 2;
+const SIGTRAP: Byte = 5;
+const SIGBUS: Byte = 10;
+
+pub struct GdbStub {
+    machine: Pdp11,
+    connection: Option<TcpStream>, // Watched for Ctrl-C while the machine runs
+    closing: bool, // Detached or killed, the connection ends after the reply
+    watchpoints: Vec<GdbWatchpoint>,
+}
+
+// Memory watches the physical bytes the address was mapped to when gdb set it
+struct GdbWatchpoint {
+    address: Address,
+    length: usize,
+    kind: WatchKind,
+    physical: Address,
+}
+
+impl GdbStub {
+    // The machine is booted so that the first continue starts the loaded image
+    pub fn new(mut machine: Pdp11) -> Self {
+        machine.boot();
+
+        GdbStub {
+            machine,
+            connection: None,
+            closing: false,
+            watchpoints: Vec::new(),
+        }
+    }
+
+    pub fn machine(&self) -> &Pdp11 {
+        &self.machine
+    }
+
+    pub fn into_machine(self) -> Pdp11 {
+        self.machine
+    }
+
+    // Only the loopback interface, there is no authentication
+    pub fn bind(port: u16) -> io::Result<TcpListener> {
+        TcpListener::bind(("127.0.0.1", port))
+    }
+
+    // Serves one debugger until it detaches, kills the target or disconnects
+    pub fn listen(&mut self, listener: &TcpListener) -> io::Result<()> {
+        let (stream, _) = listener.accept()?;
+
+        self.serve(stream)
+    }
+
+    pub fn serve(&mut self, stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+
+        self.connection = Some(stream.try_clone()?);
+        self.closing = false;
+
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut writer = stream;
+        let mut last_reply = String::new();
+
+        while !self.closing {
+            let Some(byte) = read_byte(&mut reader)? else {
+                break;
+            };
+
+            match byte {
+                b'$' => {
+                    let Some(packet) = read_packet(&mut reader)? else {
+                        writer.write_all(b"-")?;
+                        continue;
+                    };
+
+                    writer.write_all(b"+")?;
+
+                    trace!("gdb <- {packet}");
+                    let reply = self.handle_packet(&packet);
+                    trace!("gdb -> {reply}");
+
+                    last_reply = frame(&reply);
+                    writer.write_all(last_reply.as_bytes())?;
+                },
+                b'-' => writer.write_all(last_reply.as_bytes())?,
+                _ => {}, // Acks and Ctrl-C while stopped
+            }
+        }
+
+        self.connection = None;
+
+        Ok(())
+    }
+
+    // The reply to one packet without its framing, empty for unsupported packets
+    pub fn handle_packet(&mut self, packet: &str) -> String {
+        let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match command {
+            "?" => Some(self.stop_reply(SIGTRAP)),
+            "g" => Some(self.read_registers()),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" => self.step(args),
+            "c" => self.resume(args),
+            "Z" => self.insert_point(args),
+            "z" => self.remove_point(args),
+            "H" => Some("OK".to_string()),
+            "q" if args == "Attached" => Some("1".to_string()),
+            "q" if args.starts_with("Supported") => Some("PacketSize=1000".to_string()),
+            "D" => {
+                self.closing = true;
+                Some("OK".to_string())
+            },
+            "k" => {
+                self.closing = true;
+                Some(String::new())
+            },
+            _ => Some(String::new()),
+        };
+
+        reply.unwrap_or_else(|| "E01".to_string())
+    }
+}
+
+// Packets
+impl GdbStub {
+    fn read_registers(&self) -> String {
+        (0..REGISTER_COUNT).map(|register| hex_word(self.register(register))).collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> Option<String> {
+        let words = parse_hex_words(args)?;
+
+        if words.len() != REGISTER_COUNT {
+            return None;
+        }
+
+        for (register, word) in words.into_iter().enumerate() {
+            self.set_register(register, word);
+        }
+
+        Some("OK".to_string())
+    }
+
+    fn read_register(&self, args: &str) -> Option<String> {
+        let register = usize::from_str_radix(args, 16).ok().filter(|register| *register < REGISTER_COUNT)?;
+
+        Some(hex_word(self.register(register)))
+    }
+
+    fn write_register(&mut self, args: &str) -> Option<String> {
+        let (register, value) = args.split_once('=')?;
+        let register = usize::from_str_radix(register, 16).ok().filter(|register| *register < REGISTER_COUNT)?;
+        let [value] = parse_hex_words(value)?[..] else {
+            return None;
+        };
+
+        self.set_register(register, value);
+
+        Some("OK".to_string())
+    }
+
+    // Device registers are read without side effects
+    fn read_memory(&self, args: &str) -> Option<String> {
+        let (address, length) = parse_address_length(args)?;
+        let memory = self.machine.memory();
+        let memory = memory.lock().unwrap();
+
+        (address..address + length)
+            .map(|address| {
+                let word = memory.peek_word(self.physical_address(address)? & !0x1).ok()?;
+                let byte = if address & 0x1 != 0 { word >> 8 } else { word & 0xFF };
+
+                Some(format!("{byte:02x}"))
+            })
+            .collect()
+    }
+
+    fn write_memory(&mut self, args: &str) -> Option<String> {
+        let (range, data) = args.split_once(':')?;
+        let (address, length) = parse_address_length(range)?;
+        let bytes = parse_hex_bytes(data)?;
+
+        if bytes.len() != length {
+            return None;
+        }
+
+        let memory = self.machine.memory();
+        let mut memory = memory.lock().unwrap();
+
+        for (offset, byte) in bytes.into_iter().enumerate() {
+            memory.write_byte(self.physical_address(address + offset)?, byte).ok()?;
+        }
+
+        Some("OK".to_string())
+    }
+
+    fn step(&mut self, args: &str) -> Option<String> {
+        self.jump_if_asked(args)?;
+
+        self.machine.step(1);
+
+        Some(self.stop_reply(SIGTRAP))
+    }
+
+    fn resume(&mut self, args: &str) -> Option<String> {
+        self.jump_if_asked(args)?;
+
+        self.resume_interruptible();
+
+        Some(self.stop_reply(SIGINT))
+    }
+
+    // Software breakpoints are kept by the CPU, watchpoints by memory. Hardware breakpoints are not supported
+    fn insert_point(&mut self, args: &str) -> Option<String> {
+        let (kind, address, length) = parse_point(args)?;
+
+        match (kind, watch_kind(kind)) {
+            (0, _) => self.machine.cpu_mut().add_breakpoint(address as Word),
+            (_, Some(watch_kind)) => {
+                let physical = self.physical_address(address)?;
+
+                self.machine.memory().lock().unwrap().add_watchpoint(physical..physical + length, watch_kind);
+                self.watchpoints.push(GdbWatchpoint { address, length, kind: watch_kind, physical });
+            },
+            _ => return Some(String::new()),
+        }
+
+        Some("OK".to_string())
+    }
+
+    fn remove_point(&mut self, args: &str) -> Option<String> {
+        let (kind, address, length) = parse_point(args)?;
+
+        let removed = match (kind, watch_kind(kind)) {
+            (0, _) => self.machine.cpu_mut().remove_breakpoint(address as Word),
+            (_, Some(watch_kind)) => {
+                let index = self.watchpoints.iter()
+                    .position(|watchpoint| watchpoint.address == address && watchpoint.length == length && watchpoint.kind == watch_kind)?;
+                let watchpoint = self.watchpoints.remove(index);
+
+                self.machine.memory().lock().unwrap().remove_watchpoint(watchpoint.physical..watchpoint.physical + length, watch_kind)
+            },
+            _ => return Some(String::new()),
+        };
+
+        removed.then(|| "OK".to_string())
+    }
+}
+
+// Helpers
+impl GdbStub {
+    fn register(&self, register: usize) -> Word {
+        let state = self.machine.cpu().dump_state();
+
+        match register {
+            PS_REGISTER => state.status,
+            _ => state.registers[register],
+        }
+    }
+
+    fn set_register(&mut self, register: usize, value: Word) {
+        match register {
+            PS_REGISTER => self.machine.cpu_mut().set_status(value),
+            _ => self.machine.cpu_mut().set_register(register as Byte, value),
+        }
+    }
+
+    fn physical_address(&self, address: Address) -> Option<Address> {
+        self.machine.cpu().physical_address(address, AddressSpace::Data)
+    }
+
+    // Of the watched byte that was hit, as gdb set the watchpoint
+    fn virtual_address(&self, physical: Address) -> Address {
+        self.watchpoints.iter()
+            .find(|watchpoint| (watchpoint.physical..watchpoint.physical + watchpoint.length).contains(&physical))
+            .map_or(physical, |watchpoint| watchpoint.address + (physical - watchpoint.physical))
+    }
+
+    // s and c may give the address to resume at
+    fn jump_if_asked(&mut self, args: &str) -> Option<()> {
+        if !args.is_empty() {
+            let address = Word::from_str_radix(args, 16).ok()?;
+            self.set_register(PROGRAM_COUNTER_INDEX as usize, address);
+        }
+
+        Some(())
+    }
+
+    // Ctrl-C from the debugger stops the machine like the monitor's escape char
+    fn resume_interruptible(&mut self) {
+        let watcher = self.connection.as_ref().and_then(|connection| connection.try_clone().ok()).map(|mut connection| {
+            let running_flag = self.machine.running_flag();
+            let done = Arc::new(AtomicBool::new(false));
+            let watcher_done = done.clone();
+
+            let _ = connection.set_read_timeout(Some(INTERRUPT_POLL_PERIOD));
+
+            let thread = thread::spawn(move || {
+                let mut byte = [0];
+
+                while !watcher_done.load(Ordering::Acquire) {
+                    match connection.read(&mut byte) {
+                        Ok(1) if byte[0] == INTERRUPT_CHAR => *running_flag.lock().unwrap() = false,
+                        Ok(0) => break,
+                        _ => {},
+                    }
+                }
+
+                // The timeout is shared with the connection the packets are read from
+                let _ = connection.set_read_timeout(None);
+            });
+
+            (done, thread)
+        });
+
+        self.machine.resume();
+
+        if let Some((done, thread)) = watcher {
+            done.store(true, Ordering::Release);
+            let _ = thread.join();
+        }
+    }
+
+    // Stops without a halt reason were asked for, by a step or an interrupt
+    fn stop_reply(&self, signal: Byte) -> String {
+        match self.machine.cpu().halt_reason() {
+            Some(HaltReason::Watchpoint(hit)) => {
+                let name = match hit.kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+
+                format!("T{SIGTRAP:02x}{name}:{:x};", self.virtual_address(hit.address))
+            },
+            Some(HaltReason::DoubleFault { .. }) => format!("S{SIGBUS:02x}"),
+            Some(_) => format!("S{SIGTRAP:02x}"),
+            None => format!("S{signal:02x}"),
+        }
+    }
+}
+
+fn read_byte(reader: &mut impl Read) -> io::Result<Option<Byte>> {
+    let mut byte = [0];
+
+    match reader.read(&mut byte)? {
+        0 => Ok(None),
+        _ => Ok(Some(byte[0])),
+    }
+}
+
+// The data after $ up to #, None if the checksum is wrong
+fn read_packet(reader: &mut impl Read) -> io::Result<Option<String>> {
+    let mut data = Vec::new();
+
+    loop {
+        match read_byte(reader)? {
+            Some(b'#') => break,
+            Some(byte) => data.push(byte),
+            None => return Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    let mut checksum = [0; 2];
+    reader.read_exact(&mut checksum)?;
+
+    let expected = std::str::from_utf8(&checksum).ok().and_then(|checksum| Byte::from_str_radix(checksum, 16).ok());
+
+    if expected != Some(checksum_of(&data)) {
+        return Ok(None);
+    }
+
+    Ok(Some(String::from_utf8_lossy(&data).into_owned()))
+}
+
+pub fn frame(data: &str) -> String {
+    format!("${data}#{:02x}", checksum_of(data.as_bytes()))
+}
+
+fn checksum_of(data: &[Byte]) -> Byte {
+    data.iter().fold(0, |sum: Byte, byte| sum.wrapping_add(*byte))
+}
+
+fn hex_word(word: Word) -> String {
+    format!("{:02x}{:02x}", word & 0xFF, word >> 8)
+}
+
+fn parse_hex_bytes(text: &str) -> Option<Vec<Byte>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+
+    (0..text.len()).step_by(2).map(|index| Byte::from_str_radix(text.get(index..index + 2)?, 16).ok()).collect()
+}
+
+fn parse_hex_words(text: &str) -> Option<Vec<Word>> {
+    let bytes = parse_hex_bytes(text)?;
+
+    if !bytes.len().is_multiple_of(2) {
+        return None;
+    }
+
+    Some(bytes.chunks(2).map(|pair| pair[0] as Word | (pair[1] as Word) << 8).collect())
+}
+
+fn parse_address_length(text: &str) -> Option<(Address, usize)> {
+    let (address, length) = text.split_once(',')?;
+
+    Some((Address::from_str_radix(address, 16).ok()?, usize::from_str_radix(length, 16).ok()?))
+}
+
+// Z2, Z3 and Z4
+fn watch_kind(kind: Byte) -> Option<WatchKind> {
+    match kind {
+        2 => Some(WatchKind::Write),
+        3 => Some(WatchKind::Read),
+        4 => Some(WatchKind::Access),
+        _ => None,
+    }
+}
+
+// type,address,kind
+fn parse_point(text: &str) -> Option<(Byte, Address, usize)> {
+    let (kind, rest) = text.split_once(',')?;
+    let (address, length) = parse_address_length(rest)?;
+
+    Some((kind.parse().ok()?, address, length))
+}
@@ -0,0 +1,37 @@
+#![allow(dead_code)]
+#![allow(clippy::upper_case_acronyms)]
+
+// PDP-11 emulator as a library. A machine is a Pdp11, configured by Pdp11Config:
+//
+//     let mut machine = Pdp11::with_config(Pdp11Config { deterministic: true, ..Pdp11Config::default() });
+//     machine.load("hello.lda")?;
+//     machine.run();
+//
+// Between runs its registers and memory can be read and written, it can be single stepped,
+// and devices of your own attach to the I/O page with add_device. The modules below are
+// the building blocks the machine is made of, for tools that need more than Pdp11 gives
+
+#[macro_use] extern crate log;
+
+pub mod utils;
+pub mod mem;
+pub mod bus;
+pub mod cpu;
+pub mod tty;
+pub mod line_clock;
+pub mod assembly;
+pub mod benchmark;
+pub mod snapshot;
+pub mod scheduler;
+pub mod loader;
+pub mod assembler;
+pub mod monitor;
+pub mod gdb;
+
+pub use assembly::{Pdp11, Pdp11Config};
+pub use bus::{AccessWidth, BusDevice};
+pub use cpu::{model::CpuModel, HaltReason};
+pub use loader::{ImageFormat, LoadError, LoadedImage};
+pub use mem::BusError;
+pub use scheduler::ScheduledDevice;
+pub use utils::{Address, Byte, Word};
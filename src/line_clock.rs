@@ -0,0 +1,195 @@
+use std::{ops::Range, sync::{Arc, Mutex}, thread, time::{Duration, Instant}};
+
+use crate::{bus::{read_register, write_register, AccessWidth, BusDevice}, cpu::interruptions::InterruptionBus, scheduler::{ScheduledDevice, VIRTUAL_TICKS_PER_SECOND}, mem::{BusError, MappedMemoryWord, Memory}, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter}, utils::{Address, Byte, Number, Word}};
+
+// KW11-L line time clock
+// https://bitsavers.org/pdf/dec/unibus/KW11-L_Line_Time_Clock_Manual.pdf
+
+pub const LINE_CLOCK_STATUS_ADDRESS: Address = 0xFF66; // 177546 (oct)
+
+pub const MONITOR_STATUS_BIT: Byte = 0x07;
+pub const INT_STATUS_BIT: Byte = 0x06;
+
+pub const INT_PRIORITY: Byte = 0x06;
+
+pub const LINE_CLOCK_INT: Address = 0x0040; // 100 (oct)
+
+pub const DEFAULT_TICK_RATE: u32 = 60; // Hz
+
+struct LineClockStatus {
+    word: Word,
+    request: Option<Arc<Mutex<InterruptionBus>>>, // Where the one outstanding request waits for the CPU
+}
+
+impl LineClockStatus {
+    fn new() -> Self {
+        LineClockStatus {
+            word: 0x0000u16,
+            request: None,
+        }
+    }
+
+    // Ticks while a request is outstanding don't queue another one
+    fn tick(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>) {
+        self.word = self.word.set_n_bit(MONITOR_STATUS_BIT, true);
+
+        if self.interrupt_enabled() && self.request.is_none() {
+            interruption_bus.lock().unwrap().interrupt(LINE_CLOCK_INT, INT_PRIORITY);
+            self.request = Some(interruption_bus);
+        }
+    }
+
+    fn interrupt_enabled(&self) -> bool {
+        self.word.get_n_bit(INT_STATUS_BIT)
+    }
+
+    fn withdraw_request(&mut self) {
+        if let Some(interruption_bus) = self.request.take() {
+            interruption_bus.lock().unwrap().withdraw(LINE_CLOCK_INT, INT_PRIORITY);
+        }
+    }
+}
+
+impl MappedMemoryWord for LineClockStatus {
+    fn read_word(&self) -> Word {
+        self.word
+    }
+
+    // The monitor bit can only be cleared by software
+    fn write_word(&mut self, word: Word) {
+        let monitor = self.word.get_n_bit(MONITOR_STATUS_BIT) && word.get_n_bit(MONITOR_STATUS_BIT);
+
+        self.word = 0x0000u16
+            .set_n_bit(MONITOR_STATUS_BIT, monitor)
+            .set_n_bit(INT_STATUS_BIT, word.get_n_bit(INT_STATUS_BIT));
+    }
+
+    // INIT sets the monitor bit and disables the interrupt
+    fn reset(&mut self) {
+        self.word = 0x0000u16.set_n_bit(MONITOR_STATUS_BIT, true);
+    }
+}
+
+impl BusDevice for LineClockStatus {
+    fn address_range(&self) -> Range<Address> {
+        LINE_CLOCK_STATUS_ADDRESS..LINE_CLOCK_STATUS_ADDRESS + 2
+    }
+
+    fn read(&mut self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        Ok(read_register(self, address, width))
+    }
+
+    // Clearing the interrupt enable takes back a request the CPU hasn't taken yet
+    fn write(&mut self, address: Address, width: AccessWidth, data: Word) -> Result<(), BusError> {
+        write_register(self, address, width, data);
+
+        if !self.interrupt_enabled() {
+            self.withdraw_request();
+        }
+
+        Ok(())
+    }
+
+    fn peek(&self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        Ok(read_register(self, address, width))
+    }
+
+    // The CPU clears the interruption bus itself on INIT
+    fn reset(&mut self) {
+        MappedMemoryWord::reset(self);
+        self.request = None;
+    }
+
+    fn interrupt_acknowledge(&mut self, vector_address: Address) {
+        if vector_address == LINE_CLOCK_INT {
+            self.request = None;
+        }
+    }
+}
+
+pub struct Kw11LineClock {
+    tick_rate: u32,
+    status: Arc<Mutex<LineClockStatus>>,
+}
+
+impl Kw11LineClock {
+    pub fn new(tick_rate: u32) -> Self {
+        assert!(tick_rate > 0);
+
+        Kw11LineClock {
+            tick_rate,
+            status: Arc::new(Mutex::new(LineClockStatus::new())),
+        }
+    }
+
+    pub fn tick_rate(&self) -> u32 {
+        self.tick_rate
+    }
+
+    pub fn status_word(&self) -> Word {
+        self.status.lock().unwrap().word
+    }
+
+    pub fn reset(&mut self) {
+        BusDevice::reset(&mut *self.status.lock().unwrap());
+    }
+
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_word(self.status.lock().unwrap().word);
+    }
+
+    pub fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.status.lock().unwrap().word = reader.read_word()?;
+
+        Ok(())
+    }
+}
+
+impl Default for Kw11LineClock {
+    fn default() -> Self {
+        Self::new(DEFAULT_TICK_RATE)
+    }
+}
+
+impl Kw11LineClock {
+    // The status register has to be mapped before the CPU starts, so it is done apart from run
+    pub fn run(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>, running_flag: Arc<Mutex<bool>>) {
+        trace!("line clock start");
+
+        let period = Duration::from_secs(1) / self.tick_rate;
+        let mut next_tick = Instant::now() + period;
+
+        while *running_flag.lock().unwrap() {
+            thread::sleep(next_tick.saturating_duration_since(Instant::now()));
+            next_tick += period;
+
+            trace!("line clock tick");
+            self.tick(interruption_bus.clone());
+        }
+
+        trace!("line clock stop");
+    }
+
+    fn tick(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>) {
+        self.status.lock().unwrap().tick(interruption_bus);
+    }
+
+    pub fn map_registers(&mut self, mem: Arc<Mutex<Memory>>) {
+        mem.lock().unwrap().attach_device(self.status.clone());
+    }
+
+    pub fn unmap_registers(&mut self, mem: Arc<Mutex<Memory>>) {
+        mem.lock().unwrap().detach_device(LINE_CLOCK_STATUS_ADDRESS);
+    }
+}
+
+impl ScheduledDevice for Kw11LineClock {
+    fn service_period(&self) -> u64 {
+        VIRTUAL_TICKS_PER_SECOND / self.tick_rate as u64
+    }
+
+    fn service(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>) {
+        trace!("line clock tick");
+        self.tick(interruption_bus);
+    }
+}
@@ -0,0 +1,124 @@
+use std::{fmt, io, path::Path, str::FromStr};
+
+use crate::{mem::{BusError, Memory}, utils::{Address, Byte, Word}};
+
+use symbols::SymbolTable;
+
+pub mod absolute;
+pub mod aout;
+pub mod raw;
+pub mod sav;
+pub mod symbols;
+
+// Program images in the formats PDP-11 software comes in
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Truncated,
+    BadChecksum { offset: usize }, // Offset of the block in the image
+    BadFormat(String),
+    Bus(BusError), // The image doesn't fit into the configured memory
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(error) => write!(f, "image i/o error: {error}"),
+            LoadError::Truncated => write!(f, "truncated image"),
+            LoadError::BadChecksum { offset } => write!(f, "bad checksum in the block at offset {offset}"),
+            LoadError::BadFormat(reason) => write!(f, "bad image: {reason}"),
+            LoadError::Bus(error) => write!(f, "image doesn't fit into memory: {error}"),
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(error: io::Error) -> Self {
+        LoadError::Io(error)
+    }
+}
+
+impl From<BusError> for LoadError {
+    fn from(error: BusError) -> Self {
+        LoadError::Bus(error)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ImageFormat {
+    Absolute, // Absolute Loader paper tape
+    Aout, // Unix a.out executable
+    Sav, // RT-11 save image
+    Macro11, // MACRO-11 source, assembled on load
+    Raw, // Memory dump loaded at a given address
+}
+
+impl ImageFormat {
+    pub const ALL: [ImageFormat; 5] = [
+        ImageFormat::Absolute,
+        ImageFormat::Aout,
+        ImageFormat::Sav,
+        ImageFormat::Macro11,
+        ImageFormat::Raw,
+    ];
+
+    // .lda and .bin are paper tapes, .mac is source, .sav a save image and anything else a.out
+    pub fn from_path(path: &Path) -> Self {
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_ascii_lowercase();
+
+        match extension.as_str() {
+            "lda" | "bin" => ImageFormat::Absolute,
+            "mac" => ImageFormat::Macro11,
+            "sav" => ImageFormat::Sav,
+            _ => ImageFormat::Aout,
+        }
+    }
+}
+
+impl fmt::Display for ImageFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ImageFormat::Absolute => "lda",
+            ImageFormat::Aout => "aout",
+            ImageFormat::Sav => "sav",
+            ImageFormat::Macro11 => "mac",
+            ImageFormat::Raw => "raw",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+impl FromStr for ImageFormat {
+    type Err = String;
+
+    // The names Display gives, as well as "absolute", "a.out" and "macro11"
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "absolute" => Ok(ImageFormat::Absolute),
+            "a.out" => Ok(ImageFormat::Aout),
+            "macro11" | "macro-11" => Ok(ImageFormat::Macro11),
+            name => ImageFormat::ALL.into_iter()
+                .find(|format| format.to_string() == name)
+                .ok_or(format!("unknown image format {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct LoadedImage {
+    pub start_address: Option<Word>, // None when the image doesn't say where to start
+    pub load_only: bool, // The image asks not to be started at all
+    pub stack_pointer: Option<Word>,
+    pub data_space_base: Option<Address>, // Physical start of the D space for split I/D images
+    pub symbols: SymbolTable,
+}
+
+fn deposit(memory: &mut Memory, mut address: Address, bytes: &[Byte]) -> Result<(), LoadError> {
+    for byte in bytes {
+        address = memory.write_byte(address, *byte)?;
+    }
+
+    Ok(())
+}
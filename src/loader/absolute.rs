@@ -0,0 +1,80 @@
+use crate::{mem::{Memory, IO_PAGE_START}, utils::{make_word, Address, Byte, Word}};
+
+use super::{deposit, LoadError, LoadedImage};
+
+// Absolute Loader paper tape format (.LDA/.BIN)
+// https://bitsavers.org/pdf/dec/pdp11/papertapeSoftware/DEC-11-UABA-D_PDP-11_Absolute_Loader_Sep70.pdf
+//
+// Every block is 001, 000, byte count (2), load address (2), data, checksum,
+// where the byte count covers everything but the checksum and all bytes of a block add up to 0.
+// The block without data gives the start address, an odd one means "don't start".
+//
+// Load addresses are the 16-bit ones the loader sees with memory management off. They go to the
+// same physical addresses, so blocks reaching the I/O page at 160000 are rejected rather than
+// written to RAM behind it.
+
+pub const BLOCK_HEADER: [Byte; 2] = [0x01, 0x00];
+pub const BLOCK_HEADER_SIZE: usize = 6;
+
+pub fn load(image: &[Byte], memory: &mut Memory) -> Result<LoadedImage, LoadError> {
+    let mut offset = 0;
+
+    loop {
+        // Leader and trailer blank tape between the blocks
+        while offset < image.len() && image[offset] == 0x00 {
+            offset += 1;
+        }
+
+        if offset >= image.len() {
+            return Err(LoadError::BadFormat("no start address block".to_string()));
+        }
+
+        let (block, next_offset) = read_block(image, offset)?;
+
+        if block.data.is_empty() {
+            let load_only = block.address % 2 != 0;
+            let start_address = (!load_only).then_some(block.address);
+
+            return Ok(LoadedImage { start_address, load_only, ..LoadedImage::default() });
+        }
+
+        if block.address as Address + block.data.len() > IO_PAGE_START {
+            return Err(LoadError::BadFormat(format!("block at {:06o} reaches the I/O page", block.address)));
+        }
+
+        deposit(memory, block.address as Address, block.data)?;
+
+        offset = next_offset;
+    }
+}
+
+struct Block<'a> {
+    address: Word,
+    data: &'a [Byte],
+}
+
+fn read_block(image: &[Byte], offset: usize) -> Result<(Block<'_>, usize), LoadError> {
+    let header = image.get(offset..offset + BLOCK_HEADER_SIZE).ok_or(LoadError::Truncated)?;
+
+    if header[0..2] != BLOCK_HEADER {
+        return Err(LoadError::BadFormat(format!("no block header at offset {offset}")));
+    }
+
+    let byte_count = make_word(header[2], header[3]) as usize;
+    let address = make_word(header[4], header[5]);
+
+    if byte_count < BLOCK_HEADER_SIZE {
+        return Err(LoadError::BadFormat(format!("byte count {byte_count} at offset {offset}")));
+    }
+
+    // The checksum byte follows the counted bytes
+    let block = image.get(offset..offset + byte_count + 1).ok_or(LoadError::Truncated)?;
+
+    if block.iter().fold(0x00u8, |sum, byte| sum.wrapping_add(*byte)) != 0x00 {
+        return Err(LoadError::BadChecksum { offset });
+    }
+
+    let data = &block[BLOCK_HEADER_SIZE..byte_count];
+
+    Ok((Block { address, data }, offset + byte_count + 1))
+}
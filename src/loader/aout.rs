@@ -0,0 +1,149 @@
+use crate::{mem::{Memory, IO_PAGE_START}, utils::{make_word, Address, Byte, Word}};
+
+use super::{deposit, symbols::{Symbol, SymbolKind, SymbolTable}, LoadError, LoadedImage};
+
+// Unix V6/V7 a.out executables
+// https://www.tuhs.org/cgi-bin/utree.pl?file=V7/usr/man/man5/a.out.5
+//
+// Header of eight words: magic, text size, data size, bss size, symbol table size, entry point,
+// unused and the relocation flag, then text, data, relocation (unless stripped) and symbols
+
+pub const HEADER_SIZE: usize = 16;
+pub const SYMBOL_SIZE: usize = 12; // Name (8), type, value
+
+pub const IMPURE_MAGIC: Word = 0o407; // Data right after text
+pub const PURE_MAGIC: Word = 0o410; // Data on the next 8 KB boundary after text
+pub const SEPARATE_MAGIC: Word = 0o411; // Text in I space, data in D space, both from 0
+
+// Physical memory of the D space for 0411 images, the I space takes the first 64 KB
+pub const SEPARATE_DATA_SPACE_BASE: Address = 0x10000;
+
+const PAGE_SIZE: usize = 0x2000;
+
+const SYMBOL_TYPE_MASK: Byte = 0x1F;
+const SYMBOL_EXTERNAL_BIT: Byte = 0x20;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Header {
+    pub magic: Word,
+    pub text_size: usize,
+    pub data_size: usize,
+    pub bss_size: usize,
+    pub symbols_size: usize,
+    pub entry: Word,
+    pub relocation_stripped: bool,
+}
+
+impl Header {
+    pub fn parse(image: &[Byte]) -> Result<Self, LoadError> {
+        let header = image.get(..HEADER_SIZE).ok_or(LoadError::Truncated)?;
+        let word = |index: usize| make_word(header[index * 2], header[index * 2 + 1]);
+
+        let magic = word(0);
+
+        if ![IMPURE_MAGIC, PURE_MAGIC, SEPARATE_MAGIC].contains(&magic) {
+            return Err(LoadError::BadFormat(format!("unknown a.out magic {magic:o}")));
+        }
+
+        Ok(Header {
+            magic,
+            text_size: word(1) as usize,
+            data_size: word(2) as usize,
+            bss_size: word(3) as usize,
+            symbols_size: word(4) as usize,
+            entry: word(5),
+            relocation_stripped: word(7) != 0,
+        })
+    }
+
+    pub fn has_separate_spaces(&self) -> bool {
+        self.magic == SEPARATE_MAGIC
+    }
+
+    // Where text and data go in physical memory, the CPU sees the same addresses unmapped
+    fn segment_addresses(&self) -> (Address, Address) {
+        match self.magic {
+            PURE_MAGIC => (0, self.text_size.div_ceil(PAGE_SIZE) * PAGE_SIZE),
+            SEPARATE_MAGIC => (0, SEPARATE_DATA_SPACE_BASE),
+            _ => (0, self.text_size),
+        }
+    }
+
+    // The stack grows down from the top of the data space, which ends at the I/O page or with memory
+    fn stack_pointer(&self, data_address: Address, memory_size: usize) -> Result<Word, LoadError> {
+        let (data_space_start, data_space_size) = match self.magic {
+            SEPARATE_MAGIC => (0, memory_size.saturating_sub(data_address)),
+            _ => (data_address, memory_size),
+        };
+
+        let bss_end = data_space_start + self.data_size + self.bss_size;
+        let top = data_space_size.min(IO_PAGE_START) & !1;
+
+        if top <= bss_end {
+            return Err(LoadError::BadFormat(format!("no room for the stack above the bss ending at {bss_end:o}")));
+        }
+
+        Ok(top as Word)
+    }
+
+    fn symbols_offset(&self) -> usize {
+        let relocation_size = if self.relocation_stripped { 0 } else { self.text_size + self.data_size };
+
+        HEADER_SIZE + self.text_size + self.data_size + relocation_size
+    }
+}
+
+pub fn load(image: &[Byte], memory: &mut Memory) -> Result<LoadedImage, LoadError> {
+    let header = Header::parse(image)?;
+
+    let text_start = HEADER_SIZE;
+    let data_start = text_start + header.text_size;
+
+    let text = image.get(text_start..data_start).ok_or(LoadError::Truncated)?;
+    let data = image.get(data_start..data_start + header.data_size).ok_or(LoadError::Truncated)?;
+
+    let (text_address, data_address) = header.segment_addresses();
+    let stack_pointer = header.stack_pointer(data_address, memory.size())?;
+
+    deposit(memory, text_address, text)?;
+    deposit(memory, data_address, data)?;
+    deposit(memory, data_address + header.data_size, &vec![0x00; header.bss_size])?;
+
+    let symbols_start = header.symbols_offset();
+    let symbols = image.get(symbols_start..symbols_start + header.symbols_size).ok_or(LoadError::Truncated)?;
+
+    Ok(LoadedImage {
+        start_address: Some(header.entry),
+        stack_pointer: Some(stack_pointer),
+        data_space_base: header.has_separate_spaces().then_some(data_address),
+        symbols: parse_symbols(symbols)?,
+        ..LoadedImage::default()
+    })
+}
+
+fn parse_symbols(bytes: &[Byte]) -> Result<SymbolTable, LoadError> {
+    if !bytes.len().is_multiple_of(SYMBOL_SIZE) {
+        return Err(LoadError::BadFormat(format!("symbol table size {} is not a multiple of {SYMBOL_SIZE}", bytes.len())));
+    }
+
+    let symbols = bytes.chunks(SYMBOL_SIZE).map(|entry| {
+        let name = entry[0..8].iter().take_while(|byte| **byte != 0x00).map(|byte| *byte as char).collect();
+        let kind = match entry[8] & SYMBOL_TYPE_MASK {
+            0x01 => SymbolKind::Absolute,
+            0x02 => SymbolKind::Text,
+            0x03 => SymbolKind::Data,
+            0x04 => SymbolKind::Bss,
+            0x1F => SymbolKind::FileName,
+            _ => SymbolKind::Undefined,
+        };
+
+        Symbol {
+            name,
+            kind,
+            external: entry[8] & SYMBOL_EXTERNAL_BIT != 0,
+            value: make_word(entry[10], entry[11]),
+        }
+    });
+
+    Ok(SymbolTable::new(symbols.collect()))
+}
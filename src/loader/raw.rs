@@ -0,0 +1,11 @@
+use crate::{mem::Memory, utils::{Address, Byte}};
+
+use super::{deposit, LoadError, LoadedImage};
+
+// Plain memory dump, the bytes go to consecutive addresses from the load address.
+// Nothing in it says where to start
+pub fn load(image: &[Byte], memory: &mut Memory, address: Address) -> Result<LoadedImage, LoadError> {
+    deposit(memory, address, image)?;
+
+    Ok(LoadedImage::default())
+}
@@ -0,0 +1,83 @@
+use crate::{mem::Memory, utils::{make_word, Address, Byte, Word}};
+
+use super::{deposit, LoadError, LoadedImage};
+
+// RT-11 save images, a memory image from address 0 in 512-byte blocks
+// with the job parameters in block 0
+// https://bitsavers.org/pdf/dec/pdp11/rt11/v5.6_Aug91/AA-PD6PA-TC_RT-11_Volume_and_File_Formats_Manual_Aug91.pdf
+
+pub const BLOCK_SIZE: usize = 512;
+
+pub const START_ADDRESS_OFFSET: usize = 0x20; // 40 (oct)
+pub const STACK_POINTER_OFFSET: usize = 0x22; // 42 (oct)
+pub const JOB_STATUS_WORD_OFFSET: usize = 0x24; // 44 (oct)
+pub const HIGH_LIMIT_OFFSET: usize = 0x28; // 50 (oct)
+pub const PROTECTION_BITMAP_OFFSET: usize = 0xF0; // 360 (oct)
+pub const PROTECTION_BITMAP_SIZE: usize = 16;
+
+#[derive(Clone, Copy, Debug)]
+pub struct JobArea {
+    pub start_address: Word,
+    pub stack_pointer: Word,
+    pub job_status_word: Word,
+    pub high_limit: Word, // Highest address used by the program
+    pub protection_bitmap: [Byte; PROTECTION_BITMAP_SIZE], // One bit a block, block 0 is the high bit of the first byte
+}
+
+impl JobArea {
+    pub fn parse(image: &[Byte]) -> Result<Self, LoadError> {
+        let block = image.get(..BLOCK_SIZE).ok_or(LoadError::Truncated)?;
+        let word = |offset: usize| make_word(block[offset], block[offset + 1]);
+
+        Ok(JobArea {
+            start_address: word(START_ADDRESS_OFFSET),
+            stack_pointer: word(STACK_POINTER_OFFSET),
+            job_status_word: word(JOB_STATUS_WORD_OFFSET),
+            high_limit: word(HIGH_LIMIT_OFFSET),
+            protection_bitmap: block[PROTECTION_BITMAP_OFFSET..PROTECTION_BITMAP_OFFSET + PROTECTION_BITMAP_SIZE].try_into().unwrap(),
+        })
+    }
+
+    // Images linked without a bitmap have every block loaded
+    pub fn is_block_used(&self, block: usize) -> bool {
+        if self.protection_bitmap.iter().all(|byte| *byte == 0x00) {
+            return true;
+        }
+
+        self.protection_bitmap.get(block / 8).is_some_and(|byte| byte & (0x80 >> (block % 8)) != 0)
+    }
+
+    // Blocks past the high limit hold overlays, they are read by the program itself
+    fn block_count(&self, image_size: usize) -> usize {
+        let image_blocks = image_size.div_ceil(BLOCK_SIZE);
+
+        if self.high_limit == 0x0000 {
+            image_blocks
+        } else {
+            image_blocks.min(self.high_limit as usize / BLOCK_SIZE + 1)
+        }
+    }
+}
+
+pub fn load(image: &[Byte], memory: &mut Memory) -> Result<LoadedImage, LoadError> {
+    let job_area = JobArea::parse(image)?;
+
+    if job_area.start_address % 2 != 0 || job_area.stack_pointer % 2 != 0 {
+        return Err(LoadError::BadFormat(format!("odd start address {:o} or stack {:o}", job_area.start_address, job_area.stack_pointer)));
+    }
+
+    for block in 0..job_area.block_count(image.len()) {
+        if job_area.is_block_used(block) {
+            let start = block * BLOCK_SIZE;
+            let end = image.len().min(start + BLOCK_SIZE);
+
+            deposit(memory, start as Address, &image[start..end])?;
+        }
+    }
+
+    Ok(LoadedImage {
+        start_address: Some(job_area.start_address),
+        stack_pointer: (job_area.stack_pointer != 0x0000).then_some(job_area.stack_pointer),
+        ..LoadedImage::default()
+    })
+}
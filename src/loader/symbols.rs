@@ -0,0 +1,73 @@
+use crate::utils::Word;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SymbolKind {
+    Undefined,
+    Absolute,
+    Text,
+    Data,
+    Bss,
+    FileName,
+}
+
+impl SymbolKind {
+    // Symbols naming a place in the loaded program
+    pub fn is_located(&self) -> bool {
+        matches!(self, SymbolKind::Text | SymbolKind::Data | SymbolKind::Bss)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub kind: SymbolKind,
+    pub external: bool,
+    pub value: Word,
+}
+
+// Sorted by value, for traces and dumps with symbolic names
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.value);
+
+        SymbolTable { symbols }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Symbol> {
+        self.symbols.iter()
+    }
+
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    // The closest located symbol at or below the address and the offset from it
+    pub fn lookup(&self, address: Word) -> Option<(&Symbol, Word)> {
+        self.symbols.iter()
+            .rev()
+            .find(|symbol| symbol.kind.is_located() && symbol.value <= address)
+            .map(|symbol| (symbol, address - symbol.value))
+    }
+
+    // "_main+4", or the octal address when no symbol is below it
+    pub fn describe(&self, address: Word) -> String {
+        match self.lookup(address) {
+            Some((symbol, 0)) => symbol.name.clone(),
+            Some((symbol, offset)) => format!("{}+{offset:o}", symbol.name),
+            None => format!("{address:06o}"),
+        }
+    }
+}
@@ -1,29 +1,91 @@
+#![allow(dead_code)]
+
 extern crate pretty_env_logger;
 #[macro_use] extern crate log;
 
-mod utils;
-mod mem;
-mod cpu;
-mod tty;
-mod assembly;
+mod cli;
 
 mod test_programs;
-use assembly::Pdp11;
-use cpu::CPU;
+use std::process::ExitCode;
+
+use cli::{CliCommand, Frontend, RunOptions, USAGE, USAGE_EXIT_CODE};
+use pdp11_rust::{benchmark, cpu::CPU, gdb::GdbStub, monitor::Monitor, Pdp11};
 use test_programs::test_cpu;
 
-fn main() {
+fn main() -> ExitCode {
     pretty_env_logger::init();
-    run_cpu_tests();
 
-    run_assembled_pdp_11();
+    let command = match cli::parse(std::env::args().skip(1)) {
+        Ok(command) => command,
+        Err(error) => {
+            eprintln!("{error}\n\n{USAGE}");
+            return ExitCode::from(USAGE_EXIT_CODE);
+        }
+    };
+
+    match command {
+        CliCommand::Run(options) => run_machine(&options),
+        CliCommand::Test => {
+            run_cpu_tests();
+            println!("CPU tests passed");
+            ExitCode::SUCCESS
+        },
+        CliCommand::Benchmark => {
+            benchmark::run_benchmark(benchmark::DEFAULT_OUTER_LOOPS);
+            ExitCode::SUCCESS
+        },
+        CliCommand::Help => {
+            println!("{USAGE}");
+            ExitCode::SUCCESS
+        },
+    }
 }
 
-fn run_assembled_pdp_11() {
-    // TODO: LOAD PROGRAMM
-    let assembly = Pdp11::new();
+// Until the machine halts, the monitor quits or gdb detaches
+fn run_machine(options: &RunOptions) -> ExitCode {
+    let assembly = match options.build() {
+        Ok(assembly) => assembly,
+        Err(error) => {
+            error!("{error}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let assembly = match options.frontend {
+        Frontend::Console if assembly.is_load_only() => {
+            error!("The image asks not to be started, give --start or use --monitor");
+            return ExitCode::FAILURE;
+        },
+        Frontend::Console => assembly.run_async().join().unwrap(),
+        Frontend::Monitor => {
+            let mut monitor = Monitor::new(assembly);
+            monitor.run();
+            monitor.into_machine()
+        },
+        Frontend::Gdb(port) => match run_gdb_stub(assembly, port) {
+            Some(assembly) => assembly,
+            None => return ExitCode::FAILURE,
+        },
+    };
+
+    ExitCode::from(cli::exit_code(&assembly))
+}
+
+// Waits for one debugger on localhost, the machine is stopped until it continues
+fn run_gdb_stub(assembly: Pdp11, port: u16) -> Option<Pdp11> {
+    let mut stub = GdbStub::new(assembly);
+
+    let result = GdbStub::bind(port).and_then(|listener| {
+        info!("Waiting for gdb on localhost:{port}");
+        stub.listen(&listener)
+    });
+
+    if let Err(error) = result {
+        error!("gdb connection failed: {error}");
+        return None;
+    }
 
-    let _ = assembly.run_async().join();
+    Some(stub.into_machine())
 }
 
 fn run_cpu_tests() {
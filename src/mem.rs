@@ -1,10 +1,64 @@
-use std::sync::{Arc, Mutex};
+use std::{cell::Cell, collections::BTreeMap, ops::Range, sync::{Arc, Mutex}};
+
+use crate::{bus::{AccessWidth, MappedWordDevice, SharedBusDevice}, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter}, utils::{make_word, Address, Byte, LongWord, Number, Word}};
+
+/**
+ * 22-bit physical address space
+ */
+pub const PHYSICAL_SPACE_SIZE: usize = 1 << 22;
+
+/**
+ * 18-bit physical address space of the Unibus machines
+ */
+pub const UNIBUS_SPACE_SIZE: usize = 1 << 18;
+
+pub const IO_PAGE_SIZE: usize = 0x2000;
+pub const IO_PAGE_START: Address = 0xE000; // 160000 (oct), as seen from a 16-bit program
+pub const UNIBUS_IO_PAGE_START: Address = UNIBUS_SPACE_SIZE - IO_PAGE_SIZE; // 760000 (oct)
+pub const PHYSICAL_IO_PAGE_START: Address = PHYSICAL_SPACE_SIZE - IO_PAGE_SIZE; // 17760000 (oct)
+
+// Memory fills the physical space up to the I/O page at most
+pub const MIN_MEMORY_SIZE: usize = 0x2000; // 8 KB
+pub const MAX_MEMORY_SIZE: usize = PHYSICAL_IO_PAGE_START;
+pub const DEFAULT_MEMORY_SIZE: usize = UNIBUS_IO_PAGE_START; // 248 KB, all of an 18-bit machine
+
+// With memory management off the top 8 KB of the 16-bit space are relocated to the I/O page
+pub fn unmapped_physical_address(address: Address) -> Address {
+    if address >= IO_PAGE_START {
+        return PHYSICAL_IO_PAGE_START + (address - IO_PAGE_START);
+    }
+
+    address
+}
+
+// With 18-bit mapping the top 8 KB of the 18-bit space are relocated to the I/O page
+pub fn unibus_physical_address(address: Address) -> Address {
+    if address >= UNIBUS_IO_PAGE_START {
+        return PHYSICAL_IO_PAGE_START + (address - UNIBUS_IO_PAGE_START);
+    }
+
+    address
+}
 
-use dashmap::{mapref::one::{Ref, RefMut}, DashMap};
+// The 16-bit address devices know their registers by
+pub fn io_page_address(address: Address) -> Address {
+    IO_PAGE_START + (address - PHYSICAL_IO_PAGE_START)
+}
 
-use crate::utils::{make_word, Address, Byte, Number, Word};
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BusError {
+    OddAddress(Address),
+    NonExistentMemory(Address),
+}
 
-const MEM_SIZE: usize = 2 << 16;
+impl std::fmt::Display for BusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BusError::OddAddress(address) => write!(f, "odd address {address:06o}"),
+            BusError::NonExistentMemory(address) => write!(f, "non-existent memory {address:06o}"),
+        }
+    }
+}
 
 pub trait MappedMemoryWord {
     fn read_word(&self) -> Word;
@@ -32,12 +86,21 @@ pub trait MappedMemoryWord {
 
         self.write_word(new_word);
     }
+
+    // Bus INIT from RESET, registers it doesn't clear keep their value
+    fn reset(&mut self) {}
 }
 
 pub struct SimpleMappedMemoryWord {
     word: Word
 }
 
+impl Default for SimpleMappedMemoryWord {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl SimpleMappedMemoryWord {
     pub fn new() -> Self {
         SimpleMappedMemoryWord {
@@ -56,104 +119,326 @@ impl MappedMemoryWord for SimpleMappedMemoryWord {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access, // Reads and writes
+}
+
+impl WatchKind {
+    fn matches(&self, write: bool) -> bool {
+        match self {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        }
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Watchpoint {
+    pub range: Range<Address>, // Physical
+    pub kind: WatchKind,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct WatchpointHit {
+    pub address: Address, // First watched byte of the access
+    pub kind: WatchKind, // Of the watchpoint
+    pub write: bool,
+}
+
+impl std::fmt::Display for WatchpointHit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.write {
+            true => write!(f, "write to watched {:06o}", self.address),
+            false => write!(f, "read of watched {:06o}", self.address),
+        }
+    }
+}
+
+// What an instruction stored, for the trace
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct MemoryWrite {
+    pub address: Address, // Physical
+    pub value: Word,
+    pub byte: bool,
+}
+
+struct AttachedDevice {
+    end: Address, // Last physical address claimed
+    device: SharedBusDevice,
+}
+
 pub struct Memory {
-    bytes: [Byte; MEM_SIZE],
-    mapped: DashMap<Address,  Arc<Mutex<dyn MappedMemoryWord + Send + Sync>>>,
+    bytes: Vec<Byte>, // Everything below the size is memory, above it only the I/O page answers
+    devices: BTreeMap<Address, AttachedDevice>, // By the first physical address they claim
+    watchpoints: Vec<Watchpoint>,
+    watchpoint_hit: Cell<Option<WatchpointHit>>, // Reads record hits too, they only borrow memory
+    write_log: Option<Vec<MemoryWrite>>, // Writes are only logged once asked to
 }
 
 impl Memory {
     pub fn new() -> Arc<Mutex<Self>> {
+        Self::with_size(DEFAULT_MEMORY_SIZE)
+    }
+
+    pub fn with_size(size: usize) -> Arc<Mutex<Self>> {
+        assert!((MIN_MEMORY_SIZE..=MAX_MEMORY_SIZE).contains(&size) && size.is_multiple_of(2), "unsupported memory size {size}");
+
         Arc::new(Mutex::new(Memory {
-            bytes: [0; MEM_SIZE],
-            mapped: DashMap::new(),
+            bytes: vec![0; size],
+            devices: BTreeMap::new(),
+            watchpoints: Vec::new(),
+            watchpoint_hit: Cell::new(None),
+            write_log: None,
         }))
     }
 
-    pub fn read_byte(&self, address: Address) -> Byte {
-        Self::validate_address(address);
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn read_byte(&self, address: Address) -> Result<Byte, BusError> {
+        self.validate_address(address)?;
+        self.check_watchpoints(address..address + 1, false);
 
-        let mapped_address = address & 0xFFFE;
-        if let Some(mapped) = self.get_mapped(address) {
-            return mapped.lock().unwrap().read_byte(address != mapped_address);
+        if Self::is_io_page(address) {
+            return Ok(self.read_device(address, AccessWidth::Byte)?.low());
         }
-        
-        return self.bytes[address];
+
+        Ok(self.bytes[address])
     }
 
-    pub fn write_byte(&mut self, address: Address, data: Byte) -> Address {
-        Self::validate_address(address);
-        
+    pub fn write_byte(&mut self, address: Address, data: Byte) -> Result<Address, BusError> {
+        self.validate_address(address)?;
+        self.check_watchpoints(address..address + 1, true);
+
+        if Self::is_io_page(address) {
+            self.write_device(address, AccessWidth::Byte, data.word())?;
+            self.log_write(address, data.word(), true);
+            return Ok(Self::next_byte_address(address));
+        }
+
         self.bytes[address] = data;
+        self.log_write(address, data.word(), true);
 
-        let mapped_address = address & 0xFFFE;
-        if let Some(mapped) = self.get_mapped_mut(address) {
-            mapped.lock().unwrap().write_byte(data, address != mapped_address);
+        Ok(Self::next_byte_address(address))
+    }
+
+    pub fn read_word(&self, address: Address) -> Result<Word, BusError> {
+        self.validate_word_address(address)?;
+        self.check_watchpoints(address..address + 2, false);
+
+        if Self::is_io_page(address) {
+            return self.read_device(address, AccessWidth::Word);
         }
 
-        Self::next_byte_address(address)
+        Ok(make_word(self.bytes[address], self.bytes[address + 1]))
     }
 
-    pub fn read_word(&self, address: Address) -> Word {
-        Self::validate_word_address(address);
+    pub fn write_word(&mut self, address: Address, word: Word) -> Result<Address, BusError> {
+        self.validate_word_address(address)?;
+        self.check_watchpoints(address..address + 2, true);
 
-        if let Some(mapped) = self.get_mapped(address) {
-            return mapped.lock().unwrap().read_word();
+        if Self::is_io_page(address) {
+            self.write_device(address, AccessWidth::Word, word)?;
+            self.log_write(address, word, false);
+            return Ok(Self::next_word_address(address));
         }
-    
-        let high = self.read_byte(address + 1);
-        let low = self.read_byte(address);
-        
-        return make_word(low, high);
+
+        self.bytes[address] = word.low();
+        self.bytes[address + 1] = word.high();
+        self.log_write(address, word, false);
+
+        Ok(Self::next_word_address(address))
     }
 
-    pub fn write_word(&mut self, address: Address, word: Word) -> Address {
-        Self::validate_word_address(address);
+    // Like read_word, but device registers are read without side effects
+    pub fn peek_word(&self, address: Address) -> Result<Word, BusError> {
+        self.validate_word_address(address)?;
 
-        self.write_byte(address, word.low());
-        self.write_byte(address + 1, word.high());
+        if Self::is_io_page(address) {
+            let device = self.device_at(address).ok_or(BusError::NonExistentMemory(address))?;
 
-        if let Some(mapped) = self.get_mapped_mut(address) {
-            mapped.lock().unwrap().write_word(word);
+            return device.lock().unwrap()
+                .peek(io_page_address(address), AccessWidth::Word)
+                .map_err(|_| BusError::NonExistentMemory(address));
         }
 
-        Self::next_word_address(address)
+        Ok(make_word(self.bytes[address], self.bytes[address + 1]))
     }
 
-    pub fn map_word(&mut self, address: Address, mapped_word: Arc<Mutex<dyn MappedMemoryWord + Send + Sync>>) -> Address {
-        Self::validate_word_address(address);
+    // Devices claim a part of the I/O page, overlapping claims are a configuration error
+    pub fn attach_device(&mut self, device: SharedBusDevice) {
+        let range = device.lock().unwrap().address_range();
+        let start = unmapped_physical_address(range.start);
+        let end = unmapped_physical_address(range.end - 1);
 
-        self.mapped.insert(address, mapped_word);
+        assert!(Self::is_io_page(start) && range.start < range.end);
+        assert!((start..=end).all(|address| self.device_at(address).is_none()), "I/O page range {:06o}-{:06o} is taken", range.start, range.end - 1);
 
-        Self::next_word_address(address)
+        self.devices.insert(start, AttachedDevice { end, device });
     }
 
-    pub fn unmap_word(&mut self, address: Address) -> Address {
-        Self::validate_word_address(address);
+    // Detaches the device claiming the 16-bit I/O page address
+    pub fn detach_device(&mut self, io_address: Address) {
+        let address = unmapped_physical_address(io_address);
 
-        let value = self.read_word(address);
+        if let Some(start) = self.device_start(address) {
+            self.devices.remove(&start);
+        }
+    }
 
-        self.mapped.remove(&address);
+    // A single register given by its 16-bit I/O page address, for outside code with no device of its own
+    pub fn map_word(&mut self, io_address: Address, mapped_word: Arc<Mutex<dyn MappedMemoryWord + Send + Sync>>) -> Address {
+        assert!(io_address >= IO_PAGE_START && io_address.is_multiple_of(2));
 
-        self.write_word(address, value);
+        self.attach_device(Arc::new(Mutex::new(MappedWordDevice::new(io_address, mapped_word))));
 
-        Self::next_word_address(address)
+        Self::next_word_address(io_address)
     }
 
-    fn get_mapped_mut(&mut self, address: Address) -> Option<RefMut<Address, Arc<Mutex<dyn MappedMemoryWord + Send + Sync>>>> {
-        self.mapped.get_mut(&address)
+    pub fn unmap_word(&mut self, io_address: Address) -> Address {
+        assert!(io_address >= IO_PAGE_START && io_address.is_multiple_of(2));
+
+        self.detach_device(io_address);
+
+        Self::next_word_address(io_address)
     }
 
-    fn get_mapped(&self, address: Address) -> Option<Ref<Address, Arc<Mutex<dyn MappedMemoryWord + Send + Sync>>>> {
-        self.mapped.get(&address)
+    // Plain memory only, device registers are saved by their devices
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_long_word(self.size() as LongWord);
+        writer.write_bytes(&self.bytes);
     }
 
-    fn validate_address(address: Address) {
-        assert!(address < MEM_SIZE - 1);
+    pub fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        let size = reader.read_long_word()? as usize;
+
+        if size != self.size() {
+            return Err(SnapshotError::Mismatch(format!("snapshot of {size} bytes of memory, restoring into {}", self.size())));
+        }
+
+        self.bytes.copy_from_slice(reader.read_bytes(size)?);
+
+        Ok(())
+    }
+
+    // Peeks and device side effects aside, every read_* and write_* is checked
+    pub fn add_watchpoint(&mut self, range: Range<Address>, kind: WatchKind) {
+        self.watchpoints.push(Watchpoint { range, kind });
+    }
+
+    pub fn remove_watchpoint(&mut self, range: Range<Address>, kind: WatchKind) -> bool {
+        let count = self.watchpoints.len();
+
+        self.watchpoints.retain(|watchpoint| watchpoint.range != range || watchpoint.kind != kind);
+
+        self.watchpoints.len() != count
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    // The first hit since the last call
+    pub fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        self.watchpoint_hit.take()
+    }
+
+    fn check_watchpoints(&self, access: Range<Address>, write: bool) {
+        if self.watchpoints.is_empty() || self.watchpoint_hit.get().is_some() {
+            return;
+        }
+
+        let hit = self.watchpoints.iter()
+            .find(|watchpoint| watchpoint.kind.matches(write) && watchpoint.range.start < access.end && access.start < watchpoint.range.end)
+            .map(|watchpoint| WatchpointHit { address: access.start.max(watchpoint.range.start), kind: watchpoint.kind, write });
+
+        self.watchpoint_hit.set(hit);
+    }
+
+    // Logs every write from now until take_write_log
+    pub fn start_write_log(&mut self) {
+        self.write_log = Some(Vec::new());
+    }
+
+    pub fn take_write_log(&mut self) -> Vec<MemoryWrite> {
+        self.write_log.take().unwrap_or_default()
+    }
+
+    fn log_write(&mut self, address: Address, value: Word, byte: bool) {
+        if let Some(write_log) = &mut self.write_log {
+            write_log.push(MemoryWrite { address, value, byte });
+        }
+    }
+
+    // Asserts INIT on every attached device
+    pub fn reset_devices(&mut self) {
+        for attached in self.devices.values() {
+            attached.device.lock().unwrap().reset();
+        }
+    }
+
+    pub fn acknowledge_interrupt(&mut self, vector_address: Address) {
+        for attached in self.devices.values() {
+            attached.device.lock().unwrap().interrupt_acknowledge(vector_address);
+        }
+    }
+
+    // Nothing answers on the I/O page where no device is attached
+    fn read_device(&self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        let device = self.device_at(address).ok_or(BusError::NonExistentMemory(address))?;
+
+        device.lock().unwrap()
+            .read(io_page_address(address), width)
+            .map_err(|_| BusError::NonExistentMemory(address))
+    }
+
+    fn write_device(&self, address: Address, width: AccessWidth, data: Word) -> Result<(), BusError> {
+        let device = self.device_at(address).ok_or(BusError::NonExistentMemory(address))?;
+
+        device.lock().unwrap()
+            .write(io_page_address(address), width, data)
+            .map_err(|_| BusError::NonExistentMemory(address))
+    }
+
+    fn device_at(&self, address: Address) -> Option<&SharedBusDevice> {
+        self.device_start(address).map(|start| &self.devices[&start].device)
+    }
+
+    fn device_start(&self, address: Address) -> Option<Address> {
+        let (start, attached) = self.devices.range(..=address).next_back()?;
+
+        (address <= attached.end).then_some(*start)
+    }
+
+    fn validate_address(&self, address: Address) -> Result<(), BusError> {
+        if address >= PHYSICAL_SPACE_SIZE || (address >= self.size() && !Self::is_io_page(address)) {
+            return Err(BusError::NonExistentMemory(address));
+        }
+
+        Ok(())
+    }
+
+    fn validate_word_address(&self, address: Address) -> Result<(), BusError> {
+        self.validate_address(address)?;
+
+        if !address.is_multiple_of(2) {
+            return Err(BusError::OddAddress(address));
+        }
+
+        Ok(())
     }
 
-    fn validate_word_address(address: Address) {
-        Self::validate_address(address);
-        assert!(address % 2 == 0);
+    fn is_io_page(address: Address) -> bool {
+        (PHYSICAL_IO_PAGE_START..PHYSICAL_SPACE_SIZE).contains(&address)
     }
 
     fn next_word_address(address: Address) -> Address {
@@ -0,0 +1,611 @@
+use std::{io::Write, ops::Range, thread, time::Duration};
+
+use console::Term;
+
+use crate::{assembler::expression::{evaluate, parse_register}, assembly::Pdp11, cpu::{breakpoints::{BreakCondition, Comparison, ConditionOperand}, disassembler::Disassembler, PROGRAM_COUNTER_INDEX, REG_COUNT, STACK_POINTER_INDEX}, mem::{unmapped_physical_address, WatchKind}, tty::Keyboard, utils::{Address, Byte, Word}};
+
+// SIMH-style console. Numbers are octal, expressions and symbols of the loaded image work as in MACRO-11.
+// Addresses are physical, the PC is shown as if the MMU were off
+
+pub const PROMPT: &str = "sim> ";
+pub const ESCAPE_CHAR: Byte = 0x05; // Ctrl-E
+
+const INPUT_POLL_PERIOD: Duration = Duration::from_millis(10);
+
+const HELP: &str = "\
+examine|e [-m] location...           show registers (R0-R7, SP, PC, PSW, STATE) or memory, -m disassembles
+deposit|d location value             change a register or a memory word
+step|s [count]                       run count instructions, 1 by default
+go|g [address]                       run from the address or from the PC
+continue|c                           run from the PC, a breakpoint there is passed
+break|br [address... [if R0==5]]     add breakpoints, stopping if the register or PSW condition holds
+nobreak|nobr address...|all          remove breakpoints
+watch|w [-r|-w|-a] range...          stop on reads, writes (the default) or both of memory
+nowatch|now [-r|-w|-a] range...|all  remove watchpoints
+show|sh [cpu|devices|break|watch]    show the machine
+load|l file                          load an image and set the PC to its start
+reset                                reset the CPU and the devices, memory is kept
+quit|q                               leave the monitor
+Memory ranges are written start:end, 160000-177777 is the I/O page, Ctrl-E stops a running machine";
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Location {
+    Register(Byte),
+    Status,
+    Memory(Address),
+}
+
+pub struct Monitor {
+    machine: Pdp11,
+    disassembler: Disassembler,
+    done: bool, // Quit was typed
+}
+
+impl Monitor {
+    // The machine is booted so that go starts the loaded image
+    pub fn new(mut machine: Pdp11) -> Self {
+        machine.set_escape_char(Some(ESCAPE_CHAR));
+        machine.boot();
+
+        let disassembler = Disassembler::for_model(machine.cpu().model()).with_symbols(machine.symbols().clone());
+
+        Monitor {
+            machine,
+            disassembler,
+            done: false,
+        }
+    }
+
+    pub fn machine(&self) -> &Pdp11 {
+        &self.machine
+    }
+
+    pub fn machine_mut(&mut self) -> &mut Pdp11 {
+        &mut self.machine
+    }
+
+    pub fn into_machine(self) -> Pdp11 {
+        self.machine
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    // Commands are read from the shared keyboard until quit or the end of piped input
+    pub fn run(&mut self) {
+        let keyboard = Keyboard::shared();
+        let mut term = Term::stdout();
+
+        while !self.done {
+            print(&mut term, &keyboard, PROMPT);
+
+            let Some(line) = read_line(&mut term, &keyboard) else {
+                break;
+            };
+
+            let output = match self.execute(&line) {
+                Ok(output) => output,
+                Err(error) => format!("Error: {error}"),
+            };
+
+            if !output.is_empty() {
+                print(&mut term, &keyboard, &format!("{output}\n"));
+            }
+        }
+    }
+
+    // One command line, the output is what would be printed
+    pub fn execute(&mut self, line: &str) -> Result<String, String> {
+        let mut words = line.split_whitespace();
+
+        let Some(command) = words.next() else {
+            return Ok(String::new());
+        };
+
+        let args: Vec<&str> = words.collect();
+
+        match command.to_ascii_lowercase().as_str() {
+            "e" | "ex" | "examine" => self.examine(&args),
+            "d" | "dep" | "deposit" => self.deposit(&args),
+            "s" | "step" => self.step(&args),
+            "g" | "go" => self.go(&args),
+            "c" | "cont" | "continue" => self.resume(),
+            "br" | "break" => self.add_breakpoints(&args),
+            "nobr" | "nobreak" => self.remove_breakpoints(&args),
+            "w" | "watch" => self.add_watchpoints(&args),
+            "now" | "nowatch" => self.remove_watchpoints(&args),
+            "sh" | "show" => self.show(&args),
+            "l" | "load" => self.load(&args),
+            "reset" => self.reset(),
+            "h" | "help" | "?" => Ok(HELP.to_string()),
+            "q" | "quit" | "exit" => {
+                self.done = true;
+                Ok(String::new())
+            },
+            command => Err(format!("unknown command {command}, try help")),
+        }
+    }
+}
+
+// Commands
+impl Monitor {
+    fn examine(&self, args: &[&str]) -> Result<String, String> {
+        let (disassemble, args) = match args.first() {
+            Some(switch) if switch.eq_ignore_ascii_case("-m") => (true, &args[1..]),
+            _ => (false, args),
+        };
+
+        if args.is_empty() {
+            return Err("examine what?".to_string());
+        }
+
+        let mut lines = Vec::new();
+
+        for arg in args {
+            if arg.eq_ignore_ascii_case("state") {
+                lines.extend((0..REG_COUNT as Byte).map(|register| self.examine_location(Location::Register(register))));
+                lines.push(self.examine_location(Location::Status));
+                continue;
+            }
+
+            let (start, end) = match arg.split_once(':') {
+                Some((start, end)) => (self.location(start)?, Some(self.address(end)?)),
+                None => (self.location(arg)?, None),
+            };
+
+            match (start, end) {
+                (Location::Memory(start), end) if disassemble => {
+                    lines.extend(self.disassemble_range(start, end.unwrap_or(start)));
+                },
+                (Location::Memory(start), Some(end)) => {
+                    lines.extend((start..=end).step_by(2).map(|address| self.examine_location(Location::Memory(address))));
+                },
+                (location, None) => lines.push(self.examine_location(location)),
+                (_, Some(_)) => return Err(format!("{arg} is not a memory range")),
+            }
+        }
+
+        Ok(lines.join("\n"))
+    }
+
+    fn deposit(&mut self, args: &[&str]) -> Result<String, String> {
+        let [location, value] = args else {
+            return Err("deposit needs a location and a value".to_string());
+        };
+
+        let location = self.location(location)?;
+        let value = self.value(value)?;
+
+        match location {
+            Location::Register(register) => self.machine.cpu_mut().set_register(register, value),
+            Location::Status => self.machine.cpu_mut().set_status(value),
+            Location::Memory(address) => {
+                self.machine.memory().lock().unwrap().write_word(physical_address(address), value).map_err(|error| error.to_string())?;
+            },
+        }
+
+        Ok(String::new())
+    }
+
+    fn step(&mut self, args: &[&str]) -> Result<String, String> {
+        let count = match args {
+            [] => 1,
+            [count] => self.value(count)? as u64,
+            _ => return Err("step takes one count".to_string()),
+        };
+
+        self.machine.step(count);
+
+        Ok(self.stop_message("Step expired"))
+    }
+
+    fn go(&mut self, args: &[&str]) -> Result<String, String> {
+        match args {
+            [] => {},
+            [address] => {
+                let address = self.value(address)?;
+                self.machine.cpu_mut().set_register(PROGRAM_COUNTER_INDEX, address);
+            },
+            _ => return Err("go takes one address".to_string()),
+        }
+
+        self.resume()
+    }
+
+    fn resume(&mut self) -> Result<String, String> {
+        self.machine.resume();
+
+        Ok(self.stop_message("Simulation stopped"))
+    }
+
+    fn add_breakpoints(&mut self, args: &[&str]) -> Result<String, String> {
+        if args.is_empty() {
+            return Ok(self.breakpoint_list());
+        }
+
+        let (addresses, condition) = match args.iter().position(|arg| arg.eq_ignore_ascii_case("if")) {
+            Some(index) => (&args[..index], Some(self.condition(&args[index + 1..].join(""))?)),
+            None => (args, None),
+        };
+
+        for arg in addresses {
+            let address = self.value(arg)?;
+
+            match condition {
+                Some(condition) => self.machine.cpu_mut().add_conditional_breakpoint(address, condition),
+                None => self.machine.cpu_mut().add_breakpoint(address),
+            }
+        }
+
+        Ok(String::new())
+    }
+
+    fn remove_breakpoints(&mut self, args: &[&str]) -> Result<String, String> {
+        if args.is_empty() {
+            return Err("nobreak needs addresses or all".to_string());
+        }
+
+        for arg in args {
+            if arg.eq_ignore_ascii_case("all") {
+                self.machine.cpu_mut().clear_breakpoints();
+                continue;
+            }
+
+            let address = self.value(arg)?;
+
+            if !self.machine.cpu_mut().remove_breakpoint(address) {
+                return Err(format!("no breakpoint at {address:06o}"));
+            }
+        }
+
+        Ok(String::new())
+    }
+
+    fn add_watchpoints(&mut self, args: &[&str]) -> Result<String, String> {
+        if args.is_empty() {
+            return Ok(self.watchpoint_list());
+        }
+
+        let (kind, args) = watch_kind(args);
+
+        for arg in args {
+            let range = self.range(arg)?;
+            self.machine.memory().lock().unwrap().add_watchpoint(range, kind);
+        }
+
+        Ok(String::new())
+    }
+
+    fn remove_watchpoints(&mut self, args: &[&str]) -> Result<String, String> {
+        let (kind, args) = watch_kind(args);
+
+        if args.is_empty() {
+            return Err("nowatch needs ranges or all".to_string());
+        }
+
+        for arg in args {
+            if arg.eq_ignore_ascii_case("all") {
+                self.machine.memory().lock().unwrap().clear_watchpoints();
+                continue;
+            }
+
+            let range = self.range(arg)?;
+
+            if !self.machine.memory().lock().unwrap().remove_watchpoint(range, kind) {
+                return Err(format!("no such watchpoint on {arg}"));
+            }
+        }
+
+        Ok(String::new())
+    }
+
+    fn show(&self, args: &[&str]) -> Result<String, String> {
+        let what = args.first().map(|arg| arg.to_ascii_lowercase());
+
+        match what.as_deref() {
+            None => Ok([self.show_cpu(), self.show_devices(), self.breakpoint_list(), self.watchpoint_list()].join("\n")),
+            Some("cpu") => Ok(self.show_cpu()),
+            Some("dev" | "devices") => Ok(self.show_devices()),
+            Some("br" | "break") => Ok(self.breakpoint_list()),
+            Some("w" | "watch") => Ok(self.watchpoint_list()),
+            Some(what) => Err(format!("can't show {what}")),
+        }
+    }
+
+    fn load(&mut self, args: &[&str]) -> Result<String, String> {
+        if args.is_empty() {
+            return Err("load needs a file".to_string());
+        }
+
+        let path = args.join(" ");
+        let loaded = self.machine.load(&path).map_err(|error| format!("can't load {path}: {error}"))?;
+
+        self.machine.boot();
+        self.disassembler = Disassembler::for_model(self.machine.cpu().model()).with_symbols(self.machine.symbols().clone());
+
+        Ok(match loaded.start_address {
+            Some(start_address) => format!("Loaded {path}, start address {start_address:06o}"),
+            None if loaded.load_only => format!("Loaded {path}, not to be started"),
+            None => format!("Loaded {path}, no start address"),
+        })
+    }
+
+    fn reset(&mut self) -> Result<String, String> {
+        self.machine.reset();
+        self.machine.boot();
+
+        Ok(String::new())
+    }
+}
+
+// Helpers
+impl Monitor {
+    fn location(&self, text: &str) -> Result<Location, String> {
+        if text.eq_ignore_ascii_case("psw") {
+            return Ok(Location::Status);
+        }
+
+        if let Ok(register) = parse_register(text, &mut |name| Err(format!("undefined symbol {name}"))) {
+            return Ok(Location::Register(register));
+        }
+
+        match self.address(text)? {
+            address if address % 2 != 0 => Err(format!("{address:06o} is odd, memory is examined and deposited by words")),
+            address => Ok(Location::Memory(address)),
+        }
+    }
+
+    // Plain octal numbers reach all of physical memory, expressions only the first 64 KB
+    fn address(&self, text: &str) -> Result<Address, String> {
+        match Address::from_str_radix(text, 8) {
+            Ok(address) => Ok(address),
+            Err(_) => Ok(self.value(text)? as Address),
+        }
+    }
+
+    // start:end with the end included, or a single word
+    fn range(&self, text: &str) -> Result<Range<Address>, String> {
+        let (start, end) = match text.split_once(':') {
+            Some((start, end)) => (self.address(start)?, self.address(end)? + 1),
+            None => {
+                let address = self.address(text)?;
+                (address, address + 2)
+            },
+        };
+
+        let start_physical = physical_address(start);
+
+        Ok(start_physical..start_physical + end.saturating_sub(start))
+    }
+
+    // R0-R7, SP, PC or PSW compared with a value, as in R0==5 or PSW>=340
+    fn condition(&self, text: &str) -> Result<BreakCondition, String> {
+        let (operand, comparison, value) = Comparison::split(text).ok_or(format!("bad condition {text}"))?;
+
+        let operand = match self.location(operand)? {
+            Location::Register(register) => ConditionOperand::Register(register),
+            Location::Status => ConditionOperand::Status,
+            Location::Memory(_) => return Err(format!("{operand} is not a register")),
+        };
+
+        Ok(BreakCondition { operand, comparison, value: self.value(value)? })
+    }
+
+    fn value(&self, text: &str) -> Result<Word, String> {
+        let symbols = self.machine.symbols();
+
+        // C symbols like _main aren't MACRO-11 ones
+        if let Some(symbol) = symbols.find(text) {
+            return Ok(symbol.value);
+        }
+
+        evaluate(text, &mut |name| {
+            symbols.iter()
+                .find(|symbol| symbol.name.eq_ignore_ascii_case(name))
+                .map(|symbol| symbol.value)
+                .ok_or(format!("undefined symbol {name}"))
+        })
+    }
+
+    fn examine_location(&self, location: Location) -> String {
+        let state = self.machine.cpu().dump_state();
+
+        match location {
+            Location::Register(register) => format!("{}:\t{:06o}", register_name(register), state.registers[register as usize]),
+            Location::Status => format!("PSW:\t{:06o}", state.status),
+            Location::Memory(address) => match self.machine.memory().lock().unwrap().peek_word(physical_address(address)) {
+                Ok(word) => format!("{address:06o}:\t{word:06o}"),
+                Err(error) => format!("{address:06o}:\t{error}"),
+            },
+        }
+    }
+
+    // Instructions are decoded within the 64 KB bank of the start address
+    fn disassemble_range(&self, start: Address, end: Address) -> Vec<String> {
+        let memory = self.machine.memory();
+        let memory = memory.lock().unwrap();
+        let bank = start & !0xFFFF;
+        let mut lines = Vec::new();
+        let mut address = start;
+
+        loop {
+            let disassembly = self.disassembler.disassemble(address as Word, |word_address| {
+                memory.peek_word(physical_address(bank + word_address as Address)).ok()
+            });
+
+            if disassembly.words.is_empty() {
+                lines.push(format!("{address:06o}:\tnonexistent memory"));
+                break;
+            }
+
+            lines.push(format!("{address:06o}:\t{}", disassembly.text));
+            address += 2 * disassembly.words.len();
+
+            if address > end {
+                break;
+            }
+        }
+
+        lines
+    }
+
+    // Why the machine stopped and where
+    fn stop_message(&self, default: &str) -> String {
+        let state = self.machine.cpu().dump_state();
+        let pc = state.registers[PROGRAM_COUNTER_INDEX as usize];
+        let memory = self.machine.memory();
+        let instruction = self.disassembler.disassemble_memory(&memory.lock().unwrap(), pc).text;
+
+        match state.halt_reason {
+            Some(reason) => format!("{reason}, PC: {pc:06o} ({instruction})"),
+            None => format!("{default}, PC: {pc:06o} ({instruction})"),
+        }
+    }
+
+    fn breakpoint_list(&self) -> String {
+        let breakpoints = self.machine.cpu().breakpoints();
+
+        if breakpoints.is_empty() {
+            return "No breakpoints".to_string();
+        }
+
+        let breakpoints = breakpoints.iter().map(|breakpoint| breakpoint.to_string()).collect::<Vec<_>>().join(", ");
+
+        format!("Breakpoints: {breakpoints}")
+    }
+
+    fn watchpoint_list(&self) -> String {
+        let memory = self.machine.memory();
+        let memory = memory.lock().unwrap();
+
+        if memory.watchpoints().is_empty() {
+            return "No watchpoints".to_string();
+        }
+
+        let watchpoints = memory.watchpoints().iter()
+            .map(|watchpoint| {
+                let kind = match watchpoint.kind {
+                    WatchKind::Read => "read",
+                    WatchKind::Write => "write",
+                    WatchKind::Access => "access",
+                };
+
+                format!("{:06o}:{:06o} {kind}", watchpoint.range.start, watchpoint.range.end - 1)
+            })
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        format!("Watchpoints: {watchpoints}")
+    }
+
+    fn show_cpu(&self) -> String {
+        let cpu = self.machine.cpu();
+        let state = cpu.dump_state();
+        let memory_size = self.machine.memory().lock().unwrap().size();
+
+        let run_state = match (state.running, state.waiting) {
+            (true, _) => "running",
+            (false, true) => "waiting",
+            (false, false) => "stopped",
+        };
+
+        format!("CPU: PDP-{}, {}K memory, {run_state}, PC: {:06o}, PSW: {:06o}",
+            cpu.model(), memory_size / 1024, state.registers[PROGRAM_COUNTER_INDEX as usize], state.status)
+    }
+
+    fn show_devices(&self) -> String {
+        let [rcsr, rbuf, xcsr, xbuf] = self.machine.tty_registers();
+        let mut lines = Vec::new();
+
+        if self.machine.has_console() {
+            lines.push(format!("TTY (DL11): RCSR={rcsr:06o} RBUF={rbuf:06o} XCSR={xcsr:06o} XBUF={xbuf:06o}"));
+        }
+
+        if self.machine.has_line_clock() {
+            lines.push(format!("CLK (KW11-L): LKS={:06o}, {} Hz", self.machine.line_clock_status(), self.machine.line_clock_rate()));
+        }
+
+        match lines.is_empty() {
+            true => "No devices".to_string(),
+            false => lines.join("\n"),
+        }
+    }
+}
+
+fn register_name(register: Byte) -> String {
+    match register {
+        STACK_POINTER_INDEX => "SP".to_string(),
+        PROGRAM_COUNTER_INDEX => "PC".to_string(),
+        _ => format!("R{register}"),
+    }
+}
+
+// -r, -w or -a before the ranges, writes by default
+// The first 64 KB are addressed as a program sees them with the MMU off, so the top 8 KB of it are the I/O page as in SIMH
+fn physical_address(address: Address) -> Address {
+    match address {
+        0x0000..=0xFFFF => unmapped_physical_address(address),
+        _ => address,
+    }
+}
+
+fn watch_kind<'a>(args: &'a [&'a str]) -> (WatchKind, &'a [&'a str]) {
+    let kind = match args.first().map(|arg| arg.to_ascii_lowercase()).as_deref() {
+        Some("-r") => WatchKind::Read,
+        Some("-w") => WatchKind::Write,
+        Some("-a") => WatchKind::Access,
+        _ => return (WatchKind::Write, args),
+    };
+
+    (kind, &args[1..])
+}
+
+// The keyboard thread keeps a terminal in raw mode, so new lines need a carriage return
+fn print(term: &mut Term, keyboard: &Keyboard, text: &str) {
+    let text = match keyboard.is_interactive() {
+        true => text.replace('\n', "\r\n"),
+        false => text.to_string(),
+    };
+
+    let _ = term.write_all(text.as_bytes());
+    let _ = term.flush();
+}
+
+// Piped input is not echoed
+fn echo(term: &mut Term, keyboard: &Keyboard, text: &str) {
+    if keyboard.is_interactive() {
+        print(term, keyboard, text);
+    }
+}
+
+// Echoes what is typed and handles backspace, None once the input is over
+fn read_line(term: &mut Term, keyboard: &Keyboard) -> Option<String> {
+    let mut line = String::new();
+
+    loop {
+        let closed = keyboard.is_closed();
+
+        let Some(char) = keyboard.pop() else {
+            if closed {
+                return (!line.is_empty()).then_some(line);
+            }
+
+            thread::sleep(INPUT_POLL_PERIOD);
+            continue;
+        };
+
+        match char {
+            b'\r' | b'\n' => {
+                echo(term, keyboard, "\n");
+                return Some(line);
+            },
+            0x08 | 0x7F if line.pop().is_some() => echo(term, keyboard, "\x08 \x08"),
+            char if char.is_ascii_graphic() || char == b' ' => {
+                line.push(char as char);
+                echo(term, keyboard, &(char as char).to_string());
+            },
+            _ => {},
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::{sync::{Arc, Mutex}, time::Duration};
+
+use crate::{cpu::{interruptions::InterruptionBus, CPU}, mem::Memory};
+
+// Deterministic mode: the CPU and the devices share one thread and a virtual clock
+// counted in CPU ticks, so the same program and input always give the same trace
+
+pub const VIRTUAL_TICKS_PER_SECOND: u64 = 1_000_000; // About an 11/70
+
+pub fn virtual_ticks(duration: Duration) -> u64 {
+    (duration.as_micros() as u64 * VIRTUAL_TICKS_PER_SECOND / 1_000_000).max(1)
+}
+
+// How long the ticks last on the wall clock, for devices serviced by a thread of their own
+pub fn real_duration(ticks: u64) -> Duration {
+    Duration::from_micros(ticks.saturating_mul(1_000_000) / VIRTUAL_TICKS_PER_SECOND)
+}
+
+pub trait ScheduledDevice {
+    // Virtual time between two services, in CPU ticks
+    fn service_period(&self) -> u64;
+
+    fn service(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>);
+}
+
+struct ScheduledEntry {
+    next_due: u64,
+    device: Arc<Mutex<dyn ScheduledDevice + Send>>,
+}
+
+pub struct Scheduler {
+    now: u64,
+    next_due: u64, // Earliest due time among all devices
+    entries: Vec<ScheduledEntry>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            now: 0,
+            next_due: u64::MAX,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn add_device(&mut self, device: Arc<Mutex<dyn ScheduledDevice + Send>>) {
+        let next_due = self.now + device.lock().unwrap().service_period();
+
+        self.next_due = self.next_due.min(next_due);
+        self.entries.push(ScheduledEntry { next_due, device });
+    }
+
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    pub fn run(&mut self, cpu: &mut CPU, mem: Arc<Mutex<Memory>>) {
+        cpu.attach(mem.clone());
+
+        while cpu.is_running() {
+            cpu.tick(mem.clone());
+            self.advance(cpu.interruption_bus());
+        }
+
+        cpu.detach(mem);
+    }
+
+    // At most count instructions, the CPU is stopped afterwards whatever it was doing
+    pub fn step(&mut self, cpu: &mut CPU, mem: Arc<Mutex<Memory>>, count: u64) {
+        cpu.attach(mem.clone());
+
+        for _ in 0..count {
+            if !cpu.is_running() {
+                break;
+            }
+
+            cpu.tick(mem.clone());
+            self.advance(cpu.interruption_bus());
+        }
+
+        cpu.stop();
+        cpu.detach(mem);
+    }
+
+    // Devices due at the same time are serviced in the order they were added
+    fn advance(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>) {
+        self.now += 1;
+
+        if self.now < self.next_due {
+            return;
+        }
+
+        self.next_due = u64::MAX;
+
+        for entry in self.entries.iter_mut() {
+            if entry.next_due <= self.now {
+                let mut device = entry.device.lock().unwrap();
+
+                device.service(interruption_bus.clone());
+                entry.next_due = self.now + device.service_period();
+            }
+
+            self.next_due = self.next_due.min(entry.next_due);
+        }
+    }
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,145 @@
+use std::{fmt, io};
+
+use crate::utils::{Byte, LongWord, Word};
+
+// Machine snapshot file layout: magic, version, then every component in a fixed order,
+// all numbers little-endian
+
+pub const SNAPSHOT_MAGIC: &[Byte; 8] = b"PDP11SNP";
+pub const SNAPSHOT_VERSION: Word = 2;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(Word),
+    Truncated,
+    Mismatch(String), // The snapshot was taken on a differently configured machine
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::Io(error) => write!(f, "snapshot i/o error: {error}"),
+            SnapshotError::BadMagic => write!(f, "not a snapshot file"),
+            SnapshotError::UnsupportedVersion(version) => write!(f, "unsupported snapshot version {version}"),
+            SnapshotError::Truncated => write!(f, "truncated snapshot"),
+            SnapshotError::Mismatch(reason) => write!(f, "snapshot mismatch: {reason}"),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(error: io::Error) -> Self {
+        SnapshotError::Io(error)
+    }
+}
+
+pub struct SnapshotWriter {
+    bytes: Vec<Byte>,
+}
+
+impl SnapshotWriter {
+    pub fn new() -> Self {
+        let mut writer = SnapshotWriter { bytes: Vec::new() };
+
+        writer.write_bytes(SNAPSHOT_MAGIC);
+        writer.write_word(SNAPSHOT_VERSION);
+
+        writer
+    }
+
+    pub fn into_bytes(self) -> Vec<Byte> {
+        self.bytes
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_byte(value as Byte);
+    }
+
+    pub fn write_byte(&mut self, byte: Byte) {
+        self.bytes.push(byte);
+    }
+
+    pub fn write_word(&mut self, word: Word) {
+        self.write_bytes(&word.to_le_bytes());
+    }
+
+    pub fn write_long_word(&mut self, long_word: LongWord) {
+        self.write_bytes(&long_word.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[Byte]) {
+        self.bytes.extend_from_slice(bytes);
+    }
+}
+
+impl Default for SnapshotWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct SnapshotReader<'a> {
+    bytes: &'a [Byte],
+    position: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    pub fn new(bytes: &'a [Byte]) -> Result<Self, SnapshotError> {
+        let mut reader = SnapshotReader { bytes, position: 0 };
+
+        if reader.read_bytes(SNAPSHOT_MAGIC.len()).map_err(|_| SnapshotError::BadMagic)? != SNAPSHOT_MAGIC {
+            return Err(SnapshotError::BadMagic);
+        }
+
+        let version = reader.read_word()?;
+
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+
+        Ok(reader)
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SnapshotError> {
+        Ok(self.read_byte()? != 0)
+    }
+
+    pub fn read_byte(&mut self) -> Result<Byte, SnapshotError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_word(&mut self) -> Result<Word, SnapshotError> {
+        Ok(Word::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_long_word(&mut self) -> Result<LongWord, SnapshotError> {
+        Ok(LongWord::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SnapshotError> {
+        Ok(u64::from_le_bytes(self.read_array()?))
+    }
+
+    pub fn read_bytes(&mut self, count: usize) -> Result<&'a [Byte], SnapshotError> {
+        let end = self.position + count;
+
+        if end > self.bytes.len() {
+            return Err(SnapshotError::Truncated);
+        }
+
+        let bytes = &self.bytes[self.position..end];
+        self.position = end;
+
+        Ok(bytes)
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[Byte; N], SnapshotError> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+}
@@ -1,11 +1,46 @@
-use std::sync::{Arc, Mutex};
+use std::{fs, io::{Read, Write}, net::TcpStream, sync::{Arc, Mutex}, thread};
 
-use crate::{cpu::{debug::CPUStateDump, CPU, FIRST_COMMAND, REG_COUNT}, mem::Memory, tty::TRANSMITTER_BUFFER_ADDRESS, utils::{make_word, Byte, Word}};
+use pdp11_rust::{assembler::Assembler, bus::{AccessWidth, BusDevice}, cpu::{interruptions::InterruptionBus, PROGRAM_COUNTER_INDEX}, mem::BusError, scheduler::ScheduledDevice, utils::Address, cpu::{breakpoints::{BreakCondition, Comparison, ConditionOperand}, profiler::Profiler, trace::{rotated_path, TraceConfig, Tracer, TRACE_HEADER}}, assembly::{Pdp11, Pdp11Config}, gdb::{self, GdbStub}, monitor::Monitor, cpu::{disassembler::Disassembler, debug::CPUStateDump, model::CpuModel, mmu::{KERNEL_PAGE_REGISTERS_ADDRESS, MMU_TRAP_VECTOR, SR0_ADDRESS, SR3_ADDRESS}, ProcessorMode, HaltReason, BUS_ERROR_TRAP_VECTOR, RESERVED_INSTRUCTION_TRAP_VECTOR, CPU, FIRST_COMMAND, REG_COUNT}, line_clock::{Kw11LineClock, DEFAULT_TICK_RATE, INT_PRIORITY, LINE_CLOCK_INT, LINE_CLOCK_STATUS_ADDRESS, MONITOR_STATUS_BIT}, mem::{unmapped_physical_address, Memory, WatchKind, WatchpointHit}, loader::{absolute, aout, sav, symbols::SymbolKind, LoadError}, scheduler::Scheduler, snapshot::{SnapshotReader, SnapshotWriter}, tty::{Dl11Tty, TRANSMITTER_BUFFER_ADDRESS, TRANSMITTER_STATUS_ADDRESS}, utils::{make_word, Byte, Number, Word}};
+
+use crate::cli::{self, CliCommand};
 
 
 pub fn test_cpu(cpu: &mut CPU) {
     test_mov_add(cpu, 3, 3);
     test_mov_sub(cpu, 3, 3);
+    test_mmu(cpu);
+    test_processor_modes(cpu);
+    test_fpu(cpu);
+    test_bus_error(cpu);
+    test_double_fault(cpu);
+    test_reserved_instruction(cpu);
+    test_mov_pc(cpu);
+
+    test_fis(&mut CPU::for_model(CpuModel::Pdp1140));
+    test_pdp_11_20_quirks(&mut CPU::for_model(CpuModel::Pdp1120));
+    test_processor_status_instructions(&mut CPU::for_model(CpuModel::Pdp1103));
+    test_line_clock(&mut CPU::default());
+    test_line_clock_request();
+    test_reset(&mut CPU::default());
+    test_snapshot(&mut CPU::default());
+    test_deterministic_scheduler(&mut CPU::default());
+    test_bus_device(&mut CPU::default());
+    test_22_bit_addressing(&mut CPU::for_model(CpuModel::Pdp1183));
+    test_absolute_loader(&mut CPU::default());
+    test_aout_loader(&mut CPU::for_model(CpuModel::Pdp1170));
+    test_aout_stack();
+    test_sav_loader(&mut CPU::default());
+    test_assembler(&mut CPU::default());
+    test_disassembler();
+    test_breakpoints(&mut CPU::default());
+    test_trace(&mut CPU::default());
+    test_profiler(&mut CPU::default());
+    test_monitor();
+    test_gdb_stub();
+    test_command_line();
+    test_embedding_api();
+    test_console_output(true);
+    test_console_output(false);
 }
 
 pub fn test_mov_add(cpu: &mut CPU, a: Word, b: Word) {
@@ -30,12 +65,344 @@ pub fn test_mov_sub(cpu: &mut CPU, a: Word, b: Word) {
     );
 }
 
+pub fn test_mmu(cpu: &mut CPU) {
+    run_test("MMU relocation and abort", cpu,
+        |cpu| {
+            run_and_dump(cpu, make_mmu_test())
+        },
+        |dump| {
+            assert!(dump.registers[0] == 0x1234);
+            assert!(dump.registers[2] == 0x8007); // Non-resident abort in page 3 with relocation on
+            assert!(dump.registers[3] == 0x7F46); // W (bit 6) set by the write through page 1
+            assert!(dump.registers[4] == 0x7F06); // Page 2 was only read
+        }
+    );
+}
+
+pub fn test_processor_modes(cpu: &mut CPU) {
+    run_test("User mode HALT trap and MFPI", cpu,
+        |cpu| {
+            run_and_dump(cpu, make_processor_modes_test())
+        },
+        |dump| {
+            assert!(dump.registers[3] == 0x0300); // User SP seen from kernel mode
+            assert!(dump.registers[4] == 0x01FC); // Kernel SP after the trap
+            assert!(dump.stack_pointers[ProcessorMode::User as usize] == 0x0300);
+        }
+    );
+}
+
+pub fn test_fpu(cpu: &mut CPU) {
+    run_test("FP11 arithmetic", cpu,
+        |cpu| {
+            run_and_dump(cpu, make_fpu_test())
+        },
+        |dump| {
+            assert!(dump.registers[1] == 9);
+            assert!(dump.registers[2] == 0x4066); // 0.9 in DEC F format
+            assert!(dump.registers[3] == 0x6666);
+        }
+    );
+}
+
+pub fn test_fis(cpu: &mut CPU) {
+    run_test("FIS on the 11/40", cpu,
+        |cpu| {
+            run_and_dump(cpu, make_fis_test())
+        },
+        |dump| {
+            assert!(dump.registers[4] == 0x0504);
+            assert!(dump.registers[5] == 0x4140); // 3.0 in DEC F format
+            assert!(dump.registers[3] & 0x000F == 0x000B); // N, V and C but not Z on divide by zero
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(FIRST_COMMAND + 0x28)));
+        }
+    );
+}
+
+pub fn test_pdp_11_20_quirks(cpu: &mut CPU) {
+    run_test("11/20 missing SXT, JMP (R)+ and MOV PC", cpu,
+        |cpu| {
+            run_and_dump(cpu, make_pdp_11_20_test())
+        },
+        |dump| {
+            assert!(dump.registers[0] == 1);
+            assert!(dump.registers[3] == FIRST_COMMAND as Word + 0x16); // PC+2
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(FIRST_COMMAND + 0x1C)));
+        }
+    );
+}
+
+pub fn test_mov_pc(cpu: &mut CPU) {
+    run_test("MOV PC,@#A stores PC+4", cpu,
+        |cpu| {
+            run_and_dump(cpu, make_mov_pc_test())
+        },
+        |dump| {
+            assert!(dump.registers[3] == FIRST_COMMAND as Word + 0x04);
+        }
+    );
+}
+
+pub fn test_processor_status_instructions(cpu: &mut CPU) {
+    run_test("11/03 MTPS and MFPS", cpu,
+        |cpu| {
+            run_and_dump(cpu, make_processor_status_test())
+        },
+        |dump| {
+            assert!(dump.registers[1] == 0xFFEF); // T bit not set, sign extended
+            assert!(dump.status & 0x00FF == 0x00E9);
+        }
+    );
+}
+
+pub fn test_bus_error(cpu: &mut CPU) {
+    run_test("Bus error trap", cpu,
+        |cpu| {
+            run_and_dump(cpu, make_bus_error_test())
+        },
+        |dump| {
+            assert!(dump.registers[0] == 2); // Odd address and non-existent I/O page register
+            assert!(dump.registers[1] == 0x5555);
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(FIRST_COMMAND + 0x14)));
+        }
+    );
+}
+
+pub fn test_double_fault(cpu: &mut CPU) {
+    run_test("Double fault halt", cpu,
+        |cpu| {
+            run_and_dump(cpu, make_double_fault_test())
+        },
+        |dump| {
+            assert!(!dump.running);
+            assert!(dump.halt_reason == Some(HaltReason::DoubleFault { vector: BUS_ERROR_TRAP_VECTOR, fault_vector: BUS_ERROR_TRAP_VECTOR }));
+        }
+    );
+}
+
+pub fn test_reserved_instruction(cpu: &mut CPU) {
+    run_test("Reserved instruction trap", cpu,
+        |cpu| {
+            run_and_dump(cpu, make_reserved_instruction_test())
+        },
+        |dump| {
+            assert!(dump.registers[0] == 3); // Reserved opcode, JMP R1 and DIV into an odd register
+        }
+    );
+}
+
+pub fn test_line_clock(cpu: &mut CPU) {
+    run_test("KW11-L line clock interrupt", cpu,
+        |cpu| {
+            let memory = make_line_clock_test();
+
+            let mut line_clock = Kw11LineClock::new(DEFAULT_TICK_RATE);
+            line_clock.map_registers(memory.clone());
+
+            *cpu.running_flag().lock().unwrap() = true;
+
+            let interruption_bus = cpu.interruption_bus();
+            let running_flag = cpu.running_flag();
+
+            let line_clock_thread = thread::spawn(move || {
+                line_clock.run(interruption_bus, running_flag);
+                line_clock
+            });
+
+            let dump = run_and_dump(cpu, memory.clone());
+
+            line_clock_thread.join().unwrap().unmap_registers(memory);
+
+            dump
+        },
+        |dump| {
+            assert!(dump.registers[0].get_n_bit(MONITOR_STATUS_BIT));
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(FIRST_COMMAND + 0x16)));
+        }
+    );
+}
+
+// Ticks the CPU doesn't get to, at a high priority, leave one request, which clearing IE takes back
+pub fn test_line_clock_request() {
+    trace!("Test: KW11-L keeps one interrupt request");
+
+    let memory = Memory::new();
+    let interruption_bus = Arc::new(Mutex::new(InterruptionBus::new()));
+    let status_address = unmapped_physical_address(LINE_CLOCK_STATUS_ADDRESS);
+
+    let mut line_clock = Kw11LineClock::new(DEFAULT_TICK_RATE);
+    line_clock.map_registers(memory.clone());
+
+    memory.lock().unwrap().write_word(status_address, 0x0040).unwrap(); // IE
+
+    for _ in 0..3 {
+        line_clock.service(interruption_bus.clone());
+    }
+
+    assert!(interruption_bus.lock().unwrap().pending() == [(INT_PRIORITY, LINE_CLOCK_INT)]);
+
+    // Taken by the CPU, the next tick asks again
+    assert!(interruption_bus.lock().unwrap().next_interruption_if_any(0) == Some(LINE_CLOCK_INT));
+    memory.lock().unwrap().acknowledge_interrupt(LINE_CLOCK_INT);
+
+    line_clock.service(interruption_bus.clone());
+    line_clock.service(interruption_bus.clone());
+    assert!(interruption_bus.lock().unwrap().pending() == [(INT_PRIORITY, LINE_CLOCK_INT)]);
+
+    memory.lock().unwrap().write_word(status_address, 0x0000).unwrap();
+    assert!(interruption_bus.lock().unwrap().pending().is_empty());
+
+    line_clock.service(interruption_bus.clone());
+    assert!(interruption_bus.lock().unwrap().pending().is_empty());
+
+    line_clock.unmap_registers(memory);
+
+    trace!("Passed!");
+}
+
+pub fn test_reset(cpu: &mut CPU) {
+    run_test("RESET clears devices and pending interrupts", cpu,
+        |cpu| {
+            let memory = make_reset_test();
+
+            let mut line_clock = Kw11LineClock::new(DEFAULT_TICK_RATE);
+            line_clock.map_registers(memory.clone());
+
+            // Held off by the priority until RESET drops it
+            cpu.interruption_bus().lock().unwrap().interrupt(LINE_CLOCK_INT, INT_PRIORITY);
+
+            let dump = run_and_dump(cpu, memory.clone());
+
+            line_clock.unmap_registers(memory);
+
+            dump
+        },
+        |dump| {
+            assert!(dump.registers[0] == 0x0080); // Monitor bit set, interrupt disabled
+            assert!(dump.registers[1] == 0);
+        }
+    );
+}
+
+pub fn test_snapshot(cpu: &mut CPU) {
+    run_test("Snapshot save and restore", cpu,
+        |cpu| {
+            let memory = make_snapshot_test();
+
+            cpu.run(memory.clone());
+
+            // Held off by the priority until after the restore
+            cpu.interruption_bus().lock().unwrap().interrupt(LINE_CLOCK_INT, INT_PRIORITY);
+
+            let mut writer = SnapshotWriter::new();
+            cpu.save_state(&mut writer);
+            memory.lock().unwrap().save_state(&mut writer);
+            let bytes = writer.into_bytes();
+
+            let mut restored_cpu = CPU::default();
+            let restored_memory = Memory::new();
+
+            let mut reader = SnapshotReader::new(&bytes).unwrap();
+            restored_cpu.restore_state(&mut reader).unwrap();
+            restored_memory.lock().unwrap().restore_state(&mut reader).unwrap();
+
+            assert!(CPU::for_model(CpuModel::Pdp1120).restore_state(&mut SnapshotReader::new(&bytes).unwrap()).is_err());
+
+            restored_cpu.resume(restored_memory);
+            restored_cpu.dump_state()
+        },
+        |dump| {
+            assert!(dump.registers[0] == 6);
+            assert!(dump.registers[2] == 0x1234);
+            assert!(dump.registers[3] == 1); // Interrupt pending at the snapshot
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(FIRST_COMMAND + 0x22)));
+        }
+    );
+}
+
+pub fn test_deterministic_scheduler(cpu: &mut CPU) {
+    run_test("Deterministic line clock interrupts", cpu,
+        |cpu| {
+            let first = run_scheduled_line_clock_test(cpu);
+            let second = run_scheduled_line_clock_test(&mut CPU::default());
+
+            assert!(first.registers == second.registers);
+
+            first
+        },
+        |dump| {
+            assert!(dump.registers[1] == 3); // Ticks at 16666, 33332 and 49998
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(FIRST_COMMAND + 0x1A)));
+        }
+    );
+}
+
+fn run_scheduled_line_clock_test(cpu: &mut CPU) -> CPUStateDump {
+    let memory = make_scheduler_test();
+
+    let line_clock = Arc::new(Mutex::new(Kw11LineClock::new(DEFAULT_TICK_RATE)));
+    line_clock.lock().unwrap().map_registers(memory.clone());
+
+    let mut scheduler = Scheduler::new();
+    scheduler.add_device(line_clock.clone());
+
+    cpu.boot_registers();
+    scheduler.run(cpu, memory.clone());
+
+    line_clock.lock().unwrap().unmap_registers(memory);
+
+    cpu.dump_state()
+}
+
+pub fn test_bus_device(cpu: &mut CPU) {
+    run_test("DL11 byte and word access on the bus", cpu,
+        |cpu| {
+            let memory = make_bus_device_test();
+
+            let mut dl11tty = Dl11Tty::new();
+            dl11tty.attach(memory.clone());
+
+            let dump = run_and_dump(cpu, memory.clone());
+
+            let physical_buffer_address = unmapped_physical_address(TRANSMITTER_BUFFER_ADDRESS);
+            assert!(memory.lock().unwrap().peek_word(physical_buffer_address) == Ok(b'A' as Word));
+
+            dl11tty.detach(memory.clone());
+            assert!(memory.lock().unwrap().peek_word(physical_buffer_address).is_err());
+
+            dump
+        },
+        |dump| {
+            assert!(dump.registers[0] == 0x0000); // Transmitter busy until the character is printed
+            assert!(dump.registers[1] == 0x0000);
+        }
+    );
+}
+
+pub fn test_22_bit_addressing(cpu: &mut CPU) {
+    run_test("22-bit mapping on the 11/83 with 1 MB of memory", cpu,
+        |cpu| {
+            let memory = make_22_bit_addressing_test();
+
+            let dump = run_and_dump(cpu, memory.clone());
+
+            assert!(memory.lock().unwrap().peek_word(0x80000) == Ok(0x1234));
+
+            dump
+        },
+        |dump| {
+            assert!(dump.registers[0] == 1); // Non-existent memory above 1 MB
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(FIRST_COMMAND + 0x46)));
+        }
+    );
+}
+
 fn run_and_dump(cpu: &mut CPU, memory: Arc<Mutex<Memory>>) -> CPUStateDump {
     cpu.run(memory);
     cpu.dump_state()
 }
 
-fn run_test(name: &'static str, cpu: &mut CPU, run: impl Fn(&mut CPU) -> CPUStateDump, validate: impl Fn(&CPUStateDump) -> ()) {
+fn run_test(name: &'static str, cpu: &mut CPU, run: impl Fn(&mut CPU) -> CPUStateDump, validate: impl Fn(&CPUStateDump)) {
     trace!("Test: {name}");
 
     let dump = run(cpu);
@@ -63,15 +430,1109 @@ fn make_two_operands_test(opcode: Word, src: Word, dst: Word) -> Arc<Mutex<Memor
     let src_reg: Byte = 1;
     let dst_reg: Byte = 0;
 
-    address = memory.write_word(address, mov_const(dst_reg));
-    address = memory.write_word(address, dst);
-    address = memory.write_word(address, mov_const(src_reg));
-    address = memory.write_word(address, src);
-    address = memory.write_word(address, make_two_cmd(opcode, src_reg, dst_reg));
+    address = memory.write_word(address, mov_const(dst_reg)).unwrap();
+    address = memory.write_word(address, dst).unwrap();
+    address = memory.write_word(address, mov_const(src_reg)).unwrap();
+    address = memory.write_word(address, src).unwrap();
+    memory.write_word(address, make_two_cmd(opcode, src_reg, dst_reg)).unwrap();
 
     mem
 }
 
+fn make_mmu_test() -> Arc<Mutex<Memory>> {
+    let kernel_pdr = KERNEL_PAGE_REGISTERS_ADDRESS as Word;
+    let kernel_par = kernel_pdr + 0x20;
+    let full_read_write_page: Word = 0x7F06;
+
+    let handler = FIRST_COMMAND as Word + 0x4C;
+
+    make_program(&[
+        // Pages 1 and 2 both point to physical 0x10000, page 7 to the I/O page
+        0x15DF, full_read_write_page, kernel_pdr,
+        0x15DF, full_read_write_page, kernel_pdr + 2,
+        0x15DF, 0x0400, kernel_par + 2,
+        0x15DF, full_read_write_page, kernel_pdr + 4,
+        0x15DF, 0x0400, kernel_par + 4,
+        0x15DF, full_read_write_page, kernel_pdr + 14,
+        0x15DF, 0x0F80, kernel_par + 14,
+        0x15DF, handler, MMU_TRAP_VECTOR as Word,
+        0x15DF, 0x00E0, MMU_TRAP_VECTOR as Word + 2,
+        0x15DF, 0x0001, SR0_ADDRESS as Word,
+        0x15DF, 0x1234, 0x2000, // MOV #1234, @#20000
+        0x17C0, 0x4000,         // MOV @#40000, R0
+        0x17C1, 0x6000,         // MOV @#60000, R1
+        0x0000,
+        // handler
+        0x17C2, SR0_ADDRESS as Word, // MOV @#SR0, R2
+        0x0A1F, SR0_ADDRESS as Word, // CLR @#SR0
+        0x17C3, kernel_pdr + 2, // MOV @#KIPDR1, R3
+        0x17C4, kernel_pdr + 4, // MOV @#KIPDR2, R4
+        0x0000,
+    ])
+}
+
+fn make_processor_modes_test() -> Arc<Mutex<Memory>> {
+    let user = FIRST_COMMAND as Word + 0x10;
+    let handler = user + 0x06;
+
+    make_program(&[
+        0x15E6, 0xF000,         // MOV #170000, -(SP)
+        0x15E6, user,           // MOV #user, -(SP)
+        0x15DF, handler, 0x0004, // MOV #handler, @#4
+        0x0002,                 // RTI
+        // user
+        0x15C6, 0x0300,         // MOV #1400, SP
+        0x0000,                 // HALT
+        // handler
+        0x0D46,                 // MFPI SP
+        0x1583,                 // MOV (SP)+, R3
+        0x1184,                 // MOV SP, R4
+        0x0000,
+    ])
+}
+
+fn make_fpu_test() -> Arc<Mutex<Memory>> {
+    make_program(&[
+        0xF001,                 // SETF
+        0xF517, 0x4080,         // LDF #1.0, AC0
+        0xF417, 0x4100,         // ADDF #2.0, AC0
+        0xF217, 0x4140,         // MULF #3.0, AC0
+        0xFB01,                 // STCFI AC0, R1
+        0xFE57, 0x000A,         // LDCIF #10, AC1
+        0xF901,                 // DIVF AC1, AC0
+        0xF81F, 0x0400,         // STF AC0, @#2000
+        0x17C2, 0x0400,         // MOV @#2000, R2
+        0x17C3, 0x0402,         // MOV @#2002, R3
+        0x0000,
+    ])
+}
+
+fn make_fis_test() -> Arc<Mutex<Memory>> {
+    let handler = FIRST_COMMAND as Word + 0x24;
+
+    make_program(&[
+        0x15DF, 0x4080, 0x0500, // MOV #40200, @#2400
+        0x15DF, 0x4100, 0x0504, // MOV #40400, @#2404
+        0x15C4, 0x0500,         // MOV #2400, R4
+        0x7A04,                 // FADD R4
+        0x17C5, 0x0504,         // MOV @#2404, R5
+        0x15DF, handler, 0x00A4, // MOV #handler, @#244
+        0x15C2, 0x0508,         // MOV #2410, R2
+        0x7A1A,                 // FDIV R2, by the zero at 2410
+        0x0000,
+        // handler
+        0x1D83, 0x0002,         // MOV 2(SP), R3
+        0x0000,
+    ])
+}
+
+fn make_bus_error_test() -> Arc<Mutex<Memory>> {
+    let handler = FIRST_COMMAND as Word + 0x16;
+
+    make_program(&[
+        0x15DF, handler, 0x0004, // MOV #handler, @#4
+        0x0A00,                 // CLR R0
+        0x15C1, 0x5555,         // MOV #52525, R1
+        0x17C1, 0x0001,         // MOV @#1, R1
+        0x17C1, 0xE000,         // MOV @#160000, R1
+        0x0000,
+        // handler
+        0x0A80,                 // INC R0
+        0x0002,                 // RTI
+    ])
+}
+
+fn make_double_fault_test() -> Arc<Mutex<Memory>> {
+    make_program(&[
+        0x15C6, 0x0001,         // MOV #1, SP
+        0x17C0, 0x0001,         // MOV @#1, R0
+        0x0000,
+    ])
+}
+
+fn make_reserved_instruction_test() -> Arc<Mutex<Memory>> {
+    let handler = FIRST_COMMAND as Word + 0x10;
+
+    make_program(&[
+        0x15DF, handler, RESERVED_INSTRUCTION_TRAP_VECTOR as Word, // MOV #handler, @#10
+        0x0A00,                 // CLR R0
+        0x0007,                 // Reserved
+        0x0041,                 // JMP R1
+        0x7241,                 // DIV R1, R1
+        0x0000,
+        // handler
+        0x0A80,                 // INC R0
+        0x0002,                 // RTI
+    ])
+}
+
+fn make_pdp_11_20_test() -> Arc<Mutex<Memory>> {
+    let handler = FIRST_COMMAND as Word + 0x1E;
+    let jump_table = FIRST_COMMAND as Word + 0x12;
+
+    make_program(&[
+        0x15DF, handler, RESERVED_INSTRUCTION_TRAP_VECTOR as Word, // MOV #handler, @#10
+        0x0A00,                 // CLR R0
+        0x0DC2,                 // SXT R2
+        0x15C1, jump_table,     // MOV #jump_table, R1
+        0x0051,                 // JMP (R1)+
+        0x0000,
+        // jump_table
+        0x0000,
+        0x11DF, 0x0400,         // MOV PC, @#2000
+        0x17C3, 0x0400,         // MOV @#2000, R3
+        0x0000,
+        // handler
+        0x0A80,                 // INC R0
+        0x0002,                 // RTI
+    ])
+}
+
+fn make_mov_pc_test() -> Arc<Mutex<Memory>> {
+    make_program(&[
+        0x11DF, 0x0400,         // MOV PC, @#2000
+        0x17C3, 0x0400,         // MOV @#2000, R3
+        0x0000,
+    ])
+}
+
+fn make_processor_status_test() -> Arc<Mutex<Memory>> {
+    make_program(&[
+        0x8D17, 0x00FF,         // MTPS #377
+        0x8DC1,                 // MFPS R1
+        0x0000,
+    ])
+}
+
+fn make_line_clock_test() -> Arc<Mutex<Memory>> {
+    let handler = FIRST_COMMAND as Word + 0x18;
+    let status = LINE_CLOCK_STATUS_ADDRESS as Word;
+
+    make_program(&[
+        0x0098,                 // SPL 0
+        0x15DF, handler, LINE_CLOCK_INT as Word,      // MOV #handler, @#100
+        0x15DF, 0x00E0, LINE_CLOCK_INT as Word + 2,   // MOV #340, @#102
+        0x15DF, 0x0040, status, // MOV #100, @#177546
+        0x0001,                 // WAIT
+        0x0000,
+        // handler
+        0x17C0, status,         // MOV @#177546, R0
+        0x0A1F, status,         // CLR @#177546
+        0x0002,                 // RTI
+    ])
+}
+
+fn make_reset_test() -> Arc<Mutex<Memory>> {
+    let handler = FIRST_COMMAND as Word + 0x1E;
+    let status = LINE_CLOCK_STATUS_ADDRESS as Word;
+
+    make_program(&[
+        0x009F,                 // SPL 7
+        0x15DF, handler, LINE_CLOCK_INT as Word,      // MOV #handler, @#100
+        0x15DF, 0x00E0, LINE_CLOCK_INT as Word + 2,   // MOV #340, @#102
+        0x15DF, 0x0040, status, // MOV #100, @#177546
+        0x0005,                 // RESET
+        0x0098,                 // SPL 0
+        0x17C0, status,         // MOV @#177546, R0
+        0x0000,
+        // handler
+        0x0A81,                 // INC R1
+        0x0002,                 // RTI
+    ])
+}
+
+fn make_snapshot_test() -> Arc<Mutex<Memory>> {
+    let handler = FIRST_COMMAND as Word + 0x24;
+
+    make_program(&[
+        0x009F,                 // SPL 7
+        0x15DF, handler, LINE_CLOCK_INT as Word,      // MOV #handler, @#100
+        0x15DF, 0x00E0, LINE_CLOCK_INT as Word + 2,   // MOV #340, @#102
+        0x15DF, 0x1234, 0x0400, // MOV #1234, @#2000
+        0x15C0, 0x0003,         // MOV #3, R0
+        0x0000,                 // Snapshot taken here
+        0x6000,                 // ADD R0, R0
+        0x0098,                 // SPL 0
+        0x17C2, 0x0400,         // MOV @#2000, R2
+        0x0000,
+        // handler
+        0x0A83,                 // INC R3
+        0x0002,                 // RTI
+    ])
+}
+
+fn make_scheduler_test() -> Arc<Mutex<Memory>> {
+    let handler = FIRST_COMMAND as Word + 0x1C;
+    let status = LINE_CLOCK_STATUS_ADDRESS as Word;
+
+    make_program(&[
+        0x0098,                 // SPL 0
+        0x15DF, handler, LINE_CLOCK_INT as Word,      // MOV #handler, @#100
+        0x15DF, 0x00E0, LINE_CLOCK_INT as Word + 2,   // MOV #340, @#102
+        0x15DF, 0x0040, status, // MOV #100, @#177546
+        0x15C0, 0xC350,         // MOV #50000., R0
+        // loop
+        0x7E01,                 // SOB R0, loop
+        0x0000,
+        // handler
+        0x0A81,                 // INC R1
+        0x0002,                 // RTI
+    ])
+}
+
+fn make_bus_device_test() -> Arc<Mutex<Memory>> {
+    let transmitter_status = TRANSMITTER_STATUS_ADDRESS as Word;
+
+    make_program(&[
+        0x95DF, b'A' as Word, TRANSMITTER_BUFFER_ADDRESS as Word, // MOVB #101, @#177566
+        0x17C0, transmitter_status,     // MOV @#177564, R0
+        0x15C1, 0xFFFF,                 // MOV #-1, R1
+        0x97C1, transmitter_status + 1, // MOVB @#177565, R1
+        0x0000,
+    ])
+}
+
+fn make_22_bit_addressing_test() -> Arc<Mutex<Memory>> {
+    let kernel_pdr = KERNEL_PAGE_REGISTERS_ADDRESS as Word;
+    let kernel_par = kernel_pdr + 0x20;
+    let full_read_write_page: Word = 0x7F06;
+
+    let handler = FIRST_COMMAND as Word + 0x48;
+
+    let memory = Memory::with_size(0x100000);
+
+    write_program(&memory, &[
+        // Page 1 points to physical 2000000 (oct), page 7 to the 22-bit I/O page
+        0x15DF, full_read_write_page, kernel_pdr,
+        0x15DF, full_read_write_page, kernel_pdr + 2,
+        0x15DF, 0x2000, kernel_par + 2,
+        0x15DF, full_read_write_page, kernel_pdr + 14,
+        0x15DF, 0xFF80, kernel_par + 14,
+        0x15DF, handler, BUS_ERROR_TRAP_VECTOR as Word,
+        0x15DF, 0x00E0, BUS_ERROR_TRAP_VECTOR as Word + 2,
+        0x15DF, 0x0010, SR3_ADDRESS as Word,
+        0x15DF, 0x0001, SR0_ADDRESS as Word,
+        0x15DF, 0x1234, 0x2000, // MOV #1234, @#20000
+        0x15DF, 0x4000, kernel_par + 2, // Page 1 to physical 4000000 (oct)
+        0x17C1, 0x2000,         // MOV @#20000, R1
+        0x0000,
+        // handler
+        0x0A80,                 // INC R0
+        0x0002,                 // RTI
+    ]);
+
+    memory
+}
+
+pub fn test_absolute_loader(cpu: &mut CPU) {
+    run_test("Absolute Loader tape", cpu,
+        |cpu| {
+            let start = 0x1000;
+            let memory = Memory::new();
+
+            let mut tape = vec![0x00; 8]; // Leader
+            tape.extend(make_absolute_block(start, &[0x15C0, 0x0005])); // MOV #5, R0
+            tape.extend(make_absolute_block(start + 4, &[0x0000])); // HALT
+            tape.extend(make_absolute_block(start, &[]));
+            tape.extend([0x00; 8]); // Trailer
+
+            let mut bad_tape = tape.clone();
+            bad_tape[14] ^= 0x01; // Data of the first block
+            assert!(matches!(absolute::load(&bad_tape, &mut memory.lock().unwrap()), Err(LoadError::BadChecksum { offset: 8 })));
+            assert!(matches!(absolute::load(&tape[..20], &mut memory.lock().unwrap()), Err(LoadError::Truncated)));
+
+            let loaded = absolute::load(&tape, &mut memory.lock().unwrap()).unwrap();
+            assert!(loaded.start_address == Some(start) && !loaded.load_only);
+
+            let mut io_page_tape = make_absolute_block(0xDFFE, &[0x0000, 0x0000]);
+            io_page_tape.extend(make_absolute_block(start, &[]));
+            assert!(matches!(absolute::load(&io_page_tape, &mut memory.lock().unwrap()), Err(LoadError::BadFormat(_))));
+
+            // An odd start address loads the program without starting it
+            let load_only_tape = [&tape[..tape.len() - 15], &make_absolute_block(start + 1, &[])].concat();
+            assert!(absolute::load(&load_only_tape, &mut memory.lock().unwrap()).unwrap().load_only);
+
+            let path = std::env::temp_dir().join(format!("pdp11-absolute-test-{}.lda", std::process::id()));
+            fs::write(&path, load_only_tape).unwrap();
+
+            let mut machine = Pdp11::with_config(Pdp11Config { deterministic: true, ..Pdp11Config::default() });
+            let loaded = machine.load_absolute(&path);
+            let _ = fs::remove_file(&path);
+
+            assert!(loaded.unwrap().start_address.is_none() && machine.is_load_only());
+
+            machine.run();
+            assert!(machine.cpu().halt_reason().is_none() && machine.cpu().register(0) == 0);
+
+            machine.set_start_address(start);
+            machine.run();
+            assert!(machine.cpu().halt_reason() == Some(HaltReason::HaltInstruction(0x1004)) && machine.cpu().register(0) == 5);
+
+            cpu.boot_registers_at(start, FIRST_COMMAND as Word);
+            cpu.resume(memory);
+            cpu.dump_state()
+        },
+        |dump| {
+            assert!(dump.registers[0] == 5);
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(0x1004)));
+        }
+    );
+}
+
+pub fn test_aout_loader(cpu: &mut CPU) {
+    run_test("a.out with separate I and D spaces", cpu,
+        |cpu| {
+            let memory = Memory::new();
+            let image = make_separate_aout();
+
+            assert!(matches!(aout::load(&image[2..], &mut memory.lock().unwrap()), Err(LoadError::BadFormat(_))));
+
+            let loaded = aout::load(&image, &mut memory.lock().unwrap()).unwrap();
+            assert!(loaded.start_address == Some(0x0000));
+            assert!(loaded.data_space_base == Some(aout::SEPARATE_DATA_SPACE_BASE));
+            assert!(memory.lock().unwrap().read_word(aout::SEPARATE_DATA_SPACE_BASE + 4).unwrap() == 0x0000); // bss
+
+            let symbols = &loaded.symbols;
+            assert!(symbols.len() == 2);
+            assert!(symbols.find("_x").is_some_and(|symbol| symbol.kind == SymbolKind::Data && symbol.external && symbol.value == 2));
+            assert!(symbols.describe(0x0000) == "_main");
+            assert!(symbols.describe(0x0004) == "_x+2");
+
+            assert!(loaded.stack_pointer == Some(0xE000)); // Below the I/O page of the D space
+
+            cpu.map_separate_spaces(loaded.data_space_base.unwrap());
+            cpu.boot_registers_at(loaded.start_address.unwrap(), loaded.stack_pointer.unwrap());
+            cpu.resume(memory);
+            cpu.dump_state()
+        },
+        |dump| {
+            assert!(dump.registers[0] == 0x2222); // From D space, I space has 0002 there
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(0x0004)));
+        }
+    );
+}
+
+// Booted with the stack the loader picked, a call must not overwrite the program
+pub fn test_aout_stack() {
+    trace!("Test: a.out stack pointer");
+
+    // JSR PC,SUB; MOV SP,R1; HALT; SUB: MOV #42,R0; RTS PC
+    let text = [0o004767, 0o000004, 0o010601, 0o000000, 0o012700, 0o000042, 0o000207];
+    let path = std::env::temp_dir().join(format!("pdp11-aout-test-{}.out", std::process::id()));
+
+    for (magic, data_address) in [(aout::IMPURE_MAGIC, 0o16), (aout::PURE_MAGIC, 0o20000)] {
+        fs::write(&path, make_aout(magic, &text, &[0o1234], 0o100)).unwrap();
+
+        let config = Pdp11Config { deterministic: true, memory_size: 0x8000, ..Pdp11Config::default() };
+        let mut machine = Pdp11::with_config(config);
+        let loaded = machine.load_aout(&path).unwrap();
+
+        assert!(loaded.stack_pointer == Some(0x8000)); // Top of the 32K of memory
+
+        machine.run();
+
+        let dump = machine.cpu().dump_state();
+        assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(0o6)));
+        assert!(dump.registers[0] == 0o42 && dump.registers[1] == 0x8000);
+        assert!(machine.read_word(data_address).unwrap() == 0o1234);
+    }
+
+    // The bss takes everything up to the end of memory
+    fs::write(&path, make_aout(aout::IMPURE_MAGIC, &text, &[], 0x2000 - 0o16)).unwrap();
+
+    let mut machine = Pdp11::with_config(Pdp11Config { memory_size: 0x2000, ..Pdp11Config::default() });
+    let result = machine.load_aout(&path);
+    let _ = fs::remove_file(&path);
+
+    assert!(matches!(result, Err(LoadError::BadFormat(_))));
+
+    trace!("Passed!");
+}
+
+// Stripped, without symbols
+fn make_aout(magic: Word, text: &[Word], data: &[Word], bss_size: usize) -> Vec<Byte> {
+    let header = [magic, (text.len() * 2) as Word, (data.len() * 2) as Word, bss_size as Word, 0, 0x0000, 0x0000, 0x0001];
+
+    header.iter().chain(text).chain(data).flat_map(|word| word.to_le_bytes()).collect()
+}
+
+fn make_separate_aout() -> Vec<Byte> {
+    let header = [aout::SEPARATE_MAGIC, 6, 4, 2, 24, 0x0000, 0x0000, 0x0001];
+    let text = [0x17C0, 0x0002, 0x0000]; // MOV @#2, R0; HALT
+    let data = [0x1111, 0x2222];
+
+    let mut image = Vec::new();
+
+    for word in header.iter().chain(text.iter()).chain(data.iter()) {
+        image.extend(word.to_le_bytes());
+    }
+
+    for (name, symbol_type, value) in [("_main", 0x22u16, 0x0000u16), ("_x", 0x23, 0x0002)] {
+        let mut name = name.as_bytes().to_vec();
+        name.resize(8, 0x00);
+
+        image.extend(name);
+        image.extend(symbol_type.to_le_bytes());
+        image.extend(value.to_le_bytes());
+    }
+
+    image
+}
+
+pub fn test_sav_loader(cpu: &mut CPU) {
+    run_test("RT-11 save image", cpu,
+        |cpu| {
+            let memory = Memory::new();
+            let image = make_sav_image();
+
+            assert!(matches!(sav::load(&image[..0x100], &mut memory.lock().unwrap()), Err(LoadError::Truncated)));
+
+            let loaded = sav::load(&image, &mut memory.lock().unwrap()).unwrap();
+            assert!(loaded.start_address == Some(0x0200));
+            assert!(loaded.stack_pointer == Some(0x01F0));
+            assert!(memory.lock().unwrap().read_word(0x0400).unwrap() == 0x0000); // Not in the bitmap
+
+            cpu.boot_registers_at(loaded.start_address.unwrap(), loaded.stack_pointer.unwrap());
+            cpu.resume(memory);
+            cpu.dump_state()
+        },
+        |dump| {
+            assert!(dump.registers[0] == 0x01F0);
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(0x0202)));
+        }
+    );
+}
+
+fn make_sav_image() -> Vec<Byte> {
+    let mut image = vec![0x00; sav::BLOCK_SIZE * 3];
+
+    let mut put_word = |offset: usize, word: Word| image[offset..offset + 2].copy_from_slice(&word.to_le_bytes());
+    put_word(sav::START_ADDRESS_OFFSET, 0x0200);
+    put_word(sav::STACK_POINTER_OFFSET, 0x01F0);
+    put_word(sav::HIGH_LIMIT_OFFSET, 0x05FE);
+    put_word(0x0200, 0x1180); // MOV SP, R0
+    put_word(0x0202, 0x0000); // HALT
+    put_word(0x0400, 0xFFFF);
+
+    image[sav::PROTECTION_BITMAP_OFFSET] = 0xC0; // Blocks 0 and 1
+
+    image
+}
+
+pub fn test_assembler(cpu: &mut CPU) {
+    run_test("MACRO-11 assembler", cpu,
+        |cpu| {
+            let assembler = Assembler::new();
+            let program = assembler.assemble(ASSEMBLER_TEST_SOURCE).unwrap();
+
+            assert!(program.start_address == Some(0o1000));
+            assert!(program.symbols.find("VALUE").is_some_and(|symbol| symbol.value == 0o1062));
+            assert!(program.word(0o1000) == Some(0o012700) && program.word(0o1002) == Some(5));
+            assert!(program.word(0o1006) == Some(0o063701) && program.word(0o1010) == Some(0o1062));
+            assert!(program.word(0o1020) == Some(0o061203));
+            assert!(program.word(0o1022) == Some(0o066203) && program.word(0o1024) == Some(2));
+            assert!(program.word(0o1026) == Some(0o010346) && program.word(0o1030) == Some(0o012604));
+            assert!(program.word(0o1042) == Some(0o001374)); // BNE 1$
+            assert!(program.word(0o1044) == Some(0o116700) && program.word(0o1046) == Some(0o22)); // MOVB MSG,R0
+            assert!(program.word(0o1062) == Some(10) && program.byte(0o1074) == Some(0));
+            assert!(program.listing().contains("001000 012700 000005"));
+
+            let error = assembler.assemble("  .=1000\n  BR FAR\n  .=2000\nFAR: HALT").unwrap_err();
+            assert!(error.line == 2);
+            assert!(assembler.assemble("  MOVE R0,R1").unwrap_err().message.contains("MOVE"));
+
+            let memory = Memory::new();
+            let loaded = program.load(&mut memory.lock().unwrap()).unwrap();
+
+            cpu.boot_registers_at(loaded.start_address.unwrap(), 0o1000);
+            cpu.resume(memory);
+            cpu.dump_state()
+        },
+        |dump| {
+            assert!(dump.registers[..6] == [0o110, 15, 0o1066, 6, 6, 3]);
+            assert!(dump.status & 0x1 == 0x1); // SEC
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(0o1054)));
+        }
+    );
+}
+
+pub fn test_disassembler() {
+    trace!("Test: Disassembler");
+
+    let program = Assembler::new().assemble(ASSEMBLER_TEST_SOURCE).unwrap();
+    let memory = Memory::new();
+    program.load(&mut memory.lock().unwrap()).unwrap();
+
+    let disassemble = |disassembler: &Disassembler| -> Vec<String> {
+        disassembler.disassemble_range(&memory.lock().unwrap(), 0o1000, 0o1062).into_iter().map(|line| line.text).collect()
+    };
+
+    let plain = disassemble(&Disassembler::new());
+    assert!(plain.len() == 18);
+    assert!(plain[..4] == ["MOV #5,R0", "MOV R0,R1", "ADD @#1062,R1", "MOV #1064,R2"]);
+    assert!(plain[4..10] == ["MOV (R2)+,R3", "ADD (R2),R3", "ADD 2(R2),R3", "MOV R3,-(SP)", "MOV (SP)+,R4", "CLR R5"]);
+    assert!(plain[10..] == ["INC R5", "CMP R5,#3", "BNE 1034", "MOVB 1072,R0", "JSR PC,1056", "HALT", "SEC", "RTS PC"]);
+
+    let symbolic = disassemble(&Disassembler::new().with_symbols(program.symbols.clone()));
+    assert!(symbolic[2] == "ADD @#VALUE,R1" && symbolic[12] == "BNE START+34");
+    assert!(symbolic[13] == "MOVB MSG,R0" && symbolic[14] == "JSR PC,SUBR");
+
+    let words = [0o012737, 0o000155, 0o177566, 0o000007, 0o000277, 0o077203];
+    let fetch = |address: Word| words.get(address as usize / 2).copied();
+    let disassembler = Disassembler::new();
+
+    let first = disassembler.disassemble(0, fetch);
+    assert!(first.text == "MOV #155,@#177566" && first.words == words[..3]);
+    assert!(first.to_string() == "000000  012737 000155 177566 MOV #155,@#177566");
+    assert!(disassembler.disassemble(6, fetch).text == ".WORD 7");
+    assert!(disassembler.disassemble(8, fetch).text == "SCC");
+    assert!(disassembler.disassemble(10, fetch).text == "SOB R2,6");
+
+    let fpu_words = [0o175403, 0o177102, 0o172402];
+    let fpu_fetch = |address: Word| fpu_words.get(address as usize / 2).copied();
+    assert!(disassembler.disassemble(0, fpu_fetch).text == "STCFI AC0,R3");
+    assert!(disassembler.disassemble(2, fpu_fetch).text == "LDCIF R2,AC1");
+    assert!(disassembler.disassemble(4, fpu_fetch).text == "LDF AC2,AC0");
+
+    trace!("Passed!");
+}
+
+pub fn test_breakpoints(cpu: &mut CPU) {
+    run_test("Conditional breakpoints and watchpoints", cpu,
+        |cpu| {
+            let program = Assembler::new().assemble(BREAKPOINT_TEST_SOURCE).unwrap();
+            let memory = Memory::new();
+            program.load(&mut memory.lock().unwrap()).unwrap();
+
+            let symbol = |name: &str| program.symbols.find(name).unwrap().value;
+            let value = symbol("VALUE") as usize;
+
+            let condition = BreakCondition { operand: ConditionOperand::Register(0), comparison: Comparison::Equal, value: 3 };
+            cpu.add_conditional_breakpoint(symbol("STORE"), condition);
+
+            let dump = run_and_dump(cpu, memory.clone());
+            assert!(dump.halt_reason == Some(HaltReason::Breakpoint(symbol("STORE") as usize)) && dump.registers[0] == 3);
+            assert!(cpu.remove_breakpoint(symbol("STORE")));
+
+            memory.lock().unwrap().add_watchpoint(value..value + 2, WatchKind::Read);
+            cpu.resume(memory.clone());
+
+            let dump = cpu.dump_state();
+            assert!(dump.halt_reason == Some(HaltReason::Watchpoint(WatchpointHit { address: value, kind: WatchKind::Read, write: false })));
+            assert!(dump.registers[1] == 3 && dump.registers[7] == symbol("COMPARE"));
+
+            assert!(memory.lock().unwrap().remove_watchpoint(value..value + 2, WatchKind::Read));
+            memory.lock().unwrap().add_watchpoint(value + 1..value + 2, WatchKind::Write);
+            cpu.resume(memory.clone());
+
+            let dump = cpu.dump_state();
+            assert!(dump.halt_reason == Some(HaltReason::Watchpoint(WatchpointHit { address: value + 1, kind: WatchKind::Write, write: true })));
+            assert!(dump.registers[0] == 4 && dump.registers[1] == 3);
+
+            // Fetching the watched instruction is not an access
+            let loop_address = symbol("LOOP") as usize;
+            memory.lock().unwrap().clear_watchpoints();
+            memory.lock().unwrap().add_watchpoint(loop_address..loop_address + 2, WatchKind::Access);
+            cpu.resume(memory.clone());
+
+            cpu.dump_state()
+        },
+        |dump| {
+            assert!(dump.registers[0] == 5 && dump.registers[1] == 5);
+            assert!(matches!(dump.halt_reason, Some(HaltReason::HaltInstruction(_))));
+        }
+    );
+}
+
+const BREAKPOINT_TEST_SOURCE: &str = r#"
+        .=1000
+START:  CLR     R0
+LOOP:   INC     R0
+STORE:  MOV     R0,@#VALUE
+        MOV     @#VALUE,R1
+COMPARE:CMP     R0,#5
+        BNE     LOOP
+        HALT
+VALUE:  .WORD   0
+        .END    START
+"#;
+
+pub fn test_trace(cpu: &mut CPU) {
+    run_test("Instruction trace", cpu,
+        |cpu| {
+            let program = Assembler::new().assemble(TRACE_TEST_SOURCE).unwrap();
+            let memory = Memory::new();
+            program.load(&mut memory.lock().unwrap()).unwrap();
+
+            let path = std::env::temp_dir().join(format!("pdp11-trace-test-{}.log", std::process::id()));
+            let config = TraceConfig { pc_range: Some(0o1000..0o1016), max_lines: Some(3), max_files: 1, ..TraceConfig::new(&path) };
+
+            cpu.set_tracer(Some(Tracer::create(config, cpu.model()).unwrap()));
+            let dump = run_and_dump(cpu, memory);
+            cpu.set_tracer(None);
+
+            let rotated = fs::read_to_string(rotated_path(&path, 1)).unwrap();
+            let current = fs::read_to_string(&path).unwrap();
+            let _ = fs::remove_file(rotated_path(&path, 1));
+            let _ = fs::remove_file(&path);
+
+            let rotated: Vec<&str> = rotated.lines().collect();
+            let current: Vec<&str> = current.lines().collect();
+
+            assert!(rotated.len() == 4 && current.len() == 3);
+            assert!(rotated[0] == TRACE_HEADER && current[0] == TRACE_HEADER);
+
+            assert!(rotated[1] == "001000\t012700 000005\tMOV #5,R0\tR0=000005\tPSW=000000\t");
+            assert!(rotated[2] == "001004\t010046\tMOV R0,-(SP)\tSP=000776\tPSW=000000\tW00000776=000005");
+            assert!(rotated[3] == "001006\t110037 002000\tMOVB R0,@#2000\t\tPSW=000000\tB00002000=005");
+            assert!(current[1] == "001012\t005000\tCLR R0\tR0=000000\tPSW=000004\t");
+            assert!(current[2] == "001014\t005726\tTST (SP)+\tSP=001000\tPSW=000000\t");
+
+            dump
+        },
+        |dump| {
+            assert!(dump.halt_reason == Some(HaltReason::HaltInstruction(0o1016)));
+        }
+    );
+}
+
+const TRACE_TEST_SOURCE: &str = r#"
+        .=1000
+START:  MOV     #5,R0
+        MOV     R0,-(SP)
+        MOVB    R0,@#2000
+        CLR     R0
+        TST     (SP)+
+        HALT
+        .END    START
+"#;
+
+pub fn test_profiler(cpu: &mut CPU) {
+    run_test("Profiler", cpu,
+        |cpu| {
+            let program = Assembler::new().assemble(PROFILER_TEST_SOURCE).unwrap();
+            let memory = Memory::new();
+            program.load(&mut memory.lock().unwrap()).unwrap();
+
+            let path = std::env::temp_dir().join(format!("pdp11-profile-test-{}.txt", std::process::id()));
+            let address = |name: &str| program.symbols.find(name).unwrap().value;
+
+            cpu.set_profiler(Some(Profiler::new().with_symbols(program.symbols.clone()).with_report_path(&path)));
+            let dump = run_and_dump(cpu, memory);
+            let profiler = cpu.take_profiler().unwrap();
+
+            let report = fs::read_to_string(&path).unwrap();
+            let _ = fs::remove_file(&path);
+
+            // MOV, 3 JSR and SOB, TRAP, HALT at the top level, INC and RTS per call, INC and RTI in the handler
+            assert!(profiler.instructions() == 17);
+            assert!(profiler.pc_count(address("LOOP")) == 3 && profiler.pc_count(address("START")) == 1);
+            assert!(profiler.command_count("JSR") == 3 && profiler.command_count("RTI") == 1);
+
+            let top_level = profiler.function(None);
+            assert!(top_level.calls == 0 && top_level.inclusive == 17 && top_level.exclusive == 9);
+
+            let subroutine = profiler.function(Some(address("SUBR")));
+            assert!(subroutine.calls == 3 && subroutine.inclusive == 6 && subroutine.exclusive == 6);
+
+            let handler = profiler.function(Some(address("HANDLR")));
+            assert!(handler.calls == 1 && handler.inclusive == 2 && handler.exclusive == 2);
+
+            assert!(profiler.call_count(None, address("SUBR")) == 3 && profiler.call_count(None, address("HANDLR")) == 1);
+
+            assert!(report.starts_with("Profile of 17 instructions"));
+            assert!(report.contains("(top level) -> SUBR") && report.contains("(top level) -> HANDLR"));
+
+            dump
+        },
+        |dump| {
+            assert!(dump.registers[0] == 3 && dump.registers[1] == 0 && dump.registers[2] == 1);
+        }
+    );
+}
+
+const PROFILER_TEST_SOURCE: &str = r#"
+        .=30
+        .WORD   HANDLR,0,HANDLR,0
+        .=1000
+START:  MOV     #3,R1
+LOOP:   JSR     PC,SUBR
+        SOB     R1,LOOP
+        TRAP    0
+        HALT
+SUBR:   INC     R0
+        RTS     PC
+HANDLR: INC     R2
+        RTI
+        .END    START
+"#;
+
+pub fn test_monitor() {
+    trace!("Test: Monitor");
+
+    let mut monitor = Monitor::new(Pdp11::with_config(Pdp11Config { deterministic: true, ..Pdp11Config::default() }));
+    let mut execute = |line: &str| monitor.execute(line).unwrap();
+
+    // MOV #5,R0; INC R0; INC R0; HALT
+    for (address, word) in [("1000", "12700"), ("1002", "5"), ("1004", "5200"), ("1006", "5200"), ("1010", "0")] {
+        assert!(execute(&format!("deposit {address} {word}")).is_empty());
+    }
+
+    assert!(execute("e 1000:1004") == "001000:\t012700\n001002:\t000005\n001004:\t005200");
+    assert!(execute("e -m 1000:1006") == "001000:\tMOV #5,R0\n001004:\tINC R0\n001006:\tINC R0");
+
+    execute("br 1004 if r0==5");
+    assert!(execute("show break") == "Breakpoints: 001004 if R0==000005");
+
+    execute("watch -a 1000:1002");
+    assert!(execute("show watch") == "Watchpoints: 001000:001002 access");
+    execute("nowatch -a 1000:1002");
+    assert!(execute("go 1000") == "breakpoint at 001004, PC: 001004 (INC R0)");
+    assert!(execute("e r0") == "R0:\t000005");
+
+    assert!(execute("step") == "Step expired, PC: 001006 (INC R0)");
+    assert!(execute("e r0 pc") == "R0:\t000006\nPC:\t001006");
+
+    assert!(execute("continue") == "HALT instruction at 001010, PC: 001012 (HALT)");
+    assert!(execute("e r0") == "R0:\t000007");
+
+    execute("d r0 100");
+    execute("d psw 17");
+    assert!(execute("e r0 psw") == "R0:\t000100\nPSW:\t000017");
+
+    execute("nobr all");
+    assert!(execute("sh br") == "No breakpoints");
+    execute("d pc 1000");
+    assert!(execute("s 2") == "Step expired, PC: 001006 (INC R0)");
+
+    assert!(monitor.execute("frobnicate").is_err());
+    assert!(monitor.execute("nobr 1004").is_err());
+    assert!(monitor.execute("d 1001 0").is_err());
+    assert!(monitor.execute("e 1001:1005").is_err());
+    assert!(monitor.execute("e 177564").unwrap() == "177564:\t000200"); // XCSR, ready
+
+    monitor.execute("quit").unwrap();
+    assert!(monitor.is_done());
+
+    trace!("Passed!");
+}
+
+pub fn test_gdb_stub() {
+    trace!("Test: GDB stub");
+
+    let listener = GdbStub::bind(0).unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let server = thread::spawn(move || {
+        let mut stub = GdbStub::new(Pdp11::with_config(Pdp11Config { deterministic: true, ..Pdp11Config::default() }));
+        stub.listen(&listener).unwrap();
+        stub.into_machine()
+    });
+
+    let mut client = TcpStream::connect(("127.0.0.1", port)).unwrap();
+
+    // Sends a packet and returns the reply, both acknowledged
+    let mut exchange = |packet: &str| -> String {
+        client.write_all(gdb::frame(packet).as_bytes()).unwrap();
+
+        let mut reply = Vec::new();
+        let mut byte = [0];
+
+        while reply.len() < 3 || reply[reply.len() - 3] != b'#' {
+            client.read_exact(&mut byte).unwrap();
+
+            if !(reply.is_empty() && byte[0] == b'+') {
+                reply.push(byte[0]);
+            }
+        }
+
+        client.write_all(b"+").unwrap();
+
+        let reply = String::from_utf8(reply).unwrap();
+        assert!(reply.starts_with('$') && gdb::frame(&reply[1..reply.len() - 3]) == reply);
+
+        reply[1..reply.len() - 3].to_string()
+    };
+
+    // 1000: MOV #5,R0; INC R0; MOV R0,@#2000; HALT
+    assert!(exchange("M200,c:c0150500800a1f1000040000") == "OK");
+    assert!(exchange("m200,4") == "c0150500");
+    assert!(exchange("mff74,2") == "8000"); // XCSR on the I/O page, not RAM behind it
+    assert!(exchange("P7=0002") == "OK");
+    assert!(exchange("p7") == "0002");
+    assert!(exchange("qSupported:swbreak+") == "PacketSize=1000");
+
+    assert!(exchange("Z0,204,2") == "OK");
+    assert!(exchange("c") == "S05");
+    assert!(exchange("p0") == "0500" && exchange("p7") == "0402");
+
+    assert!(exchange("s") == "S05");
+    assert!(exchange("p0") == "0600" && exchange("p7") == "0602");
+
+    assert!(exchange("Z2,400,2") == "OK");
+    assert!(exchange("c") == "T05watch:400;");
+    assert!(exchange("m400,2") == "0600" && exchange("p7") == "0a02");
+
+    assert!(exchange("z2,400,2") == "OK" && exchange("z0,204,2") == "OK");
+    assert!(exchange("z0,204,2") == "E01");
+    assert!(exchange("c") == "S05");
+    assert!(exchange("p7") == "0c02");
+
+    let registers = exchange("g");
+    assert!(registers.len() == 36 && registers.starts_with("0600"));
+    assert!(exchange(&format!("G3412{}", &registers[4..])) == "OK");
+    assert!(exchange("p0") == "3412" && exchange("p9") == "E01");
+    assert!(exchange("vMustReplyEmpty").is_empty());
+
+    assert!(exchange("D") == "OK");
+
+    let machine = server.join().unwrap();
+    assert!(machine.cpu().dump_state().registers[0] == 0x1234);
+
+    trace!("Passed!");
+}
+
+const ASSEMBLER_TEST_SOURCE: &str = r#"
+        .TITLE  ADDRESSING
+        .=1000
+START:  MOV     #5,R0           ; Immediate
+        MOV     R0,R1
+        ADD     @#VALUE,R1      ; Absolute
+        MOV     #TABLE,R2
+        MOV     (R2)+,R3        ; Autoincrement
+        ADD     (R2),R3         ; Register deferred
+        ADD     2(R2),R3        ; Index
+        MOV     R3,-(SP)        ; Autodecrement
+        MOV     (SP)+,R4
+        CLR     R5
+1$:     INC     R5
+        CMP     R5,#3
+        BNE     1$
+        MOVB    MSG,R0          ; Relative
+        JSR     PC,SUBR
+        HALT
+SUBR:   SEC
+        RTS     PC
+VALUE:  .WORD   10.
+TABLE:  .WORD   1,2,3
+MSG:    .ASCIZ  /HI/
+        .EVEN
+        .END    START
+"#;
+
+pub fn test_command_line() {
+    trace!("Test: Command line");
+
+    let parse = |line: &str| cli::parse(line.split_whitespace().map(str::to_string));
+
+    assert!(matches!(parse("test"), Ok(CliCommand::Test)));
+    assert!(matches!(parse("--help"), Ok(CliCommand::Help)));
+    assert!(parse("test extra").is_err());
+    assert!(parse("--memory=7K").is_err());
+    assert!(parse("--address=1000 image.lda").is_err());
+    assert!(parse("--devices=rk05").is_err());
+    assert!(parse("--bogus").is_err());
+
+    // MOV #52,R0; HALT as a raw image at 2000
+    let path = std::env::temp_dir().join(format!("pdp11-cli-test-{}.raw", std::process::id()));
+    let image: Vec<Byte> = [0o012700, 0o52, 0o000000].iter().flat_map(|word: &Word| word.to_le_bytes()).collect();
+    fs::write(&path, image).unwrap();
+
+    let args = ["run", "--format=raw", "--address=2000", "--start=2000", "--cpu=11/40", "--memory=64K", "--devices=dl11", "--deterministic"]
+        .map(str::to_string).into_iter().chain([path.display().to_string()]);
+
+    let Ok(CliCommand::Run(options)) = cli::parse(args) else {
+        panic!("run options not parsed");
+    };
+
+    assert!(options.config.model == CpuModel::Pdp1140 && options.config.memory_size == 0x10000);
+    assert!(options.config.console && !options.config.line_clock && options.config.deterministic);
+
+    let machine = options.build();
+    let _ = fs::remove_file(&path);
+
+    let mut machine = machine.unwrap();
+    assert!(machine.start_address() == 0o2000 && !machine.has_line_clock());
+
+    machine.run();
+
+    assert!(machine.cpu().halt_reason() == Some(HaltReason::HaltInstruction(0o2004)));
+    assert!(cli::exit_code(&machine) == 0o52);
+
+    trace!("Passed!");
+}
+
+pub fn test_embedding_api() {
+    trace!("Test: Embedding API");
+
+    let config = Pdp11Config { deterministic: true, console: false, line_clock: false, ..Pdp11Config::default() };
+    let mut machine = Pdp11::with_config(config);
+
+    let counter = Arc::new(Mutex::new(ServiceCounter { services: 0 }));
+    machine.add_device(counter.clone());
+
+    // MOV @#170000,R1; BEQ .-4; HALT
+    let program: Vec<Byte> = [0o013701, 0o170000, 0o001775, 0o000000].iter().flat_map(|word: &Word| word.to_le_bytes()).collect();
+    machine.load_bytes(0o1000, &program).unwrap();
+    machine.set_start_address(0o1000);
+    machine.boot();
+
+    machine.step(1);
+    assert!(machine.register(PROGRAM_COUNTER_INDEX) == 0o1004 && machine.cpu().halt_reason().is_none());
+
+    machine.set_register(2, 0o1234);
+    machine.write_word(0o2000, 0o4321).unwrap();
+    assert!(machine.read_word(0o2000) == Ok(0o4321) && machine.read_byte(0o2001) == Ok(0o10));
+
+    machine.resume();
+
+    let services = counter.lock().unwrap().services;
+    assert!(services > 0 && machine.register(1) != 0 && machine.register(2) == 0o1234);
+    assert!(machine.cpu().halt_reason() == Some(HaltReason::HaltInstruction(0o1006)));
+    assert!(machine.read_word(unmapped_physical_address(0o170000)) == Ok(services));
+
+    // INC R0; MOV R0,@#2000; BR .-6, stopped from this thread through the running flag
+    let mut machine = Pdp11::with_config(config);
+    let program: Vec<Byte> = [0o005200, 0o010037, 0o002000, 0o000774].iter().flat_map(|word: &Word| word.to_le_bytes()).collect();
+    machine.load_bytes(0o1000, &program).unwrap();
+    machine.set_start_address(0o1000);
+
+    let memory = machine.memory();
+    let running_flag = machine.running_flag();
+    let handle = machine.run_async();
+
+    while memory.lock().unwrap().peek_word(0o2000) == Ok(0) {
+        thread::sleep(std::time::Duration::from_millis(1));
+    }
+
+    *running_flag.lock().unwrap() = false;
+    let machine = handle.join().unwrap();
+
+    assert!(machine.cpu().halt_reason().is_none() && !machine.cpu().is_running());
+    assert!(machine.read_word(0o2000).unwrap() > 0);
+
+    trace!("Passed!");
+}
+
+// Every character the program prints reaches the output, the last one even though the CPU halts right after it,
+// and a second run finds the console where the first one left it
+pub fn test_console_output(deterministic: bool) {
+    trace!("Test: Console output, deterministic: {deterministic}");
+
+    let mut machine = Pdp11::with_config(Pdp11Config { deterministic, ..Pdp11Config::default() });
+    let program = Assembler::new().assemble(CONSOLE_TEST_SOURCE).unwrap();
+    program.load(&mut machine.memory().lock().unwrap()).unwrap();
+    machine.set_start_address(0o1000);
+
+    let output = Arc::new(Mutex::new(Vec::new()));
+    machine.set_console_output(Some(Box::new(SharedOutput(output.clone()))));
+
+    machine.run();
+
+    assert!(machine.cpu().halt_reason() == Some(HaltReason::HaltInstruction(0o1024)));
+    assert!(*output.lock().unwrap() == b"HELLO");
+
+    machine.run();
+
+    assert!(machine.cpu().halt_reason() == Some(HaltReason::HaltInstruction(0o1024)));
+    assert!(*output.lock().unwrap() == b"HELLOHELLO");
+
+    trace!("Passed!");
+}
+
+const CONSOLE_TEST_SOURCE: &str = r#"
+        .=1000
+START:  MOV     #MSG,R1
+NEXT:   MOVB    (R1)+,R0
+        BEQ     DONE
+WAIT:   TSTB    @#177564
+        BPL     WAIT
+        MOVB    R0,@#177566
+        BR      NEXT
+DONE:   HALT
+MSG:    .ASCIZ  /HELLO/
+        .END    START
+"#;
+
+struct SharedOutput(Arc<Mutex<Vec<Byte>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, bytes: &[Byte]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(bytes);
+        Ok(bytes.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Counts its services, the count is read from its register at 170000
+struct ServiceCounter {
+    services: Word,
+}
+
+impl BusDevice for ServiceCounter {
+    fn address_range(&self) -> std::ops::Range<Address> {
+        0o170000..0o170002
+    }
+
+    fn read(&mut self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        self.peek(address, width)
+    }
+
+    fn write(&mut self, _address: Address, _width: AccessWidth, _data: Word) -> Result<(), BusError> {
+        Ok(())
+    }
+
+    fn peek(&self, _address: Address, _width: AccessWidth) -> Result<Word, BusError> {
+        Ok(self.services)
+    }
+}
+
+impl ScheduledDevice for ServiceCounter {
+    fn service_period(&self) -> u64 {
+        50
+    }
+
+    fn service(&mut self, _interruption_bus: Arc<Mutex<InterruptionBus>>) {
+        self.services += 1;
+    }
+}
+
+fn make_absolute_block(address: Word, words: &[Word]) -> Vec<Byte> {
+    let byte_count = (absolute::BLOCK_HEADER_SIZE + words.len() * 2) as Word;
+
+    let mut block = absolute::BLOCK_HEADER.to_vec();
+    block.extend(byte_count.to_le_bytes());
+    block.extend(address.to_le_bytes());
+
+    for word in words {
+        block.extend(word.to_le_bytes());
+    }
+
+    let sum = block.iter().fold(0x00u8, |sum, byte| sum.wrapping_add(*byte));
+    block.push(sum.wrapping_neg());
+
+    block
+}
+
+fn make_program(words: &[Word]) -> Arc<Mutex<Memory>> {
+    let mem = Memory::new();
+
+    write_program(&mem, words);
+
+    mem
+}
+
+fn write_program(mem: &Arc<Mutex<Memory>>, words: &[Word]) {
+    let mut address = FIRST_COMMAND;
+
+    for word in words {
+        address = mem.lock().unwrap().write_word(address, *word).unwrap();
+    }
+}
+
 fn mov_const(reg: Byte) -> Word {
     assert!(reg < (REG_COUNT as Byte));
 
@@ -90,11 +1551,11 @@ fn make_infinite_loop_print_m() -> Arc<Mutex<Memory>> {
 
     let mut address = FIRST_COMMAND;
 
-    address = memory.write_word(address, 0x15DF);
-    address = memory.write_word(address, make_word(b'm', 0x00u8));
-    address = memory.write_word(address, TRANSMITTER_BUFFER_ADDRESS as Word);
-    address = memory.write_word(address, 0x005F);
-    address = memory.write_word(address, FIRST_COMMAND as Word);
+    address = memory.write_word(address, 0x15DF).unwrap();
+    address = memory.write_word(address, make_word(b'm', 0x00u8)).unwrap();
+    address = memory.write_word(address, TRANSMITTER_BUFFER_ADDRESS as Word).unwrap();
+    address = memory.write_word(address, 0x005F).unwrap();
+    memory.write_word(address, FIRST_COMMAND as Word).unwrap();
 
     mem
 }
\ No newline at end of file
@@ -1,8 +1,8 @@
-use std::{io::Write, sync::{Arc, Mutex}, thread, time::Duration};
+use std::{io::{Read, Write}, ops::Range, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, OnceLock}, thread, time::Duration};
 
 use console::Term;
 
-use crate::{cpu::interruptions::InterruptionBus, mem::{MappedMemoryWord, Memory, SimpleMappedMemoryWord}, utils::{blocking_queue::BlockingQueue, Address, Byte, Number, Word}};
+use crate::{bus::{read_register, write_register, AccessWidth, BusDevice}, cpu::interruptions::InterruptionBus, scheduler::{virtual_ticks, ScheduledDevice}, mem::{BusError, MappedMemoryWord, Memory, SimpleMappedMemoryWord}, snapshot::{SnapshotError, SnapshotReader, SnapshotWriter}, utils::{blocking_queue::BlockingQueue, Address, Byte, Number, Word}};
 
 pub const RECEIVER_STATUS_ADDRESS: Address = 0xFF70;
 pub const RECEIVER_BUFFER_ADDRESS: Address = 0xFF72;
@@ -17,22 +17,47 @@ pub const INT_PRIORITY: Byte = 0x04;
 pub const RECEIVER_INT: Address = 0x0030;
 pub const TRANSMITTER_INT: Address = 0x0034;
 
+pub const POLL_PERIOD: Duration = Duration::from_millis(32);
+
 struct TtyMappedMemoryWord {
     has_new_data: Mutex<bool>,
     word: SimpleMappedMemoryWord,
+    reset_word: Word, // Value after INIT
 }
 
 impl TtyMappedMemoryWord {
     pub fn new() -> Self {
+        Self::with_reset_word(0x0000u16)
+    }
+
+    pub fn with_reset_word(reset_word: Word) -> Self {
         TtyMappedMemoryWord {
             has_new_data: Mutex::new(false),
             word: SimpleMappedMemoryWord::new(),
+            reset_word,
         }
     }
 
     fn has_new_data(&self) -> bool {
         *self.has_new_data.lock().unwrap()
     }
+
+    // Reading through MappedMemoryWord consumes the new data flag
+    fn peek_word(&self) -> Word {
+        self.word.read_word()
+    }
+
+    fn save_state(&self, writer: &mut SnapshotWriter) {
+        writer.write_word(self.peek_word());
+        writer.write_bool(self.has_new_data());
+    }
+
+    fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        self.word.write_word(reader.read_word()?);
+        *self.has_new_data.lock().unwrap() = reader.read_bool()?;
+
+        Ok(())
+    }
 }
 
 impl MappedMemoryWord for TtyMappedMemoryWord {
@@ -49,80 +74,267 @@ impl MappedMemoryWord for TtyMappedMemoryWord {
         let mut has_new_data = self.has_new_data.lock().unwrap();
         *has_new_data = true;
     }
+
+    fn reset(&mut self) {
+        self.word.write_word(self.reset_word);
+
+        *self.has_new_data.lock().unwrap() = false;
+    }
+}
+
+// The part of the DL11 the CPU sees on the bus
+struct Dl11Registers {
+    receiver_status: TtyMappedMemoryWord,
+    receiver_buffer: TtyMappedMemoryWord,
+    transmitter_status: TtyMappedMemoryWord,
+    transmitter_buffer: TtyMappedMemoryWord,
+}
+
+impl Dl11Registers {
+    fn new() -> Self {
+        Dl11Registers {
+            receiver_status: TtyMappedMemoryWord::new(),
+            receiver_buffer: TtyMappedMemoryWord::new(),
+            // The transmitter is ready after INIT
+            transmitter_status: TtyMappedMemoryWord::with_reset_word(0x0000u16.set_n_bit(RDY_STATUS_BIT, true)),
+            transmitter_buffer: TtyMappedMemoryWord::new(),
+        }
+    }
+
+    fn register(&self, address: Address) -> Result<&TtyMappedMemoryWord, BusError> {
+        match address & !0x1 {
+            RECEIVER_STATUS_ADDRESS => Ok(&self.receiver_status),
+            RECEIVER_BUFFER_ADDRESS => Ok(&self.receiver_buffer),
+            TRANSMITTER_STATUS_ADDRESS => Ok(&self.transmitter_status),
+            TRANSMITTER_BUFFER_ADDRESS => Ok(&self.transmitter_buffer),
+            _ => Err(BusError::NonExistentMemory(address)),
+        }
+    }
+
+    fn register_mut(&mut self, address: Address) -> Result<&mut TtyMappedMemoryWord, BusError> {
+        match address & !0x1 {
+            RECEIVER_STATUS_ADDRESS => Ok(&mut self.receiver_status),
+            RECEIVER_BUFFER_ADDRESS => Ok(&mut self.receiver_buffer),
+            TRANSMITTER_STATUS_ADDRESS => Ok(&mut self.transmitter_status),
+            TRANSMITTER_BUFFER_ADDRESS => Ok(&mut self.transmitter_buffer),
+            _ => Err(BusError::NonExistentMemory(address)),
+        }
+    }
+
+    fn all_mut(&mut self) -> [&mut TtyMappedMemoryWord; 4] {
+        [
+            &mut self.receiver_status,
+            &mut self.receiver_buffer,
+            &mut self.transmitter_status,
+            &mut self.transmitter_buffer,
+        ]
+    }
+}
+
+impl BusDevice for Dl11Registers {
+    fn address_range(&self) -> Range<Address> {
+        RECEIVER_STATUS_ADDRESS..TRANSMITTER_BUFFER_ADDRESS + 2
+    }
+
+    fn read(&mut self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        Ok(read_register(self.register(address)?, address, width))
+    }
+
+    // The transmitter is busy until the character is printed
+    fn write(&mut self, address: Address, width: AccessWidth, data: Word) -> Result<(), BusError> {
+        write_register(self.register_mut(address)?, address, width, data);
+
+        if address & !0x1 == TRANSMITTER_BUFFER_ADDRESS {
+            let status = self.transmitter_status.peek_word();
+            self.transmitter_status.word.write_word(status.set_n_bit(RDY_STATUS_BIT, false));
+        }
+
+        Ok(())
+    }
+
+    fn peek(&self, address: Address, width: AccessWidth) -> Result<Word, BusError> {
+        let word = self.register(address)?.peek_word();
+
+        Ok(match width {
+            AccessWidth::Word => word,
+            AccessWidth::Byte if address & 0x1 != 0 => word.high().word(),
+            AccessWidth::Byte => word.low().word(),
+        })
+    }
+
+    fn reset(&mut self) {
+        for register in self.all_mut() {
+            register.reset();
+        }
+    }
 }
 
 pub struct Dl11Tty {
     receiver_queue: Arc<BlockingQueue<Byte>>,
+    registers: Arc<Mutex<Dl11Registers>>,
+    escape_char: Option<Byte>, // Typed in the terminal it stops the machine instead of being received
+    running_flag: Option<Arc<Mutex<bool>>>, // Of the CPU the escape char stops on virtual time
+    output: Option<Box<dyn Write + Send>>, // None prints to the terminal
+}
 
-    receiver_status: Arc<Mutex<TtyMappedMemoryWord>>,
-    receiver_buffer: Arc<Mutex<TtyMappedMemoryWord>>,
-    transmitter_status: Arc<Mutex<TtyMappedMemoryWord>>,
-    transmitter_buffer: Arc<Mutex<TtyMappedMemoryWord>>,
+impl Default for Dl11Tty {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Dl11Tty {
     pub fn new() -> Self {
         Dl11Tty {
             receiver_queue: Arc::new(BlockingQueue::new()),
+            registers: Arc::new(Mutex::new(Dl11Registers::new())),
+            escape_char: None,
+            running_flag: None,
+            output: None,
+        }
+    }
+
+    pub fn set_escape_char(&mut self, escape_char: Option<Byte>, running_flag: Arc<Mutex<bool>>) {
+        self.escape_char = escape_char;
+        self.running_flag = escape_char.map(|_| running_flag);
+    }
+
+    pub fn set_output(&mut self, output: Option<Box<dyn Write + Send>>) {
+        self.output = output;
+    }
+
+    // RCSR, RBUF, XCSR and XBUF as the CPU would read them
+    pub fn register_words(&self) -> [Word; 4] {
+        let registers = self.registers.lock().unwrap();
 
-            receiver_status: Arc::new(Mutex::new(TtyMappedMemoryWord::new())),
-            receiver_buffer: Arc::new(Mutex::new(TtyMappedMemoryWord::new())),
-            transmitter_status: Arc::new(Mutex::new(TtyMappedMemoryWord::new())),
-            transmitter_buffer: Arc::new(Mutex::new(TtyMappedMemoryWord::new())),
+        [
+            registers.receiver_status.peek_word(),
+            registers.receiver_buffer.peek_word(),
+            registers.transmitter_status.peek_word(),
+            registers.transmitter_buffer.peek_word(),
+        ]
+    }
+
+    pub fn reset(&mut self) {
+        self.registers.lock().unwrap().reset();
+    }
+
+    // Registers and the input typed ahead but not yet received
+    pub fn save_state(&self, writer: &mut SnapshotWriter) {
+        for register in self.registers.lock().unwrap().all_mut() {
+            register.save_state(writer);
         }
+
+        let pending = self.receiver_queue.pending();
+
+        writer.write_word(pending.len() as Word);
+        writer.write_bytes(&pending);
+    }
+
+    pub fn restore_state(&mut self, reader: &mut SnapshotReader) -> Result<(), SnapshotError> {
+        for register in self.registers.lock().unwrap().all_mut() {
+            register.restore_state(reader)?;
+        }
+
+        while self.receiver_queue.pop().is_some() {}
+
+        let pending_count = reader.read_word()? as usize;
+
+        for char in reader.read_bytes(pending_count)? {
+            self.receiver_queue.push(*char);
+        }
+
+        Ok(())
     }
 }
 
 impl Dl11Tty {
-    pub fn run(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>, mem: Arc<Mutex<Memory>>, running_flag: Arc<Mutex<bool>>) {
-        self.map_registers(mem.clone());
+    // Terminal and registers exchange data until the CPU stops, the registers are attached by the caller
+    pub fn run(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>, running_flag: Arc<Mutex<bool>>) {
         trace!("tty start");
 
-        self.set_printing(false);
+        let keyboard = Keyboard::shared();
 
-        let thread_active_flag = Arc::new(Mutex::new(true));
-
-        let thread_active_flag_clone = thread_active_flag.clone();
-        let reciever_queue = self.receiver_queue.clone();
-        let stdin_loop = thread::spawn(move || { stdin_loop(reciever_queue, thread_active_flag_clone); });
-        
         while *running_flag.lock().unwrap() {
             trace!("tty tick");
-            self.try_print(interruption_bus.clone());
-            self.try_receive(interruption_bus.clone());
-            thread::sleep(Duration::from_millis(32));
+            self.take_keyboard_input(&keyboard, &running_flag);
+            self.poll(interruption_bus.clone());
+            thread::sleep(POLL_PERIOD);
         }
 
         trace!("tty stop");
-        *thread_active_flag.lock().unwrap() = false;
-        let _ = stdin_loop.join();
-        self.unmap_registers(mem.clone());
     }
 
-    fn map_registers(&mut self, mem: Arc<Mutex<Memory>>) {
-        let mut memory = mem.lock().unwrap();
+    // Registers are mapped and the transmitter is ready
+    pub fn attach(&mut self, mem: Arc<Mutex<Memory>>) {
+        self.map_registers(mem);
 
-        memory.map_word(RECEIVER_STATUS_ADDRESS, self.receiver_status.clone());
-        memory.map_word(RECEIVER_BUFFER_ADDRESS, self.receiver_buffer.clone());
-        memory.map_word(TRANSMITTER_STATUS_ADDRESS, self.transmitter_status.clone());
-        memory.map_word(TRANSMITTER_BUFFER_ADDRESS, self.transmitter_buffer.clone());
+        self.set_printing(false);
     }
 
-    fn unmap_registers(&mut self, mem: Arc<Mutex<Memory>>) {
-        let mut memory = mem.lock().unwrap();
+    pub fn detach(&mut self, mem: Arc<Mutex<Memory>>) {
+        self.unmap_registers(mem);
+    }
+
+    // Prints what the CPU left in the transmitter buffer, for a machine that stopped before the next poll
+    pub fn flush(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>) {
+        self.try_print(interruption_bus);
+    }
 
-        memory.unmap_word(RECEIVER_STATUS_ADDRESS);
-        memory.unmap_word(RECEIVER_BUFFER_ADDRESS);
-        memory.unmap_word(TRANSMITTER_STATUS_ADDRESS);
-        memory.unmap_word(TRANSMITTER_BUFFER_ADDRESS);
+    // Input for the receiver, the only source of it in deterministic mode without an escape char
+    pub fn queue_input(&self, chars: &[Byte]) {
+        for char in chars {
+            self.receiver_queue.push(*char);
+        }
+    }
+
+    // Keys typed after the escape char stay with the keyboard for whoever reads it next
+    fn take_keyboard_input(&self, keyboard: &Keyboard, running_flag: &Mutex<bool>) {
+        while let Some(char) = keyboard.pop() {
+            if Some(char) == self.escape_char {
+                *running_flag.lock().unwrap() = false;
+                return;
+            }
+
+            self.receiver_queue.push(char);
+        }
+    }
+
+    fn poll(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>) {
+        self.try_print(interruption_bus.clone());
+        self.try_receive(interruption_bus);
+    }
+
+    fn map_registers(&mut self, mem: Arc<Mutex<Memory>>) {
+        mem.lock().unwrap().attach_device(self.registers.clone());
+    }
+
+    fn unmap_registers(&mut self, mem: Arc<Mutex<Memory>>) {
+        mem.lock().unwrap().detach_device(RECEIVER_STATUS_ADDRESS);
     }
 }
 
 
+impl ScheduledDevice for Dl11Tty {
+    fn service_period(&self) -> u64 {
+        virtual_ticks(POLL_PERIOD)
+    }
+
+    // The terminal is only read with an escape char set, an interactive session is not reproducible anyway
+    fn service(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>) {
+        if let Some(running_flag) = self.running_flag.clone() {
+            self.take_keyboard_input(&Keyboard::shared(), &running_flag);
+        }
+
+        self.poll(interruption_bus);
+    }
+}
+
 // Print impl
 impl Dl11Tty {
     fn set_printing(&mut self, printing: bool) {
-        let mut status = self.transmitter_status.lock().unwrap();
+        let mut registers = self.registers.lock().unwrap();
+        let status = &mut registers.transmitter_status;
 
         let current = status.read_word();
 
@@ -132,22 +344,24 @@ impl Dl11Tty {
     }
 
     fn print_from_buffer(&mut self) {
-        let buffer = self.transmitter_buffer.lock().unwrap();
-
-        let char = [buffer.read_byte(false)];
+        let char = [self.registers.lock().unwrap().transmitter_buffer.read_byte(false)];
 
         let mut stdout = Term::stdout();
+        let output: &mut dyn Write = match &mut self.output {
+            Some(output) => output.as_mut(),
+            None => &mut stdout,
+        };
 
-        let _ = stdout.write(&char);
-        let _ = stdout.flush();
+        let _ = output.write(&char);
+        let _ = output.flush();
     }
 
     fn is_empty_transmitter(&self) -> bool {
-        !self.transmitter_buffer.lock().unwrap().has_new_data()
+        !self.registers.lock().unwrap().transmitter_buffer.has_new_data()
     }
 
     fn notify_ready_to_print(&self, interruption_bus: Arc<Mutex<InterruptionBus>>) {
-        if self.transmitter_status.lock().unwrap().read_word().get_n_bit(INT_STATUS_BIT) {
+        if self.registers.lock().unwrap().transmitter_status.read_word().get_n_bit(INT_STATUS_BIT) {
             interruption_bus.lock().unwrap().interrupt(TRANSMITTER_INT, INT_PRIORITY);
         }
     }
@@ -155,8 +369,10 @@ impl Dl11Tty {
 
 // Print
 impl Dl11Tty {
+    // Ready again once serviced, whether there was something to print or not
     fn try_print(&mut self, interruption_bus: Arc<Mutex<InterruptionBus>>) {
         if self.is_empty_transmitter() {
+            self.set_printing(false);
             return;
         }
 
@@ -172,7 +388,7 @@ impl Dl11Tty {
 // Receive impl
 impl Dl11Tty {
     fn has_received_data(&self) -> bool {
-        self.receiver_buffer.lock().unwrap().has_new_data()
+        self.registers.lock().unwrap().receiver_buffer.has_new_data()
     }
 
     fn data_from_receiver(&self) -> Option<Byte> {
@@ -180,7 +396,8 @@ impl Dl11Tty {
     }
 
     fn set_recived(&mut self, received: bool) {
-        let mut status = self.receiver_status.lock().unwrap();
+        let mut registers = self.registers.lock().unwrap();
+        let status = &mut registers.receiver_status;
 
         let current = status.read_word();
 
@@ -190,7 +407,7 @@ impl Dl11Tty {
     }
 
     fn should_notify_received(&self) -> bool {
-        let status = self.receiver_status.lock().unwrap().read_word();
+        let status = self.registers.lock().unwrap().receiver_status.read_word();
 
         status.get_n_bit(INT_STATUS_BIT) && !status.get_n_bit(RDY_STATUS_BIT)
     }
@@ -216,26 +433,71 @@ impl Dl11Tty {
         self.set_recived(false);
 
         if let Some(char) = self.data_from_receiver() {
-            self.receiver_buffer.lock().unwrap().write_byte(char, false);
+            self.registers.lock().unwrap().receiver_buffer.write_byte(char, false);
         }
     }
 }
 
-// Wait for user input
-fn blocking_get_next_char() -> Option<Byte> {
+// The terminal is read by one thread for the whole process,
+// the DL11 and the monitor take turns taking what was typed
+pub struct Keyboard {
+    queue: BlockingQueue<Byte>,
+    closed: AtomicBool,
+    interactive: bool, // A terminal in raw mode rather than piped input
+}
 
-    let char = Term::stdout().read_char().ok()? as Byte;
+static KEYBOARD: OnceLock<Arc<Keyboard>> = OnceLock::new();
 
-    Some(char)
-}
+impl Keyboard {
+    pub fn shared() -> Arc<Keyboard> {
+        KEYBOARD.get_or_init(|| {
+            let keyboard = Arc::new(Keyboard {
+                queue: BlockingQueue::new(),
+                closed: AtomicBool::new(false),
+                interactive: Term::stdout().is_term(),
+            });
 
-fn stdin_loop(reciever_queue: Arc<BlockingQueue<Byte>>, active_flag: Arc<Mutex<bool>>) {
-    trace!("stdin start");
-    while *active_flag.lock().unwrap() {
-        trace!("stdin tick");
-        if let Some(next_char) = blocking_get_next_char() {
-            reciever_queue.push(next_char);
-        };
+            let reader = keyboard.clone();
+            thread::spawn(move || reader.read_loop());
+
+            keyboard
+        }).clone()
     }
-    trace!("stdin stop");
-}
\ No newline at end of file
+
+    pub fn pop(&self) -> Option<Byte> {
+        self.queue.pop()
+    }
+
+    // Nothing more will be typed once the queue is empty
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Acquire)
+    }
+
+    pub fn is_interactive(&self) -> bool {
+        self.interactive
+    }
+
+    fn read_loop(&self) {
+        trace!("stdin start");
+        while let Some(char) = self.blocking_get_next_char() {
+            self.queue.push(char);
+        }
+        trace!("stdin stop");
+
+        self.closed.store(true, Ordering::Release);
+    }
+
+    // Wait for user input
+    fn blocking_get_next_char(&self) -> Option<Byte> {
+        if self.interactive {
+            return Some(Term::stdout().read_char().ok()? as Byte);
+        }
+
+        let mut char = [0];
+
+        match std::io::stdin().read(&mut char) {
+            Ok(1) => Some(char[0]),
+            _ => None,
+        }
+    }
+}
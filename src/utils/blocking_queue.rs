@@ -5,11 +5,17 @@ pub struct BlockingQueue<T> {
     receiver: Arc<Mutex<Receiver<T>>>,
 }
 
+impl<T> Default for BlockingQueue<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl<T> BlockingQueue<T> {
     pub fn new() -> Self {
         let (sender, receiver) = channel();
         Self {
-            sender: sender,
+            sender,
             receiver: Arc::new(Mutex::new(receiver)),
         }
     }
@@ -22,6 +28,30 @@ impl<T> BlockingQueue<T> {
         self.receiver.lock().unwrap().try_recv().ok()
     }
 
+    // Everything queued so far, left in the queue in the same order
+    pub fn pending(&self) -> Vec<T> where T: Clone {
+        let receiver = self.receiver.lock().unwrap();
+
+        let pending: Vec<T> = receiver.try_iter().collect();
+
+        for e in &pending {
+            self.push(e.clone());
+        }
+
+        pending
+    }
+
+    // Drops every queued copy of e, the others stay in order
+    pub fn remove(&self, e: &T) where T: PartialEq {
+        let receiver = self.receiver.lock().unwrap();
+
+        for kept in receiver.try_iter().collect::<Vec<T>>() {
+            if kept != *e {
+                self.push(kept);
+            }
+        }
+    }
+
     pub fn pop_blocking(&self) -> Option<T> {
         self.receiver.lock().unwrap().recv().ok()
     }